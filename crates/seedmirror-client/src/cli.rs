@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use clap::Parser;
+use seedmirror_core::{codec::DEFAULT_MAX_FRAME_SIZE, duration, filter::FilterRules};
 
 #[derive(Clone, Parser, Debug)]
 pub(crate) struct Args {
@@ -15,14 +16,23 @@ pub(crate) struct Args {
     ///
     /// Remote paths are relative to the home directory of the server and local paths are relative
     /// to the working directory of the client process.
+    ///
+    /// Options can be appended as a comma-separated list after a third colon:
+    /// /home/my_server/files/:/home/my_computer/files/:delete
+    ///
+    /// Supported options:
+    /// delete - remove local files when they are removed on the server, or when the server asks
+    /// for a full sync after missing changes. The initial sync never removes local files.
+    /// include=<PATTERN> - same as --include, but only for this mapping
+    /// exclude=<PATTERN> - same as --exclude, but only for this mapping
     #[arg(
         short = 'p',
         long = "path-mapping",
-        value_name= "<REMOTE SOURCE PATH>:<LOCAL DESTINATION PATH>",
-        value_parser = Self::parse_path_mapping,
+        value_name= "<REMOTE SOURCE PATH>:<LOCAL DESTINATION PATH>[:<OPTIONS>]",
+        value_parser = PathMapping::parse,
         action = clap::ArgAction::Append
     )]
-    pub path_mappings: Vec<(PathBuf, PathBuf)>,
+    pub path_mappings: Vec<PathMapping>,
 
     /// Perform full sync of remote directory upon connecting.
     #[arg(long, default_value_t = true)]
@@ -40,19 +50,125 @@ pub(crate) struct Args {
     /// Local path to forward unix domain socket to.
     #[arg(long, default_value_os_t = PathBuf::from("/tmp/forwarded-seedmirror-server.sock"))]
     pub local_socket_path: PathBuf,
+
+    /// Connect directly to a server listening for TLS connections on the address, e.g.
+    /// `seedbox.example.com:7878`, instead of forwarding its unix domain socket over ssh. Files
+    /// are still transferred over ssh.
+    #[arg(long, value_name = "HOST:PORT", requires = "tls_ca")]
+    pub server_address: Option<String>,
+
+    /// PEM file containing the certificate authorities that the certificate of the server must be
+    /// signed by, e.g. the self-signed certificate of the server.
+    #[arg(long, value_name = "PATH")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Name that the certificate of the server must be valid for. Defaults to the host of
+    /// `--server-address`.
+    #[arg(long, value_name = "NAME")]
+    pub tls_server_name: Option<String>,
+
+    /// PEM file containing the client certificate chain presented to the server.
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file containing the private key of `--tls-cert`.
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// File containing the token required by the server when connecting with `--server-address`.
+    #[arg(long, value_name = "PATH")]
+    pub token_file: Option<PathBuf>,
+
+    /// File storing the sequence number of the last update synced and the ID of the journal it
+    /// refers to, so that servers with a journal can replay the updates missed while disconnected
+    /// instead of running an initial sync.
+    #[arg(long, value_name = "PATH")]
+    pub state_file: Option<PathBuf>,
+
+    /// Interval in milliseconds between heartbeats sent to servers supporting them.
+    #[arg(long, default_value = "30000", value_parser = duration::parse_nonzero_millis)]
+    pub heartbeat_interval: Duration,
+
+    /// Amount of consecutive heartbeat intervals without any message from the server before the
+    /// connection is considered dead.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub missed_heartbeats: u32,
+
+    /// Maximum size in bytes of a single message exchanged with the server.
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    pub max_frame_size: usize,
+
+    /// Only sync files with names matching the pattern (wildcards: *, ? and [...]). Directories
+    /// are always synced. Specify multiple times to include multiple patterns.
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRules::validate_pattern)]
+    pub include: Vec<String>,
+
+    /// Never sync files or directories with names matching the pattern (wildcards: *, ? and
+    /// [...]). Takes precedence over --include. Specify multiple times to exclude multiple
+    /// patterns.
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRules::validate_pattern)]
+    pub exclude: Vec<String>,
 }
 
 impl Args {
-    fn parse_path_mapping(s: &str) -> clap::error::Result<(PathBuf, PathBuf), String> {
+    /// Returns the filter rules applying to all path mappings.
+    pub fn filter_rules(&self) -> FilterRules {
+        FilterRules {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            include_also: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PathMapping {
+    /// Absolute path on the server.
+    pub remote: PathBuf,
+
+    /// Absolute path on the client.
+    pub local: PathBuf,
+
+    /// Mirror removals of remote files to the local destination.
+    pub delete: bool,
+
+    /// Rules for which files to sync, specific to this mapping.
+    pub filter_rules: FilterRules,
+}
+
+impl PathMapping {
+    fn parse(s: &str) -> clap::error::Result<Self, String> {
         let parts: Vec<_> = s.split(':').collect();
-        if parts.len() != 2 {
-            return Err("expected <remote source path>:<local destination path>".into());
+        if parts.len() != 2 && parts.len() != 3 {
+            return Err(
+                "expected <remote source path>:<local destination path>[:<options>]".into(),
+            );
         }
 
-        let remote_path = Self::parse_absolute_path(parts[0])?;
-        let local_path = Self::parse_absolute_path(parts[1])?;
+        let mut mapping = Self {
+            remote: Self::parse_absolute_path(parts[0])?,
+            local: Self::parse_absolute_path(parts[1])?,
+            delete: false,
+            filter_rules: FilterRules::default(),
+        };
+
+        let options = parts.get(2).map(|options| options.split(','));
+        for option in options.into_iter().flatten() {
+            match option.split_once('=') {
+                None if option == "delete" => mapping.delete = true,
+                Some(("include", pattern)) => mapping
+                    .filter_rules
+                    .include
+                    .push(FilterRules::validate_pattern(pattern)?),
+                Some(("exclude", pattern)) => mapping
+                    .filter_rules
+                    .exclude
+                    .push(FilterRules::validate_pattern(pattern)?),
+                _ => return Err(format!("unknown path mapping option: {option:?}")),
+            }
+        }
 
-        Ok((remote_path, local_path))
+        Ok(mapping)
     }
 
     fn parse_absolute_path(s: &str) -> clap::error::Result<PathBuf, String> {
@@ -63,9 +63,11 @@ where
         .spawn()?;
 
     let stdout = child.stdout.take().expect("stdout should not be taken");
-    let mut lines = BufReader::new(stdout).lines();
+    let mut lines = BufReader::new(stdout).split(b'\n');
 
-    while let Some(line) = lines.next_line().await? {
+    // Lines aren't necessarily valid UTF-8 since they may contain file names
+    while let Some(line) = lines.next_segment().await? {
+        let line = String::from_utf8_lossy(&line).into_owned();
         log::debug!("streaming cmd: `{cmdline}`, stdout line: `{line}`");
         f(line);
     }
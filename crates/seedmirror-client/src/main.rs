@@ -5,6 +5,9 @@ use crate::{transfer::init_remote_watcher, workqueue::Workqueue};
 
 mod cli;
 mod command;
+mod manifest;
+mod state;
+mod tls;
 mod transfer;
 mod workqueue;
 
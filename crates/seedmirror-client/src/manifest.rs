@@ -0,0 +1,134 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use seedmirror_core::manifest::{self, EntryKind, ManifestEntry};
+
+use crate::{
+    cli::Args,
+    transfer::{delete_file, sync_file},
+    workqueue::{TaskId, Workqueue},
+};
+
+/// Compares the manifest of the watched remote path `remote_root` with the local tree, queueing a
+/// sync for every file that is missing locally or differs in type, size or modification time, and
+/// a removal for every local file that doesn't exist remotely if deletion is enabled.
+pub(crate) async fn sync_manifest(
+    args: Args,
+    workqueue: Workqueue,
+    remote_root: PathBuf,
+    remote_entries: Vec<ManifestEntry>,
+) -> anyhow::Result<()> {
+    let mapping = args
+        .path_mappings
+        .iter()
+        .find(|mapping| mapping.remote == remote_root)
+        .ok_or(anyhow::anyhow!(
+            "found no mapping for the remote path of the manifest: {remote_root:?}"
+        ))?;
+
+    let local_root = mapping.local.clone();
+    let filter = mapping.filter_rules.compile()?;
+    let local_entries =
+        tokio::task::spawn_blocking(move || match manifest::walk(&local_root, &filter) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            res => res,
+        })
+        .await??;
+    let local_entries: HashMap<_, _> = local_entries
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+
+    // Directories are listed before their contents, so a missing directory is synced as a whole
+    // before any of its descendants are checked
+    let mut synced_dirs = HashSet::new();
+    let mut syncs = Vec::new();
+    for remote_entry in &remote_entries {
+        if remote_entry.kind == EntryKind::Other
+            || has_ancestor_in(&remote_entry.path, &synced_dirs)
+        {
+            continue;
+        }
+
+        let changed = match local_entries.get(&remote_entry.path) {
+            Some(local_entry) if local_entry.kind != remote_entry.kind => true,
+            Some(local_entry) if local_entry.kind == EntryKind::File => {
+                local_entry.size != remote_entry.size || local_entry.mtime != remote_entry.mtime
+            }
+            Some(_) => false,
+            None => true,
+        };
+        if !changed {
+            continue;
+        }
+
+        let mut remote_path = remote_root.join(&remote_entry.path);
+        if remote_entry.kind == EntryKind::Directory {
+            // Trailing slash to sync the directory's contents, see `sync_path` on the server
+            remote_path.push("");
+            synced_dirs.insert(remote_entry.path.as_path());
+        }
+        syncs.push(remote_path);
+    }
+
+    let mut removals = Vec::new();
+    if mapping.delete {
+        let remote_paths: HashSet<_> = remote_entries
+            .iter()
+            .map(|entry| entry.path.as_path())
+            .collect();
+        let mut removed = HashSet::new();
+
+        let mut local_paths: Vec<_> = local_entries.keys().map(PathBuf::as_path).collect();
+        local_paths.sort_by_key(|path| path.components().count());
+        for local_path in local_paths {
+            if remote_paths.contains(local_path) || has_ancestor_in(local_path, &removed) {
+                continue;
+            }
+
+            removed.insert(local_path);
+            removals.push(remote_root.join(local_path));
+        }
+    }
+
+    if syncs.is_empty() && removals.is_empty() {
+        log::info!(
+            "no difference between remote {remote_root:?} and local {:?}",
+            mapping.local
+        );
+        return Ok(());
+    }
+
+    log::info!(
+        "found difference between remote {remote_root:?} and local {:?}. syncing {} and removing \
+        {} filesystem entries",
+        mapping.local,
+        syncs.len(),
+        removals.len()
+    );
+
+    for remote_path in syncs {
+        let id = TaskId::Sync(remote_path.clone());
+        workqueue
+            .push(id, sync_file(args.clone(), remote_path))
+            .await;
+    }
+    for remote_path in removals {
+        let id = TaskId::Remove(remote_path.clone());
+        workqueue
+            .push(id, delete_file(args.clone(), remote_path))
+            .await;
+    }
+
+    Ok(())
+}
+
+/// Returns true if a strict ancestor of `path` is in `dirs`.
+fn has_ancestor_in(path: &Path, dirs: &HashSet<&Path>) -> bool {
+    path.ancestors()
+        .skip(1)
+        .any(|ancestor| dirs.contains(ancestor))
+}
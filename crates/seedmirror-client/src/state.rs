@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeSet,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+/// Sequence number of the last update synced from the server's journal, persisted along with the
+/// ID of the journal so that the server can replay the updates missed while disconnected.
+#[derive(Clone)]
+pub(crate) struct SyncState {
+    /// File the sequence number is persisted to, kept in memory only if `None`.
+    path: Option<PathBuf>,
+
+    seqs: Arc<Mutex<Seqs>>,
+}
+
+struct Seqs {
+    /// ID of the journal the sequence numbers refer to, if known.
+    journal_id: Option<String>,
+
+    /// Sequence number up to which all updates were synced, 0 if none were. Sequence numbers
+    /// start at 1.
+    synced: u64,
+
+    /// Highest sequence number received.
+    received: u64,
+
+    /// Received updates that weren't synced yet, which are replayed on reconnect if they're never
+    /// synced.
+    unsynced: BTreeSet<u64>,
+}
+
+impl SyncState {
+    pub(crate) fn load(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        // The sequence number is on the first line, and the journal ID on the second one, which is
+        // missing in files predating journal IDs
+        let (synced, journal_id) = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(contents) => {
+                    let mut lines = contents.lines();
+                    let synced = lines
+                        .next()
+                        .unwrap_or_default()
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("invalid sequence number in {path:?}"))?;
+                    let journal_id = lines
+                        .next()
+                        .map(str::trim)
+                        .filter(|id| !id.is_empty())
+                        .map(str::to_string);
+                    (synced, journal_id)
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => (0, None),
+                Err(e) => {
+                    return Err(anyhow::anyhow!(e).context(format!("failed to read {path:?}")));
+                }
+            },
+            None => (0, None),
+        };
+
+        let seqs = Seqs {
+            journal_id,
+            synced,
+            received: synced,
+            unsynced: BTreeSet::new(),
+        };
+        Ok(Self {
+            path,
+            seqs: Arc::new(Mutex::new(seqs)),
+        })
+    }
+
+    /// Returns the sequence number up to which all updates were synced, if any.
+    pub(crate) fn last_seq(&self) -> Option<u64> {
+        Some(self.lock().synced).filter(|seq| *seq != 0)
+    }
+
+    /// Returns the ID of the journal that [`SyncState::last_seq`] refers to, if known.
+    pub(crate) fn journal_id(&self) -> Option<String> {
+        self.lock().journal_id.clone()
+    }
+
+    /// Records that sequence numbers refer to the journal with ID `journal_id`. Sequence numbers
+    /// of another journal are forgotten, since they start over in the new one.
+    pub(crate) async fn set_journal_id(&self, journal_id: String) -> anyhow::Result<()> {
+        {
+            let mut seqs = self.lock();
+            if seqs.journal_id.as_ref() == Some(&journal_id) {
+                return Ok(());
+            }
+
+            seqs.journal_id = Some(journal_id.clone());
+            seqs.synced = 0;
+            seqs.received = 0;
+            seqs.unsynced.clear();
+        }
+
+        self.persist(0, Some(&journal_id)).await
+    }
+
+    /// Records that the update with sequence number `seq` was received.
+    pub(crate) fn received(&self, seq: u64) {
+        let mut seqs = self.lock();
+        seqs.received = seqs.received.max(seq);
+        seqs.unsynced.insert(seq);
+    }
+
+    /// Records that the update with sequence number `seq` was synced.
+    pub(crate) async fn synced(&self, seq: u64) -> anyhow::Result<()> {
+        let (synced, journal_id) = {
+            let mut seqs = self.lock();
+            seqs.unsynced.remove(&seq);
+
+            let synced = match seqs.unsynced.first() {
+                Some(first_unsynced) => first_unsynced - 1,
+                None => seqs.received,
+            };
+            if synced <= seqs.synced {
+                return Ok(());
+            }
+            seqs.synced = synced;
+            (synced, seqs.journal_id.clone())
+        };
+
+        self.persist(synced, journal_id.as_deref()).await
+    }
+
+    async fn persist(&self, synced: u64, journal_id: Option<&str>) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut contents = format!("{synced}\n");
+        if let Some(journal_id) = journal_id {
+            contents.push_str(&format!("{journal_id}\n"));
+        }
+
+        // Replace the file at once so that it's never left half-written
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        tokio::fs::write(&tmp_path, contents)
+            .await
+            .with_context(|| format!("failed to write {tmp_path:?}"))?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| format!("failed to replace {path:?}"))?;
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Seqs> {
+        self.seqs
+            .lock()
+            .expect("sync state lock should not be poisoned")
+    }
+}
@@ -0,0 +1,79 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use rustls::{
+    ClientConfig, RootCertStore,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+use crate::cli::Args;
+
+/// Creates the connector for TLS connections to the server from the configured certificates.
+pub(crate) fn connector(args: &Args) -> anyhow::Result<TlsConnector> {
+    let Some(ca_path) = &args.tls_ca else {
+        anyhow::bail!("--tls-ca is required to connect with --server-address");
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca_path)? {
+        roots.add(cert)?;
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+
+    let config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .with_context(|| format!("failed to read private key {key_path:?}"))?;
+            builder
+                .with_client_auth_cert(read_certs(cert_path)?, key)
+                .context("invalid client certificate or private key")?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Connects to the server at `address`.
+pub(crate) async fn connect(
+    args: &Args,
+    connector: &TlsConnector,
+    address: &str,
+) -> anyhow::Result<TlsStream<TcpStream>> {
+    let server_name = match &args.tls_server_name {
+        Some(name) => name.clone(),
+        None => host(address).to_string(),
+    };
+    let server_name = ServerName::try_from(server_name)
+        .with_context(|| format!("invalid server name for {address}"))?;
+
+    let stream = TcpStream::connect(address)
+        .await
+        .with_context(|| format!("failed to connect to {address}"))?;
+
+    connector
+        .connect(server_name, stream)
+        .await
+        .with_context(|| format!("tls handshake with {address} failed"))
+}
+
+/// Returns the host part of `address`, e.g. `::1` for `[::1]:7878`.
+fn host(address: &str) -> &str {
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _port)| host);
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates {path:?}"))
+}
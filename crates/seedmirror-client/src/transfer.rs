@@ -1,29 +1,71 @@
 use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
     fs::remove_file,
-    path::{Path, PathBuf},
+    io::ErrorKind,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
     pin::Pin,
     process::Stdio,
     time::Duration,
 };
 
 use anyhow::Context;
-use seedmirror_core::message::Message;
+use futures_util::{SinkExt, StreamExt};
+use seedmirror_core::{
+    auth::Token,
+    codec::MessageCodec,
+    filter::{Filter, WatchedPathFilter},
+    manifest::ManifestEntry,
+    message::{Capability, ErrorCode, Message, PROTOCOL_VERSION},
+};
 use tokio::{
-    io::BufReader,
+    io::{AsyncRead, AsyncWrite},
     net::UnixStream,
     process::{Child, Command},
-    time::sleep,
+    sync::mpsc,
+    time::{Instant, MissedTickBehavior, interval, sleep},
 };
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 use crate::{
-    cli::Args,
+    cli::{Args, PathMapping},
     command::{run_with_output, run_with_streaming_output},
-    workqueue::Workqueue,
+    manifest::sync_manifest,
+    state::SyncState,
+    tls,
+    workqueue::{BoxFutureResult, TaskId, Workqueue},
 };
 
 type Task = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
 
+type ServerReader = FramedRead<Box<dyn AsyncRead + Unpin + Send>, MessageCodec>;
+type ServerWriter = FramedWrite<Box<dyn AsyncWrite + Unpin + Send>, MessageCodec>;
+
 pub(crate) fn init_remote_watcher(args: &Args, workqueue: Workqueue) -> anyhow::Result<Task> {
+    // The rules of each mapping must be applied by rsync too, which can't apply all of them
+    let global_filter_rules = args.filter_rules();
+    for mapping in &args.path_mappings {
+        global_filter_rules
+            .merge(&mapping.filter_rules)
+            .rsync_args()
+            .with_context(|| format!("invalid filters for remote {:?}", mapping.remote))?;
+    }
+
+    if let Some(server_address) = &args.server_address {
+        let connector = tls::connector(args)?;
+        let token = args.token_file.as_deref().map(Token::read).transpose()?;
+        let remote_watcher = new_tls_remote_watcher(
+            args.clone(),
+            workqueue,
+            connector,
+            server_address.clone(),
+            token,
+        );
+        return Ok(Box::pin(remote_watcher));
+    }
+
     if args.local_socket_path.try_exists()? {
         remove_file(&args.local_socket_path).with_context(|| {
             format!(
@@ -38,11 +80,7 @@ pub(crate) fn init_remote_watcher(args: &Args, workqueue: Workqueue) -> anyhow::
         .arg(&args.ssh_hostname)
         .arg("-nNT")
         .arg("-L")
-        .arg(format!(
-            "{}:{}",
-            args.local_socket_path.to_string_lossy(),
-            args.socket_path.to_string_lossy()
-        ))
+        .arg(join_os_str(&args.local_socket_path, &args.socket_path))
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .spawn()
@@ -58,28 +96,335 @@ struct RemoteWatcher {
 
     /// Queue for sync tasks.
     workqueue: Workqueue,
+
+    /// Write half of the connection to the server.
+    writer: ServerWriter,
+
+    /// Token authenticating the client to the server.
+    token: Option<Token>,
+
+    /// Last update synced from the server's journal.
+    state: SyncState,
+
+    /// Channel for `Ack` and `Nack` messages sent once sync tasks finish.
+    ack_tx: mpsc::UnboundedSender<Message>,
+
+    /// Features supported by both the client and the server, set once connected.
+    capabilities: Vec<Capability>,
+
+    /// Entries of manifests that are still being received, by watched remote path.
+    manifests: HashMap<PathBuf, Vec<ManifestEntry>>,
+
+    /// Compiled filter of each mapping by remote path, set once connected.
+    filters: HashMap<PathBuf, Filter>,
 }
 
 impl RemoteWatcher {
-    pub(crate) fn new(args: Args, workqueue: Workqueue) -> Self {
-        Self { args, workqueue }
+    pub(crate) fn new(
+        mut args: Args,
+        workqueue: Workqueue,
+        writer: ServerWriter,
+        token: Option<Token>,
+        state: SyncState,
+        ack_tx: mpsc::UnboundedSender<Message>,
+    ) -> Self {
+        // Resolve the filter rules of each mapping once so that every task uses the same rules
+        let global_filter_rules = args.filter_rules();
+        for mapping in &mut args.path_mappings {
+            mapping.filter_rules = global_filter_rules.merge(&mapping.filter_rules);
+        }
+
+        Self {
+            args,
+            workqueue,
+            writer,
+            token,
+            state,
+            ack_tx,
+            capabilities: Vec::new(),
+            manifests: HashMap::new(),
+            filters: HashMap::new(),
+        }
+    }
+
+    async fn send_message(&mut self, msg: Message) -> anyhow::Result<()> {
+        self.writer
+            .send(msg)
+            .await
+            .context("failed writing to socket")
+    }
+
+    fn connection_request(&self) -> Message {
+        let mappings = &self.args.path_mappings;
+
+        Message::ConnectionRequest {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capability::supported(),
+            watched_paths: mappings
+                .iter()
+                .map(|mapping| mapping.remote.clone())
+                .collect(),
+            filters: mappings
+                .iter()
+                .filter(|mapping| !mapping.filter_rules.is_empty())
+                .map(|mapping| WatchedPathFilter {
+                    path: mapping.remote.clone(),
+                    rules: mapping.filter_rules.clone(),
+                })
+                .collect(),
+            token: self.token.clone(),
+            last_seq: self.state.last_seq(),
+            journal_id: self.state.journal_id(),
+        }
+    }
+
+    /// Returns true if `remote_path` passes the filter of its mapping. Servers supporting
+    /// [`Capability::Filters`] already filter all paths, so this is only checked for older ones.
+    fn is_match(&self, remote_path: &Path, is_dir: bool) -> anyhow::Result<bool> {
+        if self.capabilities.contains(&Capability::Filters) {
+            return Ok(true);
+        }
+
+        let Some(mapping) = best_prefix_match(remote_path, &self.args.path_mappings) else {
+            return Ok(true);
+        };
+
+        let relative_path = remote_path.strip_prefix(&mapping.remote)?;
+        Ok(self
+            .filters
+            .get(&mapping.remote)
+            .is_none_or(|filter| filter.is_match(relative_path, is_dir)))
+    }
+
+    /// Queues `fut`, recording that the change with sequence number `seq` was received and that
+    /// it was synced once the task applying it succeeded, which may be another queued task that
+    /// the change was merged into. Updates of `acked_path` are acknowledged with the result of
+    /// that task if the server supports acknowledgements.
+    async fn push_tracked(
+        &mut self,
+        id: TaskId,
+        seq: Option<u64>,
+        acked_path: Option<PathBuf>,
+        fut: impl Future<Output = anyhow::Result<()>> + Send + 'static,
+    ) {
+        let on_done = self.track(seq, acked_path);
+        self.workqueue.push_tracked(id, fut, on_done).await;
+    }
+
+    /// Records that the change with sequence number `seq` was received, returning the callback
+    /// recording that it was synced and acknowledging updates of `acked_path` once the task
+    /// applying it finished. Must be called right before queueing the task, so that it can't
+    /// finish first.
+    fn track(
+        &mut self,
+        seq: Option<u64>,
+        acked_path: Option<PathBuf>,
+    ) -> impl FnOnce(&anyhow::Result<()>) -> BoxFutureResult + Send + 'static {
+        if let Some(seq) = seq {
+            self.state.received(seq);
+        }
+
+        let ack_tx = acked_path
+            .filter(|_| self.capabilities.contains(&Capability::Ack))
+            .map(|path| (path, self.ack_tx.clone()));
+        let (state, dry_run) = (self.state.clone(), self.args.dry_run);
+        move |res: &anyhow::Result<()>| -> BoxFutureResult {
+            if let Some((path, ack_tx)) = ack_tx {
+                let msg = match res {
+                    Ok(()) => Message::Ack { path, seq },
+                    Err(e) => Message::Nack {
+                        path,
+                        seq,
+                        reason: format!("{e:#}"),
+                    },
+                };
+                // Only fails if the connection is broken, in which case the update is replayed
+                // or found by the initial sync on reconnect
+                let _ = ack_tx.send(msg);
+            }
+
+            let synced = res.is_ok() && !dry_run;
+            Box::pin(async move {
+                if let Some(seq) = seq.filter(|_| synced) {
+                    state.synced(seq).await?;
+                }
+
+                Ok(())
+            })
+        }
+    }
+
+    /// Queues a full sync of each mapping of the watched remote paths.
+    async fn resync(&mut self, remote_paths: &[PathBuf]) -> anyhow::Result<()> {
+        for remote_path in remote_paths {
+            let Some(mapping) = self
+                .args
+                .path_mappings
+                .iter()
+                .find(|mapping| &mapping.remote == remote_path)
+            else {
+                log::warn!("server asked to resync unknown remote {remote_path:?}");
+                continue;
+            };
+
+            // Removals may have been missed too, unlike during the initial sync
+            let mut args = self.args.clone();
+            args.path_mappings = vec![mapping.clone()];
+            let id = TaskId::Resync(remote_path.clone());
+            self.workqueue.push(id, full_sync(args, true)).await;
+        }
+
+        Ok(())
     }
 
     async fn handle_message(&mut self, msg: Message) -> anyhow::Result<()> {
         match msg {
-            Message::Connected => {
+            Message::Connected {
+                protocol_version,
+                capabilities,
+                filters,
+                replaying,
+                journal_id,
+            } => {
                 log::debug!("received `Connected` answer from server ",);
-                if self.args.initial_sync {
+                if protocol_version != PROTOCOL_VERSION {
+                    anyhow::bail!(
+                        "server speaks protocol version {protocol_version} but client speaks \
+                        version {PROTOCOL_VERSION}, upgrade the older of the two"
+                    );
+                }
+
+                self.capabilities = Capability::negotiate(&capabilities);
+                log::info!(
+                    "negotiated capabilities with server: {:?}",
+                    self.capabilities
+                );
+
+                if !self.capabilities.contains(&Capability::Filters) {
+                    log::info!("server doesn't support filters, filtering files locally");
+                }
+
+                if let Some(journal_id) = journal_id {
+                    self.state.set_journal_id(journal_id).await?;
+                }
+
+                // Files filtered on the server can't be synced, so skip them in full syncs too
+                for mapping in &mut self.args.path_mappings {
+                    mapping.filter_rules = mapping.filter_rules.merge(&filters);
+                    mapping.filter_rules.rsync_args().with_context(|| {
+                        format!(
+                            "filters of remote {:?} can't be combined with the server's filters",
+                            mapping.remote
+                        )
+                    })?;
+                }
+                self.filters = self
+                    .args
+                    .path_mappings
+                    .iter()
+                    .map(|mapping| Ok((mapping.remote.clone(), mapping.filter_rules.compile()?)))
+                    .collect::<anyhow::Result<_>>()?;
+
+                if !self.args.initial_sync {
+                    return Ok(());
+                }
+
+                if replaying {
+                    log::info!("server is replaying missed updates, skipping initial sync");
+                    return Ok(());
+                }
+
+                if self.capabilities.contains(&Capability::Manifest) {
+                    log::info!("requesting manifests of remote paths for initial sync");
+                    let paths: Vec<_> = self
+                        .args
+                        .path_mappings
+                        .iter()
+                        .map(|mapping| mapping.remote.clone())
+                        .collect();
+                    for path in paths {
+                        self.send_message(Message::ManifestRequest { path }).await?;
+                    }
+                } else {
                     self.workqueue
-                        .push("__full_sync".to_string(), full_sync(self.args.clone()))
-                        .await?;
+                        .push(TaskId::FullSync, full_sync(self.args.clone(), false))
+                        .await;
                 }
             }
-            Message::FileUpdated { path } => {
-                let id = path.to_string_lossy().into_owned();
+            Message::Manifest {
+                path,
+                entries,
+                done,
+            } => {
+                let mut manifest = self.manifests.remove(&path).unwrap_or_default();
+                manifest.extend(entries);
+                if !done {
+                    self.manifests.insert(path, manifest);
+                    return Ok(());
+                }
+
+                log::info!(
+                    "received manifest of remote {path:?} with {} entries",
+                    manifest.len()
+                );
+                let id = TaskId::Manifest(path.clone());
+                let fut = sync_manifest(self.args.clone(), self.workqueue.clone(), path, manifest);
+                self.workqueue.push(id, fut).await;
+            }
+            Message::FileUpdated { path, .. } if !self.is_match(&path, is_dir_path(&path))? => {
+                log::debug!("ignoring filtered remote {path:?}");
+            }
+            Message::FileRemoved { path, .. } if !self.is_match(&path, true)? => {
+                log::debug!("ignoring filtered remote {path:?}");
+            }
+            Message::FileRenamed { to, .. } if !self.is_match(&to, is_dir_path(&to))? => {
+                log::debug!("ignoring filtered remote {to:?}");
+            }
+            Message::FileUpdated { path, seq } => {
+                let id = TaskId::Sync(path.clone());
+                let fut = sync_file(self.args.clone(), path.clone());
+                self.push_tracked(id, seq, Some(path), fut).await;
+            }
+            Message::FileRemoved { path, seq } => {
+                let id = TaskId::Remove(path.clone());
+                let fut = delete_file(self.args.clone(), path);
+                self.push_tracked(id, seq, None, fut).await;
+            }
+            Message::FileRenamed { from, to, seq } => {
+                // The file may have been written since it was last synced, e.g. when it's renamed
+                // right after being completed, so it's synced again using the renamed local file
+                // as the basis
+                let rename = rename_file(self.args.clone(), from.clone(), to.clone());
+                let sync = sync_file(self.args.clone(), to.clone());
+                let on_done = self.track(seq, None);
                 self.workqueue
-                    .push(id, sync_file(self.args.clone(), path))
-                    .await?;
+                    .push_rename(from, to, rename, sync, on_done)
+                    .await;
+            }
+            Message::ResyncRequired { paths } => {
+                log::warn!("server skipped updates, syncing {paths:?} in full");
+                self.resync(&paths).await?;
+            }
+            Message::Ping => {
+                self.send_message(Message::Pong).await?;
+            }
+            Message::Error { code, reason } => {
+                if code.is_fatal() {
+                    anyhow::bail!("server refused connection ({code:?}): {reason}");
+                }
+
+                log::error!("received error from server ({code:?}): {reason}");
+
+                if code == ErrorCode::ManifestFailed {
+                    // The server lists manifests one at a time, so only the one being received
+                    // can be incomplete
+                    self.manifests.clear();
+
+                    log::info!("falling back to rsync for initial sync");
+                    self.workqueue
+                        .push(TaskId::FullSync, full_sync(self.args.clone(), false))
+                        .await;
+                }
             }
             _ => (),
         };
@@ -100,26 +445,79 @@ async fn new_remote_watcher(
     wait_for_file(local_socket_path).await;
 
     log::info!("connecting to {local_socket_path:?}");
-    let mut stream = UnixStream::connect(&local_socket_path)
+    let stream = UnixStream::connect(&local_socket_path)
         .await
         .with_context(|| format!("failed to connect to socket at {local_socket_path:?}"))?;
     log::info!("connected to {local_socket_path:?}");
 
-    let req = Message::ConnectionRequest {
-        watched_paths: args
-            .path_mappings
-            .iter()
-            .map(|(remote, _local)| remote.clone())
-            .collect(),
-    };
-    req.write_to_stream(&mut stream).await?;
+    let codec = MessageCodec::new(args.max_frame_size);
+    let (reader, writer) = stream.into_split();
+    let reader = FramedRead::new(Box::new(reader) as _, codec.clone());
+    let writer = FramedWrite::new(Box::new(writer) as _, codec);
 
-    let mut watcher = RemoteWatcher::new(args, workqueue);
-    let mut reader = BufReader::new(stream);
+    run_remote_watcher(args, workqueue, reader, writer, None).await
+}
+
+async fn new_tls_remote_watcher(
+    args: Args,
+    workqueue: Workqueue,
+    connector: TlsConnector,
+    server_address: String,
+    token: Option<Token>,
+) -> anyhow::Result<()> {
+    log::info!("connecting to {server_address}");
+    let stream = tls::connect(&args, &connector, &server_address).await?;
+    log::info!("connected to {server_address}");
+
+    let codec = MessageCodec::new(args.max_frame_size);
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = FramedRead::new(Box::new(reader) as _, codec.clone());
+    let writer = FramedWrite::new(Box::new(writer) as _, codec);
+
+    run_remote_watcher(args, workqueue, reader, writer, token).await
+}
+
+async fn run_remote_watcher(
+    args: Args,
+    workqueue: Workqueue,
+    mut reader: ServerReader,
+    writer: ServerWriter,
+    token: Option<Token>,
+) -> anyhow::Result<()> {
+    let mut heartbeat = interval(args.heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let heartbeat_timeout = args.heartbeat_interval * args.missed_heartbeats;
+
+    let state = SyncState::load(args.state_file.clone())?;
+    let (ack_tx, mut ack_rx) = mpsc::unbounded_channel();
+    let mut watcher = RemoteWatcher::new(args, workqueue, writer, token, state, ack_tx);
+    watcher.send_message(watcher.connection_request()).await?;
+    let mut last_received = Instant::now();
 
     loop {
-        let msg = Message::read_from_reader(&mut reader).await?;
-        watcher.handle_message(msg).await?;
+        tokio::select! {
+            res = reader.next() => {
+                let msg = res
+                    .context("connection to server broken")?
+                    .context("failed reading from socket")?;
+                last_received = Instant::now();
+                watcher.handle_message(msg).await?;
+            }
+            Some(msg) = ack_rx.recv() => {
+                watcher.send_message(msg).await?;
+            }
+            _ = heartbeat.tick() => {
+                if !watcher.capabilities.contains(&Capability::Heartbeat) {
+                    continue;
+                }
+
+                if last_received.elapsed() > heartbeat_timeout {
+                    anyhow::bail!("no message received from server in {heartbeat_timeout:?}");
+                }
+
+                watcher.send_message(Message::Ping).await?;
+            }
+        }
     }
 }
 
@@ -130,12 +528,15 @@ async fn wait_for_file(path: &Path) {
     }
 }
 
-async fn full_sync(args: Args) -> anyhow::Result<()> {
+/// Syncs each mapping in full. If `delete` is set, local files that no longer exist on the server
+/// are removed too for the mappings mirroring removals.
+async fn full_sync(args: Args, delete: bool) -> anyhow::Result<()> {
     log::info!("performing full sync...");
 
-    for (remote_path, local_path) in &args.path_mappings {
+    for mapping in &args.path_mappings {
+        let (remote_path, local_path) = (&mapping.remote, &mapping.local);
         let (rsync_dry_run_cmd, rsync_dry_run_args) =
-            construct_rsync_cmd(&args, remote_path, local_path, true);
+            construct_rsync_cmd(&args, mapping, remote_path, local_path, delete, true)?;
         let dry_run_output = run_with_output(rsync_dry_run_cmd, rsync_dry_run_args).await?;
 
         let fs_entries = dry_run_output.lines().collect::<Vec<_>>();
@@ -155,7 +556,8 @@ async fn full_sync(args: Args) -> anyhow::Result<()> {
 
         log::info!("{diff_msg}");
 
-        let (rsync_cmd, rsync_args) = construct_rsync_cmd(&args, remote_path, local_path, false);
+        let (rsync_cmd, rsync_args) =
+            construct_rsync_cmd(&args, mapping, remote_path, local_path, delete, false)?;
         run_with_streaming_output(rsync_cmd, rsync_args, |line| {
             let line_trimmed = line.trim_matches('"');
             let remote_file_path = remote_path.join(line_trimmed);
@@ -169,15 +571,20 @@ async fn full_sync(args: Args) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn sync_file(args: Args, remote_file_path: PathBuf) -> anyhow::Result<()> {
-    let (remote_path, local_path) = best_prefix_match(&remote_file_path, &args.path_mappings).ok_or(anyhow::anyhow!(
+pub(crate) async fn sync_file(args: Args, remote_file_path: PathBuf) -> anyhow::Result<()> {
+    let mapping = best_prefix_match(&remote_file_path, &args.path_mappings).ok_or(anyhow::anyhow!(
         "found no watched remote path that matches the incoming remote file: {remote_file_path:?}"
     ))?;
 
-    let relative_path = remote_file_path.strip_prefix(remote_path)?;
-    let local_file_path = local_path.join(relative_path);
-    let (rsync_cmd, rsync_args) =
-        construct_rsync_cmd(&args, &remote_file_path, &local_file_path, false);
+    let local_file_path = local_path(mapping, &remote_file_path)?;
+    let (rsync_cmd, rsync_args) = construct_rsync_cmd(
+        &args,
+        mapping,
+        &remote_file_path,
+        &local_file_path,
+        false,
+        false,
+    )?;
 
     log::info!(r#"syncing remote {remote_file_path:?} to local {local_file_path:?}"#);
     if !args.dry_run {
@@ -187,37 +594,219 @@ async fn sync_file(args: Args, remote_file_path: PathBuf) -> anyhow::Result<()>
     Ok(())
 }
 
+pub(crate) async fn delete_file(args: Args, remote_file_path: PathBuf) -> anyhow::Result<()> {
+    let mapping = best_prefix_match(&remote_file_path, &args.path_mappings).ok_or(anyhow::anyhow!(
+        "found no watched remote path that matches the removed remote file: {remote_file_path:?}"
+    ))?;
+
+    if !mapping.delete {
+        log::debug!("ignoring removal of remote {remote_file_path:?} since deletion is disabled");
+        return Ok(());
+    }
+
+    let local_file_path = local_path(mapping, &remote_file_path)?;
+    if local_file_path == mapping.local {
+        log::warn!(
+            "refusing to remove local {:?} since the watched remote path {remote_file_path:?} was removed",
+            mapping.local
+        );
+        return Ok(());
+    }
+
+    log::info!("removing local {local_file_path:?} since remote {remote_file_path:?} was removed");
+    if args.dry_run {
+        return Ok(());
+    }
+
+    match tokio::fs::symlink_metadata(&local_file_path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&local_file_path).await?,
+        Ok(_) => tokio::fs::remove_file(&local_file_path).await?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::debug!("local {local_file_path:?} was already removed");
+        }
+        Err(e) => {
+            return Err(anyhow::anyhow!(e).context(format!("failed to remove {local_file_path:?}")));
+        }
+    }
+
+    Ok(())
+}
+
+async fn rename_file(
+    args: Args,
+    remote_from_path: PathBuf,
+    remote_to_path: PathBuf,
+) -> anyhow::Result<()> {
+    let from_mapping = best_prefix_match(&remote_from_path, &args.path_mappings);
+    let to_mapping = best_prefix_match(&remote_to_path, &args.path_mappings);
+
+    // Only rename locally if both ends are in the same destination tree and the file has
+    // already been synced, otherwise fall back to removing the previous path, leaving the renamed
+    // file to be transferred by the sync following the rename. Renaming removes the previous local
+    // path, so the file is linked to its new path if deletion is disabled.
+    if let (Some(from_mapping), Some(to_mapping)) = (from_mapping, to_mapping)
+        && from_mapping.local == to_mapping.local
+    {
+        let local_paths = local_path(from_mapping, &remote_from_path)
+            .and_then(|from| Ok((from, local_path(to_mapping, &remote_to_path)?)));
+        let (local_from_path, local_to_path) = match local_paths {
+            Ok(local_paths) => local_paths,
+            Err(e) => {
+                log::warn!("{e:#}, syncing renamed file instead");
+                return remove_previous_path(args, remote_from_path).await;
+            }
+        };
+
+        if tokio::fs::symlink_metadata(&local_from_path).await.is_ok() {
+            if from_mapping.delete {
+                log::info!("renaming local {local_from_path:?} to {local_to_path:?}");
+            } else {
+                log::info!(
+                    "linking local {local_from_path:?} to {local_to_path:?} since deletion is disabled"
+                );
+            }
+            if args.dry_run {
+                return Ok(());
+            }
+
+            if let Some(parent) = local_to_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            if !from_mapping.delete {
+                let (from, to) = (local_from_path.clone(), local_to_path.clone());
+                return tokio::task::spawn_blocking(move || link_recursive(&from, &to))
+                    .await?
+                    .with_context(|| {
+                        format!("failed to link {local_from_path:?} to {local_to_path:?}")
+                    });
+            }
+
+            return tokio::fs::rename(&local_from_path, &local_to_path)
+                .await
+                .with_context(|| {
+                    format!("failed to rename {local_from_path:?} to {local_to_path:?}")
+                });
+        }
+
+        log::debug!("local {local_from_path:?} doesn't exist, syncing renamed file instead");
+    }
+
+    remove_previous_path(args, remote_from_path).await
+}
+
+/// Removes the previous local path of a file that can't be renamed locally, if it's mapped and
+/// deletion is enabled.
+async fn remove_previous_path(args: Args, remote_from_path: PathBuf) -> anyhow::Result<()> {
+    if best_prefix_match(&remote_from_path, &args.path_mappings).is_none() {
+        return Ok(());
+    }
+
+    delete_file(args, remote_from_path).await
+}
+
+/// Creates `to` with the contents of `from`, which is kept. Files are hard linked, or copied if
+/// they can't be linked, e.g. because `to` is on another filesystem. Directories are recreated
+/// with their entries linked.
+fn link_recursive(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(from)?.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            link_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        }
+
+        return Ok(());
+    }
+
+    // Like renaming, replace an existing file
+    match std::fs::remove_file(to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    if let Err(e) = std::fs::hard_link(from, to) {
+        log::debug!("failed to link {from:?} to {to:?}, copying instead: {e}");
+        std::fs::copy(from, to)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the rsync command syncing `remote_path` to `local_path`. Local files missing on the
+/// server are only removed if `delete` is set and the mapping mirrors removals, since other syncs
+/// mustn't remove files that were already in the destination.
 fn construct_rsync_cmd<'a>(
     args: &'a Args,
+    mapping: &PathMapping,
     remote_path: &'a Path,
     local_path: &'a Path,
+    delete: bool,
     dry_run: bool,
-) -> (&'a str, Vec<String>) {
+) -> anyhow::Result<(&'a str, Vec<OsString>)> {
     let ssh_hostname = &args.ssh_hostname;
     let mut args = vec![
-        "-ahz".to_string(),
-        "--partial".to_string(),
-        "--mkpath".to_string(), // automatically create destination path
-        r#"--out-format="%n""#.to_string(),
-        format!("{}:{}", ssh_hostname, remote_path.to_string_lossy()),
-        local_path.to_string_lossy().to_string(),
+        OsString::from("-ahz"),
+        OsString::from("--partial"),
+        OsString::from("--mkpath"), // automatically create destination path
+        OsString::from(r#"--out-format="%n""#),
+        join_os_str(ssh_hostname, remote_path),
+        local_path.as_os_str().to_owned(),
     ];
 
+    let filter_args = mapping.filter_rules.rsync_args()?;
+    args.extend(filter_args.into_iter().map(OsString::from));
+
+    if delete && mapping.delete {
+        args.push(OsString::from("--delete"));
+    }
+
     if dry_run {
-        args.push("-n".to_string());
+        args.push(OsString::from("-n"));
+    }
+
+    Ok(("rsync", args))
+}
+
+/// Returns true if `path` refers to a directory, i.e. has a trailing slash.
+fn is_dir_path(path: &Path) -> bool {
+    path.as_os_str().as_bytes().ends_with(b"/")
+}
+
+/// Returns `<a>:<b>` without any lossy conversions.
+fn join_os_str(a: impl AsRef<OsStr>, b: impl AsRef<OsStr>) -> OsString {
+    let mut joined = a.as_ref().to_owned();
+    joined.push(":");
+    joined.push(b);
+    joined
+}
+
+/// Returns the local path that `remote_path` is synced to under `mapping`. Fails if the path
+/// relative to the mapping has components other than normal ones, like `..`, which would point
+/// outside of the local destination.
+fn local_path(mapping: &PathMapping, remote_path: &Path) -> anyhow::Result<PathBuf> {
+    let relative_path = remote_path.strip_prefix(&mapping.remote)?;
+    let is_normal = relative_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !is_normal {
+        anyhow::bail!(
+            "refusing remote {remote_path:?}, which isn't a plain path under {:?}",
+            mapping.remote
+        );
     }
 
-    ("rsync", args)
+    Ok(mapping.local.join(relative_path))
 }
 
 /// Returns the mapping that best matches `remote_file_path` based on the remote path with the
 /// longest prefix (amount of shared parent directories).
 fn best_prefix_match<'a>(
     remote_file_path: &'a Path,
-    mappings: &'a [(PathBuf, PathBuf)],
-) -> Option<&'a (PathBuf, PathBuf)> {
+    mappings: &'a [PathMapping],
+) -> Option<&'a PathMapping> {
     mappings
         .iter()
-        .filter(|(remote, _local)| remote_file_path.starts_with(remote))
-        .max_by_key(|(remote, _local)| remote.components().count())
+        .filter(|mapping| remote_file_path.starts_with(&mapping.remote))
+        .max_by_key(|mapping| mapping.remote.components().count())
 }
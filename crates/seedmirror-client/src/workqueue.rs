@@ -1,52 +1,449 @@
-use std::{collections::HashSet, pin::Pin, sync::Arc};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
 
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{Mutex, Notify};
 
-type Task = (String, BoxFutureResult);
-type BoxFutureResult = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + Sync>>;
+pub(crate) type BoxFutureResult = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
 
+/// Called with the result of the task applying a change once it finishes.
+type OnDone = Box<dyn FnOnce(&anyhow::Result<()>) -> BoxFutureResult + Send>;
+
+/// Identifies a task. A task isn't queued if a task with the same ID is already queued and
+/// applies it as well.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub(crate) enum TaskId {
+    FullSync,
+
+    /// Full sync of a single watched remote path.
+    Resync(PathBuf),
+
+    /// Diff of the manifest of a watched remote path against the local tree.
+    Manifest(PathBuf),
+    Sync(PathBuf),
+    Remove(PathBuf),
+
+    /// Local rename, always followed by a sync of `to` transferring the changes of the renamed
+    /// file.
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl TaskId {
+    /// Returns the paths whose local state is replaced by the task, undoing syncs of them or of
+    /// paths under them that run before it.
+    fn replaced_paths(&self) -> Vec<&Path> {
+        match self {
+            TaskId::Remove(path) => vec![path],
+            TaskId::Rename { from, to } => vec![from, to],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns the paths synced or replaced by a task applying a single change.
+    fn changed_paths(&self) -> Vec<&Path> {
+        match self {
+            TaskId::Sync(path) => vec![path],
+            id => id.replaced_paths(),
+        }
+    }
+
+    /// Returns true if the task and `other` have to run in the order they were queued in.
+    fn is_ordered_with(&self, other: &TaskId) -> bool {
+        if self.replaced_paths().is_empty() && other.replaced_paths().is_empty() {
+            return false;
+        }
+
+        let other_paths = other.changed_paths();
+        self.changed_paths().iter().any(|path| {
+            other_paths
+                .iter()
+                .any(|other_path| path.starts_with(other_path) || other_path.starts_with(path))
+        })
+    }
+}
+
+struct Task {
+    id: TaskId,
+
+    /// Callbacks of the changes applied by the task, including those of the tasks merged into it
+    /// or cancelled by it.
+    on_done: Vec<OnDone>,
+
+    fut: BoxFutureResult,
+}
+
+/// Runs tasks one at a time, in the order they were queued in.
+#[derive(Clone)]
 pub(crate) struct Workqueue {
-    sender: mpsc::UnboundedSender<Task>,
-    active: Arc<Mutex<HashSet<String>>>,
+    /// Tasks that aren't running yet.
+    queued: Arc<Mutex<VecDeque<Task>>>,
+
+    /// Notified when a task is queued.
+    notify: Arc<Notify>,
 }
 
 impl Workqueue {
     pub(crate) fn new() -> Self {
-        let (tx, rx) = mpsc::unbounded_channel::<Task>();
-        let active = Arc::new(Mutex::new(HashSet::new()));
-        tokio::spawn(Self::start(rx, active.clone()));
+        let queued = Arc::new(Mutex::new(VecDeque::new()));
+        let notify = Arc::new(Notify::new());
+        tokio::spawn(Self::start(queued.clone(), notify.clone()));
 
-        Self { sender: tx, active }
+        Self { queued, notify }
+    }
+
+    /// Queues `fut`, unless a queued task with the same ID applies it as well.
+    pub(crate) async fn push<Fut>(&self, id: TaskId, fut: Fut)
+    where
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.queue(id, Box::pin(fut), Vec::new()).await;
     }
 
-    pub(crate) async fn push<Fut>(&self, id: String, fut: Fut) -> anyhow::Result<()>
+    /// Queues `fut`, which applies a change. `on_done` is called with the result of the task
+    /// applying the change once it finishes, which may be another task that the change was merged
+    /// into or that cancelled it.
+    ///
+    /// Queued syncs undone by a removal are cancelled, and their changes are applied by the
+    /// removal instead. If a queued task with the same ID doesn't have to run before another
+    /// queued task, the change is merged into it instead of queueing a new task. Running tasks may
+    /// have started before the change, so changes are never merged into them.
+    pub(crate) async fn push_tracked<Fut, F>(&self, id: TaskId, fut: Fut, on_done: F)
     where
-        Fut: Future<Output = anyhow::Result<()>> + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+        F: FnOnce(&anyhow::Result<()>) -> BoxFutureResult + Send + 'static,
     {
-        let mut active = self.active.lock().await;
-        if active.contains(&id) {
-            // Already queued/running
-            log::debug!("skipping task `{id}` since it already exists");
-            return Ok(());
+        let mut queued = self.queued.lock().await;
+        let mut on_done: Vec<OnDone> = vec![Box::new(on_done)];
+        if let TaskId::Remove(path) = &id {
+            on_done.append(&mut Self::cancel_syncs(&mut queued, &[path], &id));
         }
+        self.enqueue(&mut queued, id, Box::pin(fut), on_done);
+    }
 
-        active.insert(id.clone());
-        self.sender.send((id, Box::pin(fut)))?;
+    /// Queues `rename`, which renames `from` to `to` locally, followed by `sync`, which syncs `to`
+    /// and applies the rename. `on_done` is called with the result of the sync once it finishes.
+    ///
+    /// Queued syncs of paths under `from` or `to` are undone by the rename, so they're cancelled
+    /// and their changes are applied by the sync of `to` instead, which transfers the current
+    /// content of the renamed file.
+    pub(crate) async fn push_rename<R, S, F>(
+        &self,
+        from: PathBuf,
+        to: PathBuf,
+        rename: R,
+        sync: S,
+        on_done: F,
+    ) where
+        R: Future<Output = anyhow::Result<()>> + Send + 'static,
+        S: Future<Output = anyhow::Result<()>> + Send + 'static,
+        F: FnOnce(&anyhow::Result<()>) -> BoxFutureResult + Send + 'static,
+    {
+        let mut queued = self.queued.lock().await;
+        let id = TaskId::Rename {
+            from: from.clone(),
+            to: to.clone(),
+        };
+        let mut on_done: Vec<OnDone> = vec![Box::new(on_done)];
+        on_done.append(&mut Self::cancel_syncs(&mut queued, &[&from, &to], &id));
+        self.enqueue(&mut queued, id, Box::pin(rename), Vec::new());
+        self.enqueue(&mut queued, TaskId::Sync(to), Box::pin(sync), on_done);
+    }
 
-        Ok(())
+    /// Cancels the queued syncs of paths under `paths` undone by the task `id`, returning their
+    /// callbacks.
+    fn cancel_syncs(queued: &mut VecDeque<Task>, paths: &[&PathBuf], id: &TaskId) -> Vec<OnDone> {
+        let mut on_done = Vec::new();
+        queued.retain_mut(|task| {
+            let TaskId::Sync(path) = &task.id else {
+                return true;
+            };
+            if !paths.iter().any(|undone| path.starts_with(undone)) {
+                return true;
+            }
+
+            log::debug!("cancelling task {:?} undone by task {id:?}", task.id);
+            on_done.append(&mut task.on_done);
+            false
+        });
+
+        on_done
     }
 
-    async fn start(
-        mut rx: mpsc::UnboundedReceiver<Task>,
-        active_worker: Arc<Mutex<HashSet<String>>>,
+    async fn queue(&self, id: TaskId, fut: BoxFutureResult, on_done: Vec<OnDone>) {
+        let mut queued = self.queued.lock().await;
+        self.enqueue(&mut queued, id, fut, on_done);
+    }
+
+    fn enqueue(
+        &self,
+        queued: &mut VecDeque<Task>,
+        id: TaskId,
+        fut: BoxFutureResult,
+        mut on_done: Vec<OnDone>,
     ) {
-        while let Some((id, task)) = rx.recv().await {
-            if let Err(e) = task.await {
-                log::error!("task `{id}` failed: {e:#}");
+        let mergeable = queued.iter().rposition(|task| task.id == id).filter(|&i| {
+            !queued
+                .iter()
+                .skip(i + 1)
+                .any(|task| task.id.is_ordered_with(&id))
+        });
+        if let Some(i) = mergeable {
+            log::debug!("merging task {id:?} into the queued one");
+            queued[i].on_done.append(&mut on_done);
+            return;
+        }
+
+        queued.push_back(Task { id, on_done, fut });
+        self.notify.notify_one();
+    }
+
+    async fn start(queued: Arc<Mutex<VecDeque<Task>>>, notify: Arc<Notify>) {
+        loop {
+            // Not locked while waiting, so that tasks can be queued
+            let task = queued.lock().await.pop_front();
+            let Some(task) = task else {
+                notify.notified().await;
+                continue;
+            };
+
+            let res = task.fut.await;
+            if let Err(e) = &res {
+                log::error!("task {:?} failed: {e:#}", task.id);
             }
 
-            let mut active = active_worker.lock().await;
-            active.remove(&id);
+            for on_done in task.on_done {
+                if let Err(e) = on_done(&res).await {
+                    log::error!("failed to complete task {:?}: {e:#}", task.id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::*;
+
+    /// Records the tasks run by a workqueue and the changes completed by them.
+    struct Recorder {
+        workqueue: Workqueue,
+        tx: mpsc::UnboundedSender<String>,
+        rx: mpsc::UnboundedReceiver<String>,
+    }
+
+    impl Recorder {
+        /// Returns a recorder whose workqueue is kept busy until the returned sender is used or
+        /// dropped, so that the tasks pushed meanwhile stay queued.
+        async fn blocked() -> (Self, oneshot::Sender<()>) {
+            let workqueue = Workqueue::new();
+            let (unblock_tx, unblock_rx) = oneshot::channel();
+            workqueue
+                .push(TaskId::FullSync, async move {
+                    let _ = unblock_rx.await;
+                    Ok(())
+                })
+                .await;
+
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Self { workqueue, tx, rx }, unblock_tx)
         }
+
+        /// Queues a task applying the change `label`.
+        async fn push(&self, id: TaskId, label: &'static str) {
+            let (run_tx, done_tx) = (self.tx.clone(), self.tx.clone());
+            let fut = async move {
+                run_tx.send(format!("run {label}"))?;
+                Ok(())
+            };
+            let on_done = move |res: &anyhow::Result<()>| -> BoxFutureResult {
+                let state = if res.is_ok() { "done" } else { "failed" };
+                let _ = done_tx.send(format!("{state} {label}"));
+                Box::pin(async { Ok(()) })
+            };
+            self.workqueue.push_tracked(id, fut, on_done).await;
+        }
+
+        /// Queues a rename applying the change `label`, followed by the sync of `to`.
+        async fn push_rename(&self, from: &str, to: &str, label: &'static str) {
+            let (rename_tx, sync_tx, done_tx) = (self.tx.clone(), self.tx.clone(), self.tx.clone());
+            let rename = async move {
+                rename_tx.send(format!("run {label}"))?;
+                Ok(())
+            };
+            let sync = async move {
+                sync_tx.send(format!("run {label} sync"))?;
+                Ok(())
+            };
+            let on_done = move |res: &anyhow::Result<()>| -> BoxFutureResult {
+                let state = if res.is_ok() { "done" } else { "failed" };
+                let _ = done_tx.send(format!("{state} {label}"));
+                Box::pin(async { Ok(()) })
+            };
+            self.workqueue
+                .push_rename(from.into(), to.into(), rename, sync, on_done)
+                .await;
+        }
+
+        /// Returns everything recorded once the queued tasks finished.
+        async fn finish(mut self) -> Vec<String> {
+            let tx = self.tx.clone();
+            let id = TaskId::Manifest(PathBuf::from("/end"));
+            self.workqueue
+                .push(id, async move {
+                    tx.send("end".to_string())?;
+                    Ok(())
+                })
+                .await;
+
+            let mut events = Vec::new();
+            while let Some(event) = self.rx.recv().await {
+                if event == "end" {
+                    return events;
+                }
+                events.push(event);
+            }
+
+            events
+        }
+    }
+
+    fn sync(path: &str) -> TaskId {
+        TaskId::Sync(PathBuf::from(path))
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let (recorder, unblock) = Recorder::blocked().await;
+        recorder.push(sync("/dir/file"), "1").await;
+        recorder.push(sync("/dir/other"), "2").await;
+        recorder.push(sync("/dir/file"), "3").await;
+        unblock.send(()).unwrap();
+
+        assert_eq!(
+            recorder.finish().await,
+            ["run 1", "done 1", "done 3", "run 2", "done 2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_by_remove() {
+        let (recorder, unblock) = Recorder::blocked().await;
+        recorder.push(sync("/dir/file"), "sync").await;
+        recorder.push(sync("/other"), "other").await;
+        recorder
+            .push(TaskId::Remove(PathBuf::from("/dir")), "remove")
+            .await;
+        unblock.send(()).unwrap();
+
+        assert_eq!(
+            recorder.finish().await,
+            [
+                "run other",
+                "done other",
+                "run remove",
+                "done remove",
+                "done sync"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_by_rename() {
+        let (recorder, unblock) = Recorder::blocked().await;
+        recorder.push(sync("/from/file"), "from").await;
+        recorder.push(sync("/to/file"), "to").await;
+        recorder.push_rename("/from", "/to", "rename").await;
+        unblock.send(()).unwrap();
+
+        // The cancelled syncs are only done once the renamed file was synced
+        assert_eq!(
+            recorder.finish().await,
+            [
+                "run rename",
+                "run rename sync",
+                "done rename",
+                "done from",
+                "done to"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_failed_sync() {
+        let (recorder, unblock) = Recorder::blocked().await;
+        recorder.push(sync("/from/file"), "from").await;
+        let done_tx = recorder.tx.clone();
+        let on_done = move |res: &anyhow::Result<()>| -> BoxFutureResult {
+            assert!(res.is_err());
+            let _ = done_tx.send("failed rename".to_string());
+            Box::pin(async { Ok(()) })
+        };
+        recorder
+            .workqueue
+            .push_rename(
+                "/from".into(),
+                "/to".into(),
+                async { Ok(()) },
+                async { anyhow::bail!("sync failed") },
+                on_done,
+            )
+            .await;
+        unblock.send(()).unwrap();
+
+        // The renamed file wasn't transferred, so neither change is applied
+        assert_eq!(recorder.finish().await, ["failed rename", "failed from"]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_after_rename_of_ancestor() {
+        let (recorder, unblock) = Recorder::blocked().await;
+        recorder.push(sync("/from/file"), "1").await;
+        recorder.push_rename("/from", "/to", "rename").await;
+        recorder.push(sync("/from/file"), "2").await;
+        unblock.send(()).unwrap();
+
+        // The sync queued before the rename is undone by it, the one after it runs after it
+        assert_eq!(
+            recorder.finish().await,
+            [
+                "run rename",
+                "run rename sync",
+                "done rename",
+                "done 1",
+                "run 2",
+                "done 2"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_after_rename_of_descendant() {
+        let (recorder, unblock) = Recorder::blocked().await;
+        recorder.push(sync("/dir/"), "1").await;
+        recorder.push_rename("/dir/from", "/dir/to", "rename").await;
+        recorder.push(sync("/dir/"), "2").await;
+        unblock.send(()).unwrap();
+
+        // Merging the second sync into the first one would run it before the rename
+        assert_eq!(
+            recorder.finish().await,
+            [
+                "run 1",
+                "done 1",
+                "run rename",
+                "run rename sync",
+                "done rename",
+                "run 2",
+                "done 2"
+            ]
+        );
     }
 }
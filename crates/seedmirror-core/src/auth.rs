@@ -0,0 +1,43 @@
+//! Authentication of clients connecting to the server over TCP.
+
+use std::{fmt, fs, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Secret shared by the server and its clients. Never printed, so that it doesn't end up in logs.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+#[serde(transparent)]
+pub struct Token(String);
+
+impl Token {
+    /// Reads a token from the first line of the file at `path`.
+    pub fn read(path: &Path) -> anyhow::Result<Token> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("failed to read token {path:?}"))?;
+        let token = contents.lines().next().unwrap_or_default().trim();
+        if token.is_empty() {
+            anyhow::bail!("token file {path:?} is empty");
+        }
+
+        Ok(Token(token.to_string()))
+    }
+
+    /// Returns true if both tokens are equal, taking the same time regardless of where they
+    /// differ.
+    pub fn verify(&self, other: &Token) -> bool {
+        let (a, b) = (self.0.as_bytes(), other.0.as_bytes());
+        let diff = a
+            .iter()
+            .zip(b)
+            .fold(a.len() ^ b.len(), |diff, (x, y)| diff | usize::from(x ^ y));
+
+        diff == 0
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(<redacted>)")
+    }
+}
@@ -0,0 +1,294 @@
+use std::{fmt, io};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::message::Message;
+
+/// Default maximum size in bytes of the JSON payload of a single frame.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// Maximum amount of digits in the length prefix of a frame, enough for any `usize`.
+const MAX_LENGTH_DIGITS: usize = 20;
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// Reading from or writing to the underlying stream failed.
+    Io(io::Error),
+
+    /// The length prefix of a frame isn't a valid number.
+    InvalidLength(String),
+
+    /// The payload of a frame is larger than the maximum frame size.
+    Oversize { size: usize, max_frame_size: usize },
+
+    /// The stream ended in the middle of a frame.
+    Truncated { remaining: usize },
+
+    /// The payload of a frame isn't a valid message.
+    BadJson(serde_json::Error),
+}
+
+impl CodecError {
+    /// Returns true if the error is caused by the peer closing the connection.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            CodecError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            CodecError::Truncated { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "i/o error: {e}"),
+            CodecError::InvalidLength(prefix) => {
+                write!(f, "invalid frame length prefix: {prefix:?}")
+            }
+            CodecError::Oversize {
+                size,
+                max_frame_size,
+            } => write!(
+                f,
+                "frame of {size} bytes exceeds the maximum frame size of {max_frame_size} bytes"
+            ),
+            CodecError::Truncated { remaining } => {
+                write!(
+                    f,
+                    "stream ended with {remaining} bytes of an incomplete frame"
+                )
+            }
+            CodecError::BadJson(e) => write!(f, "invalid message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            CodecError::BadJson(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+/// Codec for frames consisting of the payload length in bytes followed by a newline and the
+/// message encoded as JSON.
+#[derive(Clone, Debug)]
+pub struct MessageCodec {
+    max_frame_size: usize,
+}
+
+impl MessageCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    fn check_frame_size(&self, size: usize) -> Result<(), CodecError> {
+        if size > self.max_frame_size {
+            return Err(CodecError::Oversize {
+                size,
+                max_frame_size: self.max_frame_size,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let prefix_end = src
+            .iter()
+            .take(MAX_LENGTH_DIGITS + 1)
+            .position(|b| *b == b'\n');
+
+        let Some(prefix_end) = prefix_end else {
+            if src.len() > MAX_LENGTH_DIGITS {
+                let prefix = String::from_utf8_lossy(&src[..MAX_LENGTH_DIGITS]);
+                return Err(CodecError::InvalidLength(prefix.into_owned()));
+            }
+
+            return Ok(None);
+        };
+
+        let prefix = String::from_utf8_lossy(&src[..prefix_end]);
+        let size: usize = prefix
+            .trim()
+            .parse()
+            .map_err(|_| CodecError::InvalidLength(prefix.to_string()))?;
+        self.check_frame_size(size)?;
+
+        let frame_size = prefix_end + 1 + size;
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+
+        src.advance(prefix_end + 1);
+        let payload = src.split_to(size);
+
+        // Logged after parsing so that secrets are redacted
+        let msg = serde_json::from_slice(&payload).map_err(CodecError::BadJson)?;
+        log::debug!("received message: {msg:?}");
+
+        Ok(Some(msg))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(msg) => Ok(Some(msg)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(CodecError::Truncated {
+                remaining: buf.len(),
+            }),
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let payload = serde_json::to_vec(&item).map_err(CodecError::BadJson)?;
+        self.check_frame_size(payload.len())?;
+
+        let prefix = format!("{}\n", payload.len());
+        dst.reserve(prefix.len() + payload.len());
+        dst.put_slice(prefix.as_bytes());
+        dst.put_slice(&payload);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(payload: &str) -> BytesMut {
+        BytesMut::from(format!("{}\n{payload}", payload.len()).as_str())
+    }
+
+    fn ping_payload() -> String {
+        serde_json::to_string(&Message::Ping).unwrap()
+    }
+
+    #[test]
+    fn test_decode_oversized_length() {
+        let mut codec = MessageCodec::new(16);
+        let mut src = BytesMut::from("17\n");
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(CodecError::Oversize {
+                size: 17,
+                max_frame_size: 16
+            })
+        ));
+    }
+
+    #[test]
+    fn test_decode_invalid_length() {
+        let mut codec = MessageCodec::default();
+        let mut src = BytesMut::from("abc\n{}");
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(CodecError::InvalidLength(_))
+        ));
+
+        // The newline is expected after at most `MAX_LENGTH_DIGITS` digits
+        let mut src = BytesMut::from("1".repeat(MAX_LENGTH_DIGITS + 1).as_str());
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(CodecError::InvalidLength(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_truncated_frame() {
+        let mut codec = MessageCodec::default();
+        let mut src = frame(&ping_payload());
+        src.truncate(src.len() - 1);
+        let remaining = src.len();
+
+        assert!(matches!(codec.decode(&mut src), Ok(None)));
+        let err = codec.decode_eof(&mut src).unwrap_err();
+        assert!(matches!(err, CodecError::Truncated { remaining: r } if r == remaining));
+        assert!(err.is_disconnect());
+    }
+
+    #[test]
+    fn test_decode_invalid_json() {
+        let mut codec = MessageCodec::default();
+        let mut src = frame("{\"type\":");
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(CodecError::BadJson(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_split_frame() {
+        let mut codec = MessageCodec::default();
+        let mut frames = frame(&ping_payload());
+        frames.extend_from_slice(&frame(&serde_json::to_string(&Message::Pong).unwrap()));
+
+        // Fed one byte at a time, each message is decoded once its last byte arrives
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in frames.iter() {
+            src.put_u8(*byte);
+            if let Some(msg) = codec.decode(&mut src).unwrap() {
+                decoded.push(msg);
+            }
+        }
+
+        assert_eq!(decoded, [Message::Ping, Message::Pong]);
+        assert!(src.is_empty());
+        assert!(matches!(codec.decode_eof(&mut src), Ok(None)));
+    }
+
+    #[test]
+    fn test_frame_size_limit() {
+        let payload = ping_payload();
+        let mut codec = MessageCodec::new(payload.len());
+
+        let mut dst = BytesMut::new();
+        codec.encode(Message::Ping, &mut dst).unwrap();
+        assert_eq!(dst, frame(&payload));
+        assert_eq!(codec.decode(&mut dst).unwrap(), Some(Message::Ping));
+
+        let mut codec = MessageCodec::new(payload.len() - 1);
+        assert!(matches!(
+            codec.encode(Message::Ping, &mut BytesMut::new()),
+            Err(CodecError::Oversize { .. })
+        ));
+        assert!(matches!(
+            codec.decode(&mut frame(&payload)),
+            Err(CodecError::Oversize { .. })
+        ));
+    }
+}
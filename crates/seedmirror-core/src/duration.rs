@@ -0,0 +1,23 @@
+//! Parsing of the durations configured on the client and the server, given in milliseconds.
+
+use std::time::Duration;
+
+/// Parses a duration in milliseconds.
+pub fn parse_millis(s: &str) -> Result<Duration, String> {
+    s.parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|e| format!("invalid duration '{}': {}", s, e))
+}
+
+/// Parses a duration in milliseconds that can't be zero, see [`nonzero`].
+pub fn parse_nonzero_millis(s: &str) -> Result<Duration, String> {
+    nonzero(parse_millis(s)?)
+}
+
+/// Fails if `duration` is zero, for durations that can't be, e.g. intervals.
+pub fn nonzero(duration: Duration) -> Result<Duration, String> {
+    match duration {
+        Duration::ZERO => Err("duration must be greater than 0".to_string()),
+        duration => Ok(duration),
+    }
+}
@@ -0,0 +1,196 @@
+//! Include/exclude rules deciding which files under a watched path are synchronized.
+//!
+//! Patterns are globs (`*`, `?` and `[...]`) matched against the names of files and directories,
+//! behaving like rsync filter patterns without a slash. A path is excluded if any of its
+//! components matches an exclude pattern, so excluding a directory excludes everything inside
+//! it. If there are include patterns, files must additionally match one of them, while
+//! directories are always included. Merged rules keep the include patterns of each set apart, and
+//! files must match one pattern of every set.
+
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct FilterRules {
+    /// Patterns of file names to synchronize. All files are synchronized if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+
+    /// Patterns of file and directory names to never synchronize.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+
+    /// Further sets of patterns that files must also match one of, added by merging rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include_also: Vec<Vec<String>>,
+}
+
+impl FilterRules {
+    /// Returns an error if `pattern` can't be used as an include or exclude pattern.
+    pub fn validate_pattern(pattern: &str) -> Result<String, String> {
+        if pattern.contains('/') {
+            return Err(format!(
+                "pattern {pattern:?} must match a file or directory name and can't contain '/'"
+            ));
+        }
+
+        Glob::new(pattern).map_err(|e| format!("invalid pattern {pattern:?}: {e}"))?;
+        Ok(pattern.to_string())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include_sets().next().is_none() && self.exclude.is_empty()
+    }
+
+    /// Returns the rules of both `self` and `other`, only synchronizing files that both of them
+    /// synchronize.
+    pub fn merge(&self, other: &FilterRules) -> FilterRules {
+        let mut include_sets = self.include_sets().chain(other.include_sets()).cloned();
+        FilterRules {
+            include: include_sets.next().unwrap_or_default(),
+            exclude: [self.exclude.as_slice(), &other.exclude].concat(),
+            include_also: include_sets.collect(),
+        }
+    }
+
+    pub fn compile(&self) -> anyhow::Result<Filter> {
+        Ok(Filter {
+            include: self
+                .include_sets()
+                .map(|patterns| build_glob_set(patterns))
+                .collect::<anyhow::Result<_>>()?,
+            exclude: build_glob_set(&self.exclude)?,
+        })
+    }
+
+    /// Returns the rsync arguments applying the same rules. rsync can only require files to match
+    /// every set of include patterns if all sets but one contain a single pattern, so other rules
+    /// are refused rather than transferring files that only match some of the sets.
+    pub fn rsync_args(&self) -> anyhow::Result<Vec<String>> {
+        let mut args: Vec<_> = self
+            .exclude
+            .iter()
+            .map(|pattern| format!("--exclude={pattern}"))
+            .collect();
+
+        // The set listed last is included pattern by pattern, the others exclude files that don't
+        // match their only pattern
+        let mut include_sets: Vec<_> = self.include_sets().collect();
+        include_sets.sort_by_key(|patterns| patterns.len());
+        let Some(last_set) = include_sets.pop() else {
+            return Ok(args);
+        };
+
+        // Directories must be included for rsync to look for included files inside them
+        args.push("--include=*/".to_string());
+        for patterns in include_sets {
+            match patterns.as_slice() {
+                [pattern] => args.push(format!("--filter=-! {pattern}")),
+                _ => anyhow::bail!(
+                    "rsync can't require files to match one of {patterns:?} as well as one of \
+                    {last_set:?}, only one set of merged include patterns may have more than one \
+                    pattern"
+                ),
+            }
+        }
+        args.extend(last_set.iter().map(|pattern| format!("--include={pattern}")));
+        args.push("--exclude=*".to_string());
+
+        Ok(args)
+    }
+
+    /// Returns the non-empty sets of include patterns.
+    fn include_sets(&self) -> impl Iterator<Item = &Vec<String>> {
+        std::iter::once(&self.include)
+            .chain(&self.include_also)
+            .filter(|patterns| !patterns.is_empty())
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Compiled [`FilterRules`].
+#[derive(Clone, Debug)]
+pub struct Filter {
+    /// Non-empty sets of include patterns.
+    include: Vec<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    /// Returns true if `relative_path` (relative to the watched path) should be synchronized.
+    /// `is_dir` should be true if the path is, or might be, a directory.
+    pub fn is_match(&self, relative_path: &Path, is_dir: bool) -> bool {
+        let excluded = relative_path
+            .components()
+            .any(|component| self.exclude.is_match(component.as_os_str()));
+        if excluded {
+            return false;
+        }
+
+        if is_dir {
+            return true;
+        }
+
+        let name = relative_path.file_name().unwrap_or_default();
+        self.include.iter().all(|include| include.is_match(name))
+    }
+}
+
+/// Filter rules for a single watched path.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct WatchedPathFilter {
+    #[serde(with = "crate::path")]
+    pub path: PathBuf,
+
+    #[serde(flatten)]
+    pub rules: FilterRules,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(include: &[&str]) -> FilterRules {
+        FilterRules {
+            include: include.iter().map(|pattern| pattern.to_string()).collect(),
+            ..FilterRules::default()
+        }
+    }
+
+    #[test]
+    fn test_rsync_args() {
+        let merged = rules(&["*.mkv", "*.mp4"])
+            .merge(&rules(&["movie*"]))
+            .merge(&FilterRules {
+                exclude: vec!["*.part".to_string()],
+                ..FilterRules::default()
+            });
+        assert_eq!(
+            merged.rsync_args().unwrap(),
+            [
+                "--exclude=*.part",
+                "--include=*/",
+                "--filter=-! movie*",
+                "--include=*.mkv",
+                "--include=*.mp4",
+                "--exclude=*",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rsync_args_refused() {
+        let merged = rules(&["*.mkv", "*.mp4"]).merge(&rules(&["movie*", "show*"]));
+        assert!(merged.rsync_args().is_err());
+    }
+}
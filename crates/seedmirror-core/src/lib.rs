@@ -1 +1,7 @@
+pub mod auth;
+pub mod codec;
+pub mod duration;
+pub mod filter;
+pub mod manifest;
 pub mod message;
+pub mod path;
@@ -0,0 +1,131 @@
+//! Listings of all files under a directory, used to find differences between the server and the
+//! client without running rsync.
+
+use std::{
+    fs::{self, Metadata},
+    io,
+    ops::ControlFlow,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::filter::Filter;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+
+    /// Sockets, devices, etc. and kinds sent by a newer peer that this version doesn't know about.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ManifestEntry {
+    /// Path relative to the listed directory.
+    #[serde(with = "crate::path")]
+    pub path: PathBuf,
+
+    pub kind: EntryKind,
+
+    /// Size in bytes.
+    pub size: u64,
+
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: i64,
+}
+
+impl ManifestEntry {
+    fn new(path: PathBuf, metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_file() {
+            EntryKind::File
+        } else if file_type.is_dir() {
+            EntryKind::Directory
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::Other
+        };
+
+        Self {
+            path,
+            kind,
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+        }
+    }
+}
+
+/// Lists everything under `root` that passes `filter`, without following symlinks. Directories are
+/// listed before their contents. Entries removed while walking are skipped, any other error fails
+/// the walk so that an incomplete listing is never mistaken for missing files.
+pub fn walk(root: &Path, filter: &Filter) -> io::Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    walk_each(root, filter, |entry| {
+        entries.push(entry);
+        ControlFlow::Continue(())
+    })?;
+
+    Ok(entries)
+}
+
+/// Same as [`walk`], but passes each entry to `f` as soon as it's found instead of collecting
+/// them. The walk stops early if `f` returns [`ControlFlow::Break`].
+pub fn walk_each(
+    root: &Path,
+    filter: &Filter,
+    mut f: impl FnMut(ManifestEntry) -> ControlFlow<()>,
+) -> io::Result<()> {
+    let mut dirs = vec![fs::read_dir(root)?];
+
+    while let Some(dir) = dirs.last_mut() {
+        let Some(res) = dir.next() else {
+            dirs.pop();
+            continue;
+        };
+
+        let (path, metadata) = match res.and_then(|entry| Ok((entry.path(), entry.metadata()?))) {
+            Ok(res) => res,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failed to read entry under {root:?}: {e}"),
+                ));
+            }
+        };
+
+        let relative_path = path
+            .strip_prefix(root)
+            .expect("entry should be under root")
+            .to_path_buf();
+        if !filter.is_match(&relative_path, metadata.is_dir()) {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            match fs::read_dir(&path) {
+                Ok(dir) => dirs.push(dir),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("failed to read directory {path:?}: {e}"),
+                    ));
+                }
+            }
+        }
+
+        if f(ManifestEntry::new(relative_path, &metadata)).is_break() {
+            break;
+        }
+    }
+
+    Ok(())
+}
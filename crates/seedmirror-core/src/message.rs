@@ -1,64 +1,325 @@
 use serde::{Deserialize, Serialize};
-use std::{io::ErrorKind, path::PathBuf};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    auth::Token,
+    filter::{FilterRules, WatchedPathFilter},
+    manifest::ManifestEntry,
+};
+
+/// Version of the wire protocol. Only bumped for changes that can't be negotiated through
+/// [`Capability`], since peers with different versions refuse to talk to each other.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features. A feature is only used if both peers advertise it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// The peer understands `FileRemoved` messages.
+    FileRemoved,
+
+    /// The peer understands `FileRenamed` messages.
+    FileRenamed,
+
+    /// The peer answers `Ping` messages and sends its own on an interval.
+    Heartbeat,
+
+    /// The peer understands paths that aren't valid UTF-8, see [`crate::path`].
+    BinaryPaths,
+
+    /// The server only reports files matching the filters in `ConnectionRequest`.
+    Filters,
+
+    /// The server answers `ManifestRequest` messages.
+    Manifest,
+
+    /// The peer understands sequence numbers of updates recorded in the server's journal, which
+    /// are replayed to reconnecting clients.
+    Journal,
+
+    /// The client acknowledges every `FileUpdated` message once it synced the file, and the
+    /// server delivers it again until it's acknowledged.
+    Ack,
+
+    /// The client understands `ResyncRequired` messages.
+    Resync,
+
+    /// Capability advertised by a newer peer that this version doesn't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    /// Returns the capabilities supported by this version.
+    pub fn supported() -> Vec<Capability> {
+        vec![
+            Capability::FileRemoved,
+            Capability::FileRenamed,
+            Capability::Heartbeat,
+            Capability::BinaryPaths,
+            Capability::Filters,
+            Capability::Manifest,
+            Capability::Journal,
+            Capability::Ack,
+            Capability::Resync,
+        ]
+    }
+
+    /// Returns the capabilities supported by both this version and the peer.
+    pub fn negotiate(peer_capabilities: &[Capability]) -> Vec<Capability> {
+        Self::supported()
+            .into_iter()
+            .filter(|capability| peer_capabilities.contains(capability))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The peers speak different protocol versions.
+    IncompatibleProtocol,
+
+    /// The request is malformed, e.g. because of an invalid filter pattern.
+    InvalidRequest,
+
+    /// The client didn't provide a valid token.
+    Unauthorized,
+
+    /// The client requested to watch a path outside the roots allowed by the server.
+    PathNotAllowed,
+
+    /// The server reached its limit of file watches, so changes under a watched path might not
+    /// be reported.
+    WatchLimitReached,
+
+    /// The server failed to list the files under a path requested with `ManifestRequest`.
+    ManifestFailed,
+
+    /// Watching a path failed on the server, so changes under it might not have been reported.
+    /// Sent when a watched path can't be watched, e.g. because it doesn't exist, and to clients
+    /// not advertising [`Capability::Resync`] instead of `ResyncRequired`.
+    WatchFailed,
+
+    /// Error code sent by a newer peer that this version doesn't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Returns true if the connection is terminated after the error is sent.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            ErrorCode::IncompatibleProtocol
+            | ErrorCode::InvalidRequest
+            | ErrorCode::Unauthorized
+            | ErrorCode::PathNotAllowed => true,
+            ErrorCode::WatchLimitReached
+            | ErrorCode::ManifestFailed
+            | ErrorCode::WatchFailed
+            | ErrorCode::Unknown => false,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 #[serde(tag = "message")]
 pub enum Message {
     ConnectionRequest {
+        /// Protocol version spoken by the client. Missing for clients predating versioning.
+        #[serde(default)]
+        protocol_version: u32,
+
+        /// Optional features supported by the client.
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+
         /// List of paths to watch.
+        #[serde(with = "crate::path::vec")]
         watched_paths: Vec<PathBuf>,
+
+        /// Rules for which files to report under each watched path. Only respected by servers
+        /// advertising [`Capability::Filters`].
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        filters: Vec<WatchedPathFilter>,
+
+        /// Token authenticating the client, required by servers listening on TCP with a token.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<Token>,
+
+        /// Sequence number of the last update synced by the client. Servers with a journal replay
+        /// the updates recorded after it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seq: Option<u64>,
+
+        /// ID of the journal that `last_seq` refers to, as sent in `Connected`. Updates are only
+        /// replayed if it's the ID of the server's journal.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        journal_id: Option<String>,
     },
 
     /// Sent by the server to acknowledge a `ConnectionRequest`.
-    Connected,
+    Connected {
+        /// Protocol version spoken by the server. Missing for servers predating versioning.
+        #[serde(default)]
+        protocol_version: u32,
+
+        /// Optional features supported by both the server and the client, which are the only ones
+        /// used on the connection.
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+
+        /// Rules configured on the server that apply to all watched paths in addition to the
+        /// client's filters.
+        #[serde(default, skip_serializing_if = "FilterRules::is_empty")]
+        filters: FilterRules,
+
+        /// True if the server replays all updates recorded after the client's `last_seq`, in
+        /// which case the client doesn't need to look for files it missed while disconnected.
+        #[serde(default)]
+        replaying: bool,
+
+        /// ID of the server's journal, which changes if it's recreated and sequence numbers start
+        /// over. Only sent to clients advertising [`Capability::Journal`] by servers with a
+        /// journal.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        journal_id: Option<String>,
+    },
+
+    /// Sent by either peer to check that the connection is alive. Only sent to peers
+    /// advertising [`Capability::Heartbeat`].
+    Ping,
+
+    /// Answer to a `Ping`.
+    Pong,
+
+    /// Sent by the server when a request can't be fulfilled.
+    Error { code: ErrorCode, reason: String },
 
     /// Sent when a file is updated.
     FileUpdated {
         /// Full (absolute) updated path.
+        #[serde(with = "crate::path")]
         path: PathBuf,
+
+        /// Sequence number of the update in the server's journal. Only sent to clients advertising
+        /// [`Capability::Journal`] by servers with a journal.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
     },
-}
 
-impl Message {
-    /// Returns true if the connection is broken and should be terminated.
-    pub async fn write_to_stream(
-        &self,
-        mut stream: impl AsyncWriteExt + Unpin,
-    ) -> anyhow::Result<bool> {
-        let json = format!("{}\n", serde_json::to_string_pretty(self)?);
-        let content_length = json.len();
-        let payload = format!("{content_length}\n{json}");
-        let write_result = stream.write_all(payload.as_bytes()).await;
-
-        if let Err(e) = write_result {
-            match e.kind() {
-                ErrorKind::BrokenPipe => {
-                    return Ok(true);
-                }
-                _ => {
-                    return Err(anyhow::anyhow!(e).context("failed writing to socket"));
-                }
-            }
-        }
+    /// Sent when a file is removed. Only sent to clients advertising
+    /// [`Capability::FileRemoved`].
+    FileRemoved {
+        /// Full (absolute) removed path.
+        #[serde(with = "crate::path")]
+        path: PathBuf,
 
-        Ok(false)
-    }
+        /// Sequence number of the removal in the server's journal, like for `FileUpdated`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+
+    /// Sent when a file is moved within the watched paths. Only sent to clients advertising
+    /// [`Capability::FileRenamed`].
+    FileRenamed {
+        /// Full (absolute) path before the rename.
+        #[serde(with = "crate::path")]
+        from: PathBuf,
+
+        /// Full (absolute) path after the rename.
+        #[serde(with = "crate::path")]
+        to: PathBuf,
+
+        /// Sequence number of the rename in the server's journal, like for `FileUpdated`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+
+    /// Sent by the client once the file of a `FileUpdated` message was synced. Only sent to
+    /// servers advertising [`Capability::Ack`].
+    Ack {
+        /// Path of the acknowledged `FileUpdated` message.
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+
+        /// Sequence number of the acknowledged `FileUpdated` message, if it had one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+
+    /// Sent by the client if the file of a `FileUpdated` message couldn't be synced, so that the
+    /// server delivers it again later. Only sent to servers advertising [`Capability::Ack`].
+    Nack {
+        /// Path of the `FileUpdated` message.
+        #[serde(with = "crate::path")]
+        path: PathBuf,
 
-    pub async fn read_from_reader<R>(reader: &mut R) -> anyhow::Result<Self>
-    where
-        R: AsyncReadExt + AsyncBufReadExt + Unpin,
-    {
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
+        /// Sequence number of the `FileUpdated` message, if it had one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
 
-        let content_length: usize = line.trim().parse()?;
-        let mut msg_bytes = vec![0; content_length];
-        reader.read_exact(&mut msg_bytes).await?;
+        reason: String,
+    },
+
+    /// Sent when updates under watched paths may have been missed, e.g. because the client
+    /// couldn't keep up with a burst of changes, so that the client syncs them in full. Only sent
+    /// to clients advertising [`Capability::Resync`].
+    ResyncRequired {
+        /// Full (absolute) watched paths that may have missed updates, as sent in
+        /// `ConnectionRequest`.
+        #[serde(with = "crate::path::vec")]
+        paths: Vec<PathBuf>,
+    },
 
-        log::debug!("received message: {}", String::from_utf8_lossy(&msg_bytes));
-        let msg: Message = serde_json::from_slice(&msg_bytes)?;
+    /// Sent by the client to list all files under a watched path. Only sent to servers
+    /// advertising [`Capability::Manifest`].
+    ManifestRequest {
+        /// Full (absolute) watched path, as sent in `ConnectionRequest`.
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+    },
 
-        Ok(msg)
+    /// Answer to a `ManifestRequest`, split over several messages to stay within the maximum
+    /// frame size. Entries are filtered by the same rules as change notifications.
+    Manifest {
+        /// Full (absolute) listed path.
+        #[serde(with = "crate::path")]
+        path: PathBuf,
+
+        entries: Vec<ManifestEntry>,
+
+        /// True for the last message of the manifest.
+        done: bool,
+    },
+}
+
+impl Message {
+    /// Returns all paths contained in the message.
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Message::ConnectionRequest {
+                watched_paths,
+                filters,
+                ..
+            } => watched_paths
+                .iter()
+                .chain(filters.iter().map(|filter| &filter.path))
+                .map(PathBuf::as_path)
+                .collect(),
+            Message::FileUpdated { path, .. }
+            | Message::FileRemoved { path, .. }
+            | Message::ManifestRequest { path }
+            | Message::Ack { path, .. }
+            | Message::Nack { path, .. } => vec![path],
+            Message::Manifest { path, entries, .. } => std::iter::once(path.as_path())
+                .chain(entries.iter().map(|entry| entry.path.as_path()))
+                .collect(),
+            Message::FileRenamed { from, to, .. } => vec![from, to],
+            Message::ResyncRequired { paths } => paths.iter().map(PathBuf::as_path).collect(),
+            Message::Connected { .. } | Message::Error { .. } | Message::Ping | Message::Pong => {
+                vec![]
+            }
+        }
     }
 }
@@ -0,0 +1,127 @@
+//! Lossless encoding of paths in messages.
+//!
+//! Paths that are valid UTF-8 are encoded as plain JSON strings, which is also how paths were
+//! encoded before non-UTF-8 paths were supported. Other paths are encoded as an object containing
+//! the raw bytes of the path in base64, e.g. `{"base64": "..."}`. Peers must advertise
+//! [`Capability::BinaryPaths`](crate::message::Capability::BinaryPaths) to receive the latter.
+
+use std::{
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum WirePath {
+    Utf8(String),
+    Bytes { base64: String },
+}
+
+impl From<&Path> for WirePath {
+    fn from(path: &Path) -> Self {
+        match path.to_str() {
+            Some(s) => WirePath::Utf8(s.to_string()),
+            None => WirePath::Bytes {
+                base64: STANDARD.encode(path.as_os_str().as_bytes()),
+            },
+        }
+    }
+}
+
+impl WirePath {
+    fn into_path_buf<E: serde::de::Error>(self) -> Result<PathBuf, E> {
+        match self {
+            WirePath::Utf8(s) => Ok(PathBuf::from(s)),
+            WirePath::Bytes { base64 } => STANDARD
+                .decode(base64)
+                .map(|bytes| PathBuf::from(OsString::from_vec(bytes)))
+                .map_err(E::custom),
+        }
+    }
+}
+
+/// Returns true if `path` can be sent to peers without
+/// [`Capability::BinaryPaths`](crate::message::Capability::BinaryPaths).
+pub fn is_utf8(path: &Path) -> bool {
+    path.to_str().is_some()
+}
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    WirePath::from(path).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    WirePath::deserialize(deserializer)?.into_path_buf()
+}
+
+/// Same as the parent module, but for lists of paths.
+pub mod vec {
+    use std::path::PathBuf;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::WirePath;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        paths
+            .iter()
+            .map(|path| WirePath::from(path.as_path()))
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PathBuf>, D::Error> {
+        Vec::<WirePath>::deserialize(deserializer)?
+            .into_iter()
+            .map(WirePath::into_path_buf)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use crate::message::Message;
+
+    use super::*;
+
+    #[test]
+    fn test_utf8_path_round_trip() {
+        let msg = Message::FileUpdated {
+            path: PathBuf::from("/watched/café.mkv"),
+            seq: None,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""path":"/watched/café.mkv""#));
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_non_utf8_path_round_trip() {
+        let path = PathBuf::from(OsStr::from_bytes(b"/watched/caf\xe9.mkv"));
+        assert!(!is_utf8(&path));
+
+        let msg = Message::FileUpdated {
+            path: path.clone(),
+            seq: None,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        let base64 = STANDARD.encode(path.as_os_str().as_bytes());
+        assert!(json.contains(&format!(r#""path":{{"base64":"{base64}"}}"#)));
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), msg);
+
+        let paths = vec![path, PathBuf::from("/watched")];
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        vec::serialize(&paths, &mut serializer).unwrap();
+        let json = serializer.into_inner();
+        let mut deserializer = serde_json::Deserializer::from_slice(&json);
+        assert_eq!(vec::deserialize(&mut deserializer).unwrap(), paths);
+    }
+}
@@ -1,24 +1,376 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum, builder::RangedU64ValueParser};
+use log::LevelFilter;
+use seedmirror_core::{codec::DEFAULT_MAX_FRAME_SIZE, duration, filter::FilterRules};
+use serde::Deserialize;
+
+use crate::torrent::HttpUrl;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about)]
 pub(crate) struct Args {
+    /// TOML file setting the socket path, completion settings, allowed roots, filters and log
+    /// level, using the names of the corresponding flags as keys, e.g. `sync-delay = 5000` or
+    /// `allowed-roots = ["/srv/torrents"]`. Flags given on the command line take precedence over
+    /// it. The file is read again when the server receives SIGHUP.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Minimum level of logged messages. Ignored if `RUST_LOG` is set.
+    #[arg(long, default_value_t = LevelFilter::Info)]
+    pub log_level: LevelFilter,
+
     // TODO: More platform-agnostic tempdir
     /// Path to unix domain socket used to communicate with server.
     #[arg(short, long, default_value_os_t = PathBuf::from("/tmp/seedmirror-server.sock"))]
     pub socket_path: PathBuf,
 
-    /// Delay in milliseconds before file modifications are reported to the client.
-    #[arg(long, default_value = "10000", value_parser = Self::parse_millis)]
+    /// Permissions of the unix domain socket, in octal. Connecting requires write permission, so
+    /// users allowed with `--allowed-uid` or `--allowed-gid` also need it, e.g. with 660 and a
+    /// group the socket's owner shares with them.
+    #[arg(long, value_name = "MODE", default_value = "600", value_parser = Self::parse_mode)]
+    pub socket_mode: u32,
+
+    /// Also allow the user to connect over the unix domain socket, in addition to the user the
+    /// server runs as. Specify multiple times to allow multiple users.
+    #[arg(long = "allowed-uid", value_name = "UID")]
+    pub allowed_uids: Vec<u32>,
+
+    /// Also allow users whose primary group is the group to connect over the unix domain socket.
+    /// Specify multiple times to allow multiple groups.
+    #[arg(long = "allowed-gid", value_name = "GID")]
+    pub allowed_gids: Vec<u32>,
+
+    /// Also listen for TLS connections on the TCP address, e.g. `0.0.0.0:7878`. Requires
+    /// `--tls-cert`, `--tls-key` and at least one of `--token-file` and `--tls-client-ca`.
+    #[arg(long, value_name = "ADDRESS", requires_all = ["tls_cert", "tls_key"])]
+    pub listen: Option<SocketAddr>,
+
+    /// PEM file containing the certificate chain presented to clients connecting over TCP.
+    #[arg(long, value_name = "PATH")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file containing the private key of `--tls-cert`.
+    #[arg(long, value_name = "PATH")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM file containing the certificate authorities that client certificates must be signed
+    /// by. Clients connecting over TCP must present a certificate if set.
+    #[arg(long, value_name = "PATH")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// File containing the token that clients connecting over TCP must provide.
+    #[arg(long, value_name = "PATH")]
+    pub token_file: Option<PathBuf>,
+
+    /// Only allow clients to watch paths under the directory. Requested paths are checked once
+    /// symlinks and `..` components are resolved, and symlinks inside watched paths aren't
+    /// followed, nor are they when using a configuration file, which can set allowed roots on
+    /// reload. Specify multiple times to allow multiple directories. All paths are allowed if not
+    /// specified.
+    #[arg(long = "allowed-root", value_name = "PATH", value_parser = Self::parse_allowed_root)]
+    pub allowed_roots: Vec<PathBuf>,
+
+    /// Serve Prometheus metrics over HTTP on the loopback address, e.g. `127.0.0.1:9187`. The
+    /// metrics aren't authenticated, so only loopback addresses are accepted.
+    #[arg(long, value_name = "ADDRESS", value_parser = Self::parse_loopback_address)]
+    pub metrics_address: Option<SocketAddr>,
+
+    /// Serve Prometheus metrics over HTTP on the unix domain socket.
+    #[arg(long, value_name = "PATH")]
+    pub metrics_socket_path: Option<PathBuf>,
+
+    /// Permissions of the metrics unix domain socket, in octal.
+    #[arg(long, value_name = "MODE", default_value = "600", value_parser = Self::parse_mode)]
+    pub metrics_socket_mode: u32,
+
+    /// Strategy used to decide when an updated file is complete and can be reported to clients.
+    #[arg(long, value_enum, default_value_t = CompletionStrategy::Delay)]
+    pub completion: CompletionStrategy,
+
+    /// Delay in milliseconds before file modifications are reported to the client. When using the
+    /// `close-write` completion strategy, the delay starts when the file is closed instead.
+    #[arg(long, default_value = "10000", value_parser = duration::parse_millis)]
     pub sync_delay: Duration,
+
+    /// Delay in milliseconds before modifications of files that are never closed are reported
+    /// when using the `close-write` completion strategy.
+    #[arg(long, default_value = "60000", value_parser = duration::parse_millis)]
+    pub unclosed_write_delay: Duration,
+
+    /// Interval in milliseconds between checks of the size and modification time of updated
+    /// files when using the `stable` completion strategy.
+    #[arg(long, default_value = "5000", value_parser = duration::parse_nonzero_millis)]
+    pub stability_check_interval: Duration,
+
+    /// Duration in milliseconds that the size and modification time of an updated file must stay
+    /// unchanged for the file to be reported when using the `stable` completion strategy.
+    #[arg(long, default_value = "30000", value_parser = duration::parse_millis)]
+    pub stability_window: Duration,
+
+    /// Maximum duration in milliseconds to wait for an updated file to become stable before
+    /// reporting it anyway when using the `stable` completion strategy.
+    #[arg(long, default_value = "3600000", value_parser = duration::parse_millis)]
+    pub stability_max_wait: Duration,
+
+    /// Backend used to watch paths for changes.
+    #[arg(long, value_enum, default_value_t = WatcherBackend::Auto)]
+    pub watcher_backend: WatcherBackend,
+
+    /// Always watch watched paths under the path by polling, regardless of `--watcher-backend`.
+    /// Specify multiple times to poll multiple paths.
+    #[arg(long = "poll-path", value_name = "PATH", value_parser = Self::parse_absolute_path)]
+    pub poll_paths: Vec<PathBuf>,
+
+    /// Poll watched paths that can't be watched using inotify because the inotify watch limit
+    /// (`fs.inotify.max_user_watches`) was reached, instead of not watching them. Directories
+    /// created under a watched path once the limit is reached are polled by themselves.
+    #[arg(long)]
+    pub poll_on_watch_limit: bool,
+
+    /// Interval in milliseconds between scans of paths watched by polling.
+    #[arg(long, default_value = "10000", value_parser = duration::parse_millis)]
+    pub poll_interval: Duration,
+
+    /// Source of the updates reported to clients. When using a torrent client, only the content
+    /// of torrents is reported once they complete, and other changes to watched paths are
+    /// ignored.
+    #[arg(long, value_enum, default_value_t = EventSource::Filesystem)]
+    pub event_source: EventSource,
+
+    /// Base URL of the torrent client's API, e.g. `http://127.0.0.1:8080` for the qBittorrent
+    /// WebUI or `http://127.0.0.1:9091` for Transmission. Only plain HTTP is supported.
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = HttpUrl::parse,
+        required_if_eq_any = [("event_source", "qbittorrent"), ("event_source", "transmission")]
+    )]
+    pub torrent_client_url: Option<HttpUrl>,
+
+    /// User name used to log in to the torrent client, if it requires authentication.
+    #[arg(long, value_name = "NAME", requires = "torrent_client_password_file")]
+    pub torrent_client_username: Option<String>,
+
+    /// File containing the password of `--torrent-client-username`.
+    #[arg(long, value_name = "PATH", requires = "torrent_client_username")]
+    pub torrent_client_password_file: Option<PathBuf>,
+
+    /// Interval in milliseconds between requests for the state of the torrents to the torrent
+    /// client.
+    #[arg(long, default_value = "10000", value_parser = duration::parse_nonzero_millis)]
+    pub torrent_poll_interval: Duration,
+
+    /// Store the hashes of the completed torrents in the file, so that torrents completed while the
+    /// server was stopped are reported when it starts. Without it, only torrents completed after
+    /// the server started are reported.
+    #[arg(long, value_name = "PATH")]
+    pub torrent_state_path: Option<PathBuf>,
+
+    /// Record reported updates, renames and removals in the file, so that they can be replayed to
+    /// clients that were disconnected when they happened.
+    #[arg(long, value_name = "PATH")]
+    pub journal_path: Option<PathBuf>,
+
+    /// Maximum amount of changes kept in the journal. Clients disconnected for longer than it
+    /// takes to record that many changes are asked to sync their watched paths in full.
+    #[arg(
+        long,
+        default_value_t = 100000,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub journal_max_entries: usize,
+
+    /// Duration in milliseconds that paths keep being watched for the journal after the last
+    /// client watching them disconnected. Clients disconnected for longer sync those paths in
+    /// full when reconnecting.
+    #[arg(long, default_value = "604800000", value_parser = duration::parse_millis)]
+    pub journal_watch_ttl: Duration,
+
+    /// Run the command with `sh -c` whenever an update is reported to clients. The updated path,
+    /// the watched path it's under and the kind of the last event that updated it (`create`,
+    /// `modify`, `close-write`, `rename`, `torrent-complete` or `other`) are passed in the
+    /// `SEEDMIRROR_PATH`, `SEEDMIRROR_WATCHED_ROOT` and `SEEDMIRROR_EVENT` environment variables.
+    #[arg(long, value_name = "COMMAND")]
+    pub hook_command: Option<String>,
+
+    /// Write a JSON object with the same information as `--hook-command` as a line to the FIFO
+    /// whenever an update is reported to clients. Updates aren't written while no process is
+    /// reading the FIFO.
+    #[arg(long, value_name = "PATH")]
+    pub hook_fifo: Option<PathBuf>,
+
+    /// Maximum duration in milliseconds of a hook command or FIFO write, after which it's aborted.
+    #[arg(long, default_value = "60000", value_parser = duration::parse_millis)]
+    pub hook_timeout: Duration,
+
+    /// Maximum amount of hook commands running at once. Further updates wait for one to finish.
+    #[arg(
+        long,
+        default_value_t = 4,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub hook_concurrency: usize,
+
+    /// Delay in milliseconds after which an update is delivered again to clients supporting
+    /// acknowledgements if they haven't acknowledged it.
+    #[arg(long, default_value = "3600000", value_parser = duration::parse_millis)]
+    pub ack_timeout: Duration,
+
+    /// Delay in milliseconds after which an update is delivered again to clients that failed to
+    /// sync it.
+    #[arg(long, default_value = "60000", value_parser = duration::parse_millis)]
+    pub nack_retry_delay: Duration,
+
+    /// Amount of times an update is delivered to a client before giving up on it being
+    /// acknowledged. The client finds it again when syncing in full.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_delivery_attempts: u32,
+
+    /// Interval in milliseconds between heartbeats sent to clients supporting them.
+    #[arg(long, default_value = "30000", value_parser = duration::parse_nonzero_millis)]
+    pub heartbeat_interval: Duration,
+
+    /// Amount of consecutive heartbeat intervals without any message from a client before its
+    /// connection is considered dead. Clients also have to request a connection within that time.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub missed_heartbeats: u32,
+
+    /// Maximum size in bytes of a single message exchanged with clients.
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    pub max_frame_size: usize,
+
+    /// Only report files with names matching the pattern, in addition to the client's filters.
+    /// Specify multiple times to include multiple patterns.
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRules::validate_pattern)]
+    pub include: Vec<String>,
+
+    /// Never report files or directories with names matching the pattern, in addition to the
+    /// client's filters. Specify multiple times to exclude multiple patterns.
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRules::validate_pattern)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CompletionStrategy {
+    /// Report files once they haven't been modified for `--sync-delay`.
+    Delay,
+
+    /// Report files once their size and modification time have been unchanged for
+    /// `--stability-window`.
+    Stable,
+
+    /// Report files `--sync-delay` after they're closed by a writer, or once they haven't been
+    /// modified for `--unclosed-write-delay` if they aren't closed. Only supported on Linux.
+    CloseWrite,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum WatcherBackend {
+    /// Poll paths on network and FUSE filesystems, and use inotify for other paths.
+    Auto,
+
+    /// Use inotify for all paths.
+    Inotify,
+
+    /// Poll all paths. Slower, but works on every filesystem.
+    Poll,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum EventSource {
+    /// Report changes to watched paths detected by `--watcher-backend`.
+    Filesystem,
+
+    /// Report torrents completed by qBittorrent, polling its WebUI API.
+    Qbittorrent,
+
+    /// Report torrents completed by Transmission, polling its RPC interface.
+    Transmission,
 }
 
 impl Args {
-    fn parse_millis(s: &str) -> clap::error::Result<Duration, String> {
-        s.parse::<u64>()
-            .map(Duration::from_millis)
-            .map_err(|e| format!("invalid duration '{}': {}", s, e))
+    pub fn filter_rules(&self) -> FilterRules {
+        FilterRules {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            include_also: Vec::new(),
+        }
+    }
+
+    /// Returns the canonical path `path` resolves to if it's under one of the allowed roots, which
+    /// is the path to watch, route and journal so that it's the one that was checked. Returns
+    /// `path` itself if all paths are allowed. Accesses the filesystem, so it's blocking.
+    pub fn allowed_path(&self, path: &Path) -> Option<PathBuf> {
+        if self.allowed_roots.is_empty() {
+            return Some(path.to_path_buf());
+        }
+
+        let canonical_path = match path.canonicalize() {
+            Ok(canonical_path) => canonical_path,
+            Err(e) => {
+                log::debug!("failed to resolve {path:?}: {e:#}");
+                return None;
+            }
+        };
+        if !self
+            .allowed_roots
+            .iter()
+            .any(|root| canonical_path.starts_with(root))
+        {
+            log::debug!("{path:?} resolves to {canonical_path:?}, outside of the allowed roots");
+            return None;
+        }
+
+        Some(canonical_path)
+    }
+
+    /// Returns true if a peer running as `uid` with the primary group `gid` may connect over the
+    /// unix domain socket.
+    pub fn is_peer_allowed(&self, uid: u32, gid: u32) -> bool {
+        // SAFETY: geteuid is always successful.
+        let server_uid = unsafe { libc::geteuid() };
+        uid == server_uid || self.allowed_uids.contains(&uid) || self.allowed_gids.contains(&gid)
+    }
+
+    fn parse_mode(s: &str) -> clap::error::Result<u32, String> {
+        match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(mode),
+            _ => Err(format!(
+                "invalid mode '{s}', expected octal permissions like 600"
+            )),
+        }
+    }
+
+    fn parse_allowed_root(s: &str) -> clap::error::Result<PathBuf, String> {
+        PathBuf::from(s)
+            .canonicalize()
+            .map_err(|e| format!("failed to resolve {s:?}: {e}"))
+    }
+
+    fn parse_loopback_address(s: &str) -> clap::error::Result<SocketAddr, String> {
+        let addr: SocketAddr = s
+            .parse()
+            .map_err(|e| format!("invalid address {s:?}: {e}"))?;
+        if !addr.ip().is_loopback() {
+            return Err(format!("expected {addr} to be a loopback address"));
+        }
+
+        Ok(addr)
+    }
+
+    fn parse_absolute_path(s: &str) -> clap::error::Result<PathBuf, String> {
+        let path = PathBuf::from(s);
+        if !path.is_absolute() {
+            return Err(format!("expected {path:?} to be an absolute path"));
+        }
+
+        Ok(path)
     }
 }
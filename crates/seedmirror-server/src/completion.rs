@@ -0,0 +1,62 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::{Instant, sleep};
+
+/// Waits until the size and modification time of `path` have been unchanged for `window`, or
+/// until `max_wait` has passed. The state of `path` is checked every `check_interval`.
+pub(crate) async fn wait_until_stable(
+    path: PathBuf,
+    check_interval: Duration,
+    window: Duration,
+    max_wait: Duration,
+) {
+    let started = Instant::now();
+    let mut last_state = None;
+    let mut stable_since = Instant::now();
+
+    loop {
+        sleep(check_interval).await;
+
+        let checked_path = path.clone();
+        let state = tokio::task::spawn_blocking(move || file_state(&checked_path))
+            .await
+            .ok()
+            .and_then(Result::ok);
+
+        if state != last_state {
+            log::debug!("{path:?} changed since last check: {state:?}");
+            last_state = state;
+            stable_since = Instant::now();
+        } else if stable_since.elapsed() >= window {
+            log::debug!("{path:?} has been stable for {window:?}");
+            return;
+        }
+
+        if started.elapsed() >= max_wait {
+            log::warn!("{path:?} didn't become stable within {max_wait:?}, reporting it anyway");
+            return;
+        }
+    }
+}
+
+/// Returns the total size and latest modification time of `path`, including everything inside
+/// it if it's a directory.
+fn file_state(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut size = metadata.len();
+    let mut modified = metadata.modified()?;
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let (entry_size, entry_modified) = file_state(&entry?.path())?;
+            size += entry_size;
+            modified = modified.max(entry_modified);
+        }
+    }
+
+    Ok((size, modified))
+}
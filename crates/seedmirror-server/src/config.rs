@@ -0,0 +1,149 @@
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, parser::ValueSource};
+use log::LevelFilter;
+use seedmirror_core::{duration, filter::FilterRules};
+use serde::Deserialize;
+
+use crate::cli::{Args, CompletionStrategy};
+
+/// Settings read from the file given with `--config`. Durations are in milliseconds, like their
+/// flags.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    socket_path: Option<PathBuf>,
+    log_level: Option<String>,
+    completion: Option<CompletionStrategy>,
+    sync_delay: Option<u64>,
+    unclosed_write_delay: Option<u64>,
+    stability_check_interval: Option<u64>,
+    stability_window: Option<u64>,
+    stability_max_wait: Option<u64>,
+    allowed_roots: Option<Vec<PathBuf>>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+}
+
+/// Builds the arguments from the command line and the configuration file, which can be read again
+/// to reload the configuration.
+pub(crate) struct ArgsLoader {
+    matches: ArgMatches,
+}
+
+impl ArgsLoader {
+    /// Parses the command line, exiting with a usage message if it's invalid.
+    pub(crate) fn new() -> Self {
+        Self {
+            matches: Args::command().get_matches(),
+        }
+    }
+
+    /// Returns the arguments given on the command line, completed with the ones set in the
+    /// configuration file.
+    pub(crate) fn load(&self) -> anyhow::Result<Args> {
+        let mut args = Args::from_arg_matches(&self.matches)?;
+        let Some(path) = args.config.clone() else {
+            return Ok(args);
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read configuration file {path:?}"))?;
+        let config: Config = toml::from_str(&contents)
+            .with_context(|| format!("invalid configuration file {path:?}"))?;
+        self.apply(config, &mut args)
+            .with_context(|| format!("invalid configuration file {path:?}"))?;
+
+        Ok(args)
+    }
+
+    /// Sets the arguments that weren't given on the command line to their value in `config`.
+    fn apply(&self, config: Config, args: &mut Args) -> anyhow::Result<()> {
+        let from_config =
+            |id: &str| self.matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        if let Some(socket_path) = config.socket_path
+            && from_config("socket_path")
+        {
+            args.socket_path = socket_path;
+        }
+        if let Some(log_level) = config.log_level
+            && from_config("log_level")
+        {
+            args.log_level = LevelFilter::from_str(&log_level)
+                .with_context(|| format!("invalid log level {log_level:?}"))?;
+        }
+        if let Some(completion) = config.completion
+            && from_config("completion")
+        {
+            args.completion = completion;
+        }
+
+        // Validated like their flags
+        type Validate = fn(Duration) -> Result<Duration, String>;
+        let durations: [(_, _, _, Validate); 5] = [
+            (config.sync_delay, "sync_delay", &mut args.sync_delay, Ok),
+            (
+                config.unclosed_write_delay,
+                "unclosed_write_delay",
+                &mut args.unclosed_write_delay,
+                Ok,
+            ),
+            (
+                config.stability_check_interval,
+                "stability_check_interval",
+                &mut args.stability_check_interval,
+                duration::nonzero,
+            ),
+            (
+                config.stability_window,
+                "stability_window",
+                &mut args.stability_window,
+                Ok,
+            ),
+            (
+                config.stability_max_wait,
+                "stability_max_wait",
+                &mut args.stability_max_wait,
+                Ok,
+            ),
+        ];
+        for (value, id, arg, validate) in durations {
+            if let Some(value) = value
+                && from_config(id)
+            {
+                *arg = validate(Duration::from_millis(value))
+                    .map_err(|e| anyhow::anyhow!("invalid {}: {e}", id.replace('_', "-")))?;
+            }
+        }
+
+        if let Some(allowed_roots) = config.allowed_roots
+            && from_config("allowed_roots")
+        {
+            args.allowed_roots = allowed_roots
+                .iter()
+                .map(|root| {
+                    root.canonicalize()
+                        .with_context(|| format!("failed to resolve allowed root {root:?}"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        for (patterns, id, arg) in [
+            (config.include, "include", &mut args.include),
+            (config.exclude, "exclude", &mut args.exclude),
+        ] {
+            if let Some(patterns) = patterns
+                && from_config(id)
+            {
+                *arg = patterns
+                    .iter()
+                    .map(|pattern| FilterRules::validate_pattern(pattern))
+                    .collect::<Result<_, _>>()
+                    .map_err(anyhow::Error::msg)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,27 +1,231 @@
+use std::{
+    fs::Permissions,
+    future, io,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use anyhow::Context;
-use notify::{RecursiveMode, Watcher};
-use seedmirror_core::message::Message;
+use futures_util::{SinkExt, StreamExt};
+use seedmirror_core::{
+    auth::Token,
+    codec::{CodecError, MessageCodec},
+    filter::{Filter, WatchedPathFilter},
+    message::{Capability, ErrorCode, Message, PROTOCOL_VERSION},
+    path,
+};
 use tokio::{
-    fs::remove_file,
-    io::BufReader,
-    net::{UnixListener, UnixStream},
-    sync::broadcast,
-    task::JoinSet,
+    fs::{remove_file, set_permissions},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{broadcast, watch},
+    time::{Instant, MissedTickBehavior, interval, sleep_until, timeout},
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
+
+use crate::{
+    cli::Args,
+    delivery::PendingDeliveries,
+    hooks::Hooks,
+    informer::{self, Notification},
+    journal::{self, Journal, JournalWatchGuard, SharedJournal},
+    manifest::ManifestListings,
+    metrics::{self, Metrics, SharedMetrics},
+    subscription::{Subscription, WatchedPath},
+    systemd, tls, torrent,
+    watcher::{self, SharedWatchers},
+};
+
+/// Maximum duration of the TLS handshake of a TCP connection.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-use crate::{cli::Args, informer, watcher};
+/// Listener for TLS connections over TCP.
+struct TcpServer {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+
+    /// Token that clients must provide, if any.
+    token: Option<Token>,
+}
+
+/// State shared by all connections.
+#[derive(Clone)]
+struct Shared {
+    watchers: SharedWatchers,
+
+    /// Journal of reported updates, if enabled.
+    journal: Option<SharedJournal>,
+
+    metrics: SharedMetrics,
+}
 
-pub(crate) async fn connection_manager(args: Args) {
-    if let Err(e) = connection_manager_inner(args).await {
+pub(crate) async fn connection_manager(args_rx: watch::Receiver<Args>) {
+    if let Err(e) = connection_manager_inner(args_rx).await {
         log::error!("error starting connection manager: {e:#}");
     }
 }
 
-async fn connection_manager_inner(args: Args) -> anyhow::Result<()> {
-    let socket_path = &args.socket_path;
+async fn connection_manager_inner(mut args_rx: watch::Receiver<Args>) -> anyhow::Result<()> {
+    let args = args_rx.borrow_and_update().clone();
+    let mut socket_path = args.socket_path.clone();
+    let inherited_listener = systemd::inherited_listener()?;
+    let socket_activated = inherited_listener.is_some();
+    let mut listener = match inherited_listener {
+        Some(listener) => {
+            log::info!("listening on socket passed by the service manager");
+            listener
+        }
+        None => {
+            let listener = bind_unix(&socket_path, &args).await?;
+            log::info!("listening on socket {socket_path:?}");
+            listener
+        }
+    };
+
+    let tcp_server = match args.listen {
+        Some(addr) => Some(listen_tcp(&args, addr).await?),
+        None => None,
+    };
+
+    // A single watcher and filesystem event handler is shared by all connections, and their
+    // notifications are routed to each connection based on its watched paths
+    let (watchers, notify_rx) = watcher::create_watcher(args.clone()).await?;
+    let (notification_tx, _notification_rx) = broadcast::channel::<Notification>(100);
+    let journal = args
+        .journal_path
+        .as_deref()
+        .map(|path| Journal::open(&args, path, watchers.clone()))
+        .transpose()?
+        .map(|journal| Arc::new(Mutex::new(journal)));
+    if let Some(journal) = &journal {
+        tokio::spawn(journal::expire_watches(
+            journal.clone(),
+            args.journal_watch_ttl,
+        ));
+    }
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(&args, metrics.clone(), watchers.clone()).await?;
+    let hooks = Hooks::new(&args);
+    let torrent_poller = torrent::poll_torrents(
+        args_rx.clone(),
+        watchers.clone(),
+        notification_tx.clone(),
+        journal.clone(),
+        hooks.clone(),
+    )?;
+    // Without a torrent client, there's no polling task that could stop
+    let torrent_poller = async move {
+        match torrent_poller {
+            Some(poller) => poller.await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(torrent_poller);
 
+    let mut notify_handler = tokio::spawn(informer::notify_handler(
+        args_rx.clone(),
+        notify_rx,
+        watchers.clone(),
+        notification_tx.clone(),
+        journal.clone(),
+        metrics.clone(),
+        hooks,
+    ));
+    let shared = Shared {
+        watchers,
+        journal,
+        metrics,
+    };
+    systemd::notify_ready();
+
+    loop {
+        tokio::select! {
+            _ = &mut notify_handler => {
+                anyhow::bail!("filesystem event handler stopped");
+            }
+            result = &mut torrent_poller => {
+                match result {
+                    Err(e) => anyhow::bail!("torrent client polling stopped: {e}"),
+                    Ok(()) => anyhow::bail!("torrent client polling stopped"),
+                }
+            }
+            Ok(()) = args_rx.changed() => {
+                let new_args = args_rx.borrow_and_update().clone();
+                let new_socket_path = new_args.socket_path.clone();
+                if new_socket_path == socket_path {
+                    continue;
+                }
+                if socket_activated {
+                    log::warn!(
+                        "ignoring socket path {new_socket_path:?}, the socket passed by the service \
+                        manager is used instead"
+                    );
+                    socket_path = new_socket_path;
+                    continue;
+                }
+
+                // Connections accepted on the previous socket stay open
+                match bind_unix(&new_socket_path, &new_args).await {
+                    Ok(new_listener) => {
+                        log::info!("moved socket from {socket_path:?} to {new_socket_path:?}");
+                        listener = new_listener;
+                        if let Err(e) = remove_file(&socket_path).await {
+                            log::warn!("failed to remove previous socket {socket_path:?}: {e:#}");
+                        }
+                        socket_path = new_socket_path;
+                    }
+                    Err(e) => {
+                        log::error!("{e:#}, still listening on {socket_path:?}");
+                    }
+                }
+            }
+            res = listener.accept() => match res {
+                Ok((stream, _addr)) => {
+                    // Checked against the current configuration, which may have been reloaded
+                    if !is_peer_allowed(&args_rx.borrow(), &stream) {
+                        continue;
+                    }
+
+                    tokio::spawn(connection_handler(
+                        args_rx.clone(),
+                        Box::new(stream),
+                        shared.clone(),
+                        notification_tx.subscribe(),
+                        None,
+                    ));
+                }
+                Err(e) => {
+                    log::error!("failed to accept incoming connection: {e:#}");
+                }
+            },
+            res = accept_tcp(tcp_server.as_ref()) => match res {
+                Ok((stream, addr)) => {
+                    let tcp_server = tcp_server.as_ref().expect("tcp connection accepted");
+                    tokio::spawn(tls_connection_handler(
+                        args_rx.clone(),
+                        stream,
+                        addr,
+                        tcp_server.acceptor.clone(),
+                        shared.clone(),
+                        notification_tx.subscribe(),
+                        tcp_server.token.clone(),
+                    ));
+                }
+                Err(e) => {
+                    log::error!("failed to accept incoming tcp connection: {e:#}");
+                }
+            },
+        }
+    }
+}
+
+async fn bind_unix(socket_path: &Path, args: &Args) -> anyhow::Result<UnixListener> {
     if socket_path.try_exists()? {
-        remove_file(&socket_path)
+        remove_file(socket_path)
             .await
             .with_context(|| format!("failed to remove existing socket: {socket_path:?}"))?;
     }
@@ -29,51 +233,241 @@ async fn connection_manager_inner(args: Args) -> anyhow::Result<()> {
     let listener = UnixListener::bind(socket_path)
         .with_context(|| format!("failed to listen to socket at {socket_path:?}"))?;
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _addr)) => {
-                tokio::spawn(connection_handler(args.clone(), stream));
-            }
-            Err(e) => {
-                log::error!("failed to accept incoming connection: {e:#}");
-            }
+    // Peers connecting before the permissions are set are still checked by `is_peer_allowed`
+    let mode = args.socket_mode;
+    set_permissions(socket_path, Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("failed to set permissions of socket at {socket_path:?}"))?;
+    if mode & 0o022 == 0 && (!args.allowed_uids.is_empty() || !args.allowed_gids.is_empty()) {
+        log::warn!(
+            "socket mode {mode:o} only lets the server's user connect, other allowed users and \
+            groups need --socket-mode to give them write permission"
+        );
+    }
+
+    Ok(listener)
+}
+
+/// Returns true if the user of the peer connected over the unix domain socket is allowed to
+/// connect.
+fn is_peer_allowed(args: &Args, stream: &UnixStream) -> bool {
+    let cred = match stream.peer_cred() {
+        Ok(cred) => cred,
+        Err(e) => {
+            log::warn!("refusing connection whose peer credentials are unknown: {e:#}");
+            return false;
         }
+    };
+
+    if !args.is_peer_allowed(cred.uid(), cred.gid()) {
+        log::warn!(
+            "refusing connection from uid {}, gid {}, pid {}",
+            cred.uid(),
+            cred.gid(),
+            cred.pid()
+                .map_or_else(|| "unknown".to_string(), |pid| pid.to_string())
+        );
+        return false;
     }
+
+    true
 }
 
-async fn connection_handler(args: Args, stream: UnixStream) {
-    if let Err(e) = connection_handler_inner(args, stream).await {
+async fn listen_tcp(args: &Args, addr: SocketAddr) -> anyhow::Result<TcpServer> {
+    if args.token_file.is_none() && args.tls_client_ca.is_none() {
+        anyhow::bail!("--token-file or --tls-client-ca is required to listen on TCP");
+    }
+
+    let acceptor = tls::acceptor(args)?;
+    let token = args.token_file.as_deref().map(Token::read).transpose()?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen on {addr}"))?;
+    log::info!("listening for tls connections on {addr}");
+
+    Ok(TcpServer {
+        listener,
+        acceptor,
+        token,
+    })
+}
+
+async fn accept_tcp(tcp_server: Option<&TcpServer>) -> io::Result<(TcpStream, SocketAddr)> {
+    match tcp_server {
+        Some(tcp_server) => tcp_server.listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn tls_connection_handler(
+    args_rx: watch::Receiver<Args>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    shared: Shared,
+    notification_rx: broadcast::Receiver<Notification>,
+    token: Option<Token>,
+) {
+    let stream = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            log::warn!("tls handshake with {addr} failed: {e:#}");
+            return;
+        }
+        Err(_) => {
+            log::warn!("tls handshake with {addr} timed out");
+            return;
+        }
+    };
+
+    log::info!("established tls connection with {addr}");
+    connection_handler(args_rx, Box::new(stream), shared, notification_rx, token).await;
+}
+
+async fn connection_handler(
+    args_rx: watch::Receiver<Args>,
+    stream: Box<dyn ClientIo>,
+    shared: Shared,
+    notification_rx: broadcast::Receiver<Notification>,
+    token: Option<Token>,
+) {
+    let metrics = shared.metrics.clone();
+    metrics.client_connected();
+
+    let res = connection_handler_inner(args_rx, stream, shared, notification_rx, token).await;
+    if let Err(e) = res {
         log::error!("connection handler failed: {e:#}");
     }
+
+    metrics.client_disconnected();
+}
+
+/// Connection to a client, either over a unix socket or TLS.
+trait ClientIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientIo for T {}
+
+type ClientStream = Framed<Box<dyn ClientIo>, MessageCodec>;
+
+/// State of a connection, set once the client connects.
+struct Client {
+    /// Paths are unwatched when the subscription is dropped.
+    subscription: Option<Subscription>,
+
+    /// Paths kept watched for the journal, which are released when the guard is dropped.
+    journal_watches: Option<JournalWatchGuard>,
+
+    /// Features supported by both the client and the server.
+    capabilities: Vec<Capability>,
+
+    /// Filters requested by the client, merged with the server's filters of the watched paths.
+    filters: Vec<WatchedPathFilter>,
+
+    /// Updates the client hasn't acknowledged yet, if it supports acknowledgements.
+    deliveries: PendingDeliveries,
+
+    /// Manifests requested by the client, sent alongside notifications as they're listed.
+    manifests: ManifestListings,
 }
 
-async fn connection_handler_inner(args: Args, mut stream: UnixStream) -> anyhow::Result<()> {
+async fn connection_handler_inner(
+    mut args_rx: watch::Receiver<Args>,
+    stream: Box<dyn ClientIo>,
+    shared: Shared,
+    mut notification_rx: broadcast::Receiver<Notification>,
+    token: Option<Token>,
+) -> anyhow::Result<()> {
     log::info!("established socket connection with client");
+    let mut args = args_rx.borrow_and_update().clone();
 
-    let (server_msg_tx, mut server_msg_rx) = broadcast::channel::<Message>(100);
+    let mut stream = Framed::new(stream, MessageCodec::new(args.max_frame_size));
 
-    // Watcher will be shut down on drop
-    let (mut watcher, notify_rx) = watcher::create_watcher().await?;
+    let mut heartbeat = interval(args.heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let heartbeat_timeout = args.heartbeat_interval * args.missed_heartbeats;
 
-    let mut set = JoinSet::new();
-    set.spawn(informer::notify_handler(args, notify_rx, server_msg_tx));
+    let mut client = Client {
+        subscription: None,
+        journal_watches: None,
+        capabilities: Vec::new(),
+        filters: Vec::new(),
+        deliveries: PendingDeliveries::new(args.clone()),
+        manifests: ManifestListings::new(),
+    };
+    let accepted = Instant::now();
+    let mut last_received = accepted;
 
     loop {
+        let redelivery_deadline = client.deliveries.next_deadline();
+
         tokio::select! {
-            res = server_msg_rx.recv() => {
-                match handle_server_msg(res, &mut stream).await {
+            res = notification_rx.recv() => {
+                let res =
+                    handle_notification(res, &shared.metrics, &mut client, &mut stream).await;
+                match res {
                     Ok(true) => break,
                     Ok(false) => (),
                     Err(e) => anyhow::bail!(e),
                 };
             }
-            Ok(_) = stream.readable() => {
-                match handle_client_msg(&mut watcher, &mut stream).await {
+            res = stream.next() => {
+                last_received = Instant::now();
+                let res = handle_client_msg(
+                    res,
+                    &args,
+                    token.as_ref(),
+                    &shared,
+                    &mut client,
+                    &mut stream,
+                )
+                .await;
+
+                match res {
                     Ok(true) => break,
                     Ok(false) => (),
                     Err(e) => anyhow::bail!(e),
                 };
             }
+            msg = client.manifests.next() => {
+                if send_message(&mut stream, msg).await? {
+                    break;
+                }
+            }
+            Ok(()) = args_rx.changed() => {
+                let new_args = args_rx.borrow_and_update().clone();
+                if reconfigure(&args, &new_args, &mut client, &mut stream).await? {
+                    break;
+                }
+                args = new_args;
+            }
+            _ = sleep_until(redelivery_deadline.unwrap_or_else(Instant::now)),
+                if redelivery_deadline.is_some() =>
+            {
+                if redeliver(&mut client, &mut stream).await? {
+                    break;
+                }
+            }
+            _ = heartbeat.tick() => {
+                // Peers that never complete the handshake would otherwise keep the connection
+                // open forever, whatever they send
+                if client.subscription.is_none() && accepted.elapsed() > heartbeat_timeout {
+                    log::warn!("client didn't request a connection in {heartbeat_timeout:?}");
+                    break;
+                }
+
+                if !client.capabilities.contains(&Capability::Heartbeat) {
+                    continue;
+                }
+
+                if last_received.elapsed() > heartbeat_timeout {
+                    log::warn!("no message received from client in {heartbeat_timeout:?}");
+                    break;
+                }
+
+                if send_message(&mut stream, Message::Ping).await? {
+                    break;
+                }
+            }
         }
     }
 
@@ -82,58 +476,452 @@ async fn connection_handler_inner(args: Args, mut stream: UnixStream) -> anyhow:
 }
 
 /// Returns true if the connection should be terminated.
-async fn handle_server_msg(
-    res: Result<Message, broadcast::error::RecvError>,
-    stream: &mut UnixStream,
+async fn handle_notification(
+    res: Result<Notification, broadcast::error::RecvError>,
+    metrics: &Metrics,
+    client: &mut Client,
+    stream: &mut ClientStream,
 ) -> anyhow::Result<bool> {
     match res {
-        Ok(msg) => {
-            if msg.write_to_stream(stream).await? {
-                return Ok(true);
-            }
-        }
+        Ok(notification) => send_notification(notification, client, stream).await,
         Err(broadcast::error::RecvError::Lagged(skipped)) => {
             log::warn!("receiving too many filesystem events, skipping {skipped} event(s)");
+            metrics.notifications_dropped(skipped);
+            request_resync(client, stream).await
         }
         Err(e) => anyhow::bail!("recv on filesystem event broadcast channel failed: {e:#}"),
     }
+}
+
+/// Asks the client to sync its watched paths in full after notifications were skipped. Returns
+/// true if the connection is broken and should be terminated.
+async fn request_resync(client: &Client, stream: &mut ClientStream) -> anyhow::Result<bool> {
+    let Some(subscription) = &client.subscription else {
+        return Ok(false);
+    };
+    if !client.capabilities.contains(&Capability::Resync) {
+        log::warn!("client doesn't support resyncs, skipped updates won't be synced");
+        return Ok(false);
+    }
+
+    // The skipped notifications are gone, so any watched path may be affected
+    let paths = subscription
+        .requested_paths()
+        .map(Path::to_path_buf)
+        .collect();
+    send_message(stream, Message::ResyncRequired { paths }).await
+}
+
+/// Sends `notification` to the client if it's relevant to it. Returns true if the connection is
+/// broken and should be terminated.
+async fn send_notification(
+    notification: Notification,
+    client: &mut Client,
+    stream: &mut ClientStream,
+) -> anyhow::Result<bool> {
+    let Some(msg) = client
+        .subscription
+        .as_ref()
+        .and_then(|subscription| subscription.route(notification))
+    else {
+        return Ok(false);
+    };
+
+    for msg in adapt_to_capabilities(msg, &client.capabilities) {
+        if client.capabilities.contains(&Capability::Ack) {
+            client.deliveries.sent(&msg);
+        }
+
+        if send_message(stream, msg).await? {
+            return Ok(true);
+        }
+    }
 
     Ok(false)
 }
 
-/// Returns true if the connection should be terminated.
-async fn handle_client_msg(
-    watcher: &mut impl Watcher,
-    stream: &mut UnixStream,
+/// Delivers the updates the client didn't acknowledge in time again. Returns true if the
+/// connection is broken and should be terminated.
+async fn redeliver(client: &mut Client, stream: &mut ClientStream) -> anyhow::Result<bool> {
+    for msg in client.deliveries.due() {
+        if send_message(stream, msg).await? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Applies the reloaded configuration to the client's subscription. Returns true if the connection
+/// should be terminated, which is the case if a watched path is no longer allowed.
+async fn reconfigure(
+    args: &Args,
+    new_args: &Args,
+    client: &mut Client,
+    stream: &mut ClientStream,
 ) -> anyhow::Result<bool> {
-    let (read_stream, mut write_stream) = tokio::io::split(&mut *stream);
+    let Some(subscription) = &mut client.subscription else {
+        return Ok(false);
+    };
 
-    let mut reader = BufReader::new(read_stream);
-    let msg = match Message::read_from_reader(&mut reader).await {
-        Ok(msg) => msg,
-        Err(e) => {
-            log::debug!(
-                "error when reading message from client (probably due to terminated connection): {e:#}"
+    let watched_paths: Vec<_> = subscription
+        .watched_paths()
+        .map(Path::to_path_buf)
+        .collect();
+    let allowed = allowed_paths(new_args, &watched_paths).await?;
+    if let Some((path, _allowed)) = watched_paths
+        .iter()
+        .zip(allowed)
+        .find(|(_path, allowed)| allowed.is_none())
+    {
+        log::warn!("{path:?} is no longer under the allowed roots, terminating connection");
+
+        let msg = Message::Error {
+            code: ErrorCode::PathNotAllowed,
+            reason: format!("watching {path:?} is no longer allowed by the server"),
+        };
+        send_message(stream, msg).await?;
+
+        return Ok(true);
+    }
+
+    if new_args.filter_rules() != args.filter_rules() {
+        let requested_paths: Vec<_> = subscription
+            .requested_paths()
+            .map(Path::to_path_buf)
+            .collect();
+        match compile_filters(new_args, &requested_paths, &client.filters) {
+            Ok(filters) => {
+                log::info!("applying reloaded filters to client");
+                subscription.set_filters(filters);
+            }
+            Err(e) => log::error!("failed to apply reloaded filters, keeping previous ones: {e:#}"),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns the path each of `paths` resolves to if it's allowed, see [`Args::allowed_path`].
+/// Resolved off the async runtime, since it accesses the filesystem.
+async fn allowed_paths(args: &Args, paths: &[PathBuf]) -> anyhow::Result<Vec<Option<PathBuf>>> {
+    let (args, paths) = (args.clone(), paths.to_vec());
+    let allowed = tokio::task::spawn_blocking(move || {
+        paths.iter().map(|path| args.allowed_path(path)).collect()
+    })
+    .await?;
+
+    Ok(allowed)
+}
+
+/// Returns the filter of each watched path, made of the server's filters and the ones requested
+/// by the client for the path.
+fn compile_filters(
+    args: &Args,
+    watched_paths: &[PathBuf],
+    client_filters: &[WatchedPathFilter],
+) -> anyhow::Result<Vec<(PathBuf, Filter)>> {
+    watched_paths
+        .iter()
+        .map(|path| {
+            let rules = client_filters
+                .iter()
+                .filter(|filter| &filter.path == path)
+                .fold(args.filter_rules(), |rules, filter| {
+                    rules.merge(&filter.rules)
+                });
+            let filter = rules
+                .compile()
+                .with_context(|| format!("invalid filter for {path:?}"))?;
+
+            Ok((path.clone(), filter))
+        })
+        .collect()
+}
+
+/// Keeps watching `watched_paths` for the journal and returns the changes recorded after
+/// `last_seq` in the journal with ID `journal_id`, if they can be replayed, along with the ID of
+/// the journal.
+fn replay_journal(
+    shared_journal: &SharedJournal,
+    watched_paths: &[PathBuf],
+    journal_id: Option<&str>,
+    last_seq: Option<u64>,
+) -> (JournalWatchGuard, Option<Vec<Message>>, String) {
+    let mut journal = shared_journal
+        .lock()
+        .expect("journal lock should not be poisoned");
+
+    // Checked before watching the paths, since changes of paths that weren't watched weren't
+    // recorded
+    let replay = last_seq.and_then(|last_seq| {
+        let replay = journal.replay(journal_id, last_seq, watched_paths);
+        if replay.is_none() {
+            log::info!(
+                "can't replay changes after sequence number {last_seq}, they're no longer or \
+                weren't all recorded in the journal"
             );
+        }
+        replay
+    });
+
+    let kept_paths = watched_paths
+        .iter()
+        .filter(|path| match journal.keep_watching(path) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("failed to keep watching {path:?} for the journal: {e:#}");
+                false
+            }
+        })
+        .cloned()
+        .collect();
+    let guard = JournalWatchGuard::new(shared_journal.clone(), kept_paths);
+
+    (guard, replay, journal.id().to_string())
+}
 
+/// Rewrites `msg` into messages that only use features supported by the client.
+fn adapt_to_capabilities(msg: Message, capabilities: &[Capability]) -> Vec<Message> {
+    match msg {
+        msg if !capabilities.contains(&Capability::BinaryPaths)
+            && !msg.paths().into_iter().all(path::is_utf8) =>
+        {
+            log::warn!("skipping message with non-UTF-8 path unsupported by client: {msg:?}");
+            vec![]
+        }
+        Message::FileRemoved { .. } if !capabilities.contains(&Capability::FileRemoved) => vec![],
+        Message::ResyncRequired { paths } if !capabilities.contains(&Capability::Resync) => {
+            vec![Message::Error {
+                code: ErrorCode::WatchFailed,
+                reason: format!("watching {paths:?} failed, changes under them may be missed"),
+            }]
+        }
+        Message::FileRenamed { from, to, seq }
+            if !capabilities.contains(&Capability::FileRenamed) =>
+        {
+            let mut msgs = vec![Message::FileUpdated { path: to, seq }];
+            msgs.extend(adapt_to_capabilities(
+                Message::FileRemoved {
+                    path: from,
+                    seq: None,
+                },
+                capabilities,
+            ));
+            msgs
+        }
+        msg => vec![msg],
+    }
+}
+
+/// Returns true if the connection should be terminated.
+async fn handle_client_msg(
+    res: Option<Result<Message, CodecError>>,
+    args: &Args,
+    required_token: Option<&Token>,
+    shared: &Shared,
+    client: &mut Client,
+    stream: &mut ClientStream,
+) -> anyhow::Result<bool> {
+    let msg = match res {
+        Some(Ok(msg)) => msg,
+        Some(Err(e)) if e.is_disconnect() => {
+            log::debug!("connection terminated while reading message from client: {e:#}");
+            return Ok(true);
+        }
+        Some(Err(e)) => {
+            log::warn!("received invalid message from client: {e:#}");
             return Ok(true);
         }
+        None => return Ok(true),
     };
 
-    #[allow(clippy::single_match)]
     match msg {
-        // TODO: Exchange version information to ensure client and server match
-        Message::ConnectionRequest { watched_paths } => {
-            for path in watched_paths {
-                watcher.watch(&path, RecursiveMode::Recursive)?;
+        Message::ConnectionRequest {
+            protocol_version,
+            capabilities: client_capabilities,
+            watched_paths,
+            filters: client_filters,
+            token,
+            last_seq,
+            journal_id,
+        } => {
+            if let Some(required_token) = required_token
+                && !token.is_some_and(|token| token.verify(required_token))
+            {
+                log::warn!("refusing client with missing or invalid token");
+
+                let msg = Message::Error {
+                    code: ErrorCode::Unauthorized,
+                    reason: "missing or invalid token".to_string(),
+                };
+                send_message(stream, msg).await?;
+
+                return Ok(true);
+            }
+
+            if protocol_version != PROTOCOL_VERSION {
+                log::error!(
+                    "refusing client speaking protocol version {protocol_version}, expected {PROTOCOL_VERSION}"
+                );
+
+                let msg = Message::Error {
+                    code: ErrorCode::IncompatibleProtocol,
+                    reason: format!(
+                        "client speaks protocol version {protocol_version} but server speaks \
+                        version {PROTOCOL_VERSION}, upgrade the older of the two"
+                    ),
+                };
+                send_message(stream, msg).await?;
+
+                return Ok(true);
+            }
+
+            client.capabilities = Capability::negotiate(&client_capabilities);
+            log::info!(
+                "negotiated capabilities with client: {:?}",
+                client.capabilities
+            );
+
+            // Don't tell why a path isn't allowed, to not reveal whether it exists
+            let mut resolved_paths = Vec::new();
+            for (path, allowed) in watched_paths
+                .iter()
+                .zip(allowed_paths(args, &watched_paths).await?)
+            {
+                let Some(resolved) = allowed else {
+                    log::warn!("refusing to watch {path:?} outside of the allowed roots");
+
+                    let msg = Message::Error {
+                        code: ErrorCode::PathNotAllowed,
+                        reason: format!("watching {path:?} isn't allowed by the server"),
+                    };
+                    send_message(stream, msg).await?;
+
+                    return Ok(true);
+                };
+                resolved_paths.push(resolved);
+            }
+
+            let watched_path_filters = match compile_filters(args, &watched_paths, &client_filters)
+            {
+                Ok(filters) => filters,
+                Err(e) => {
+                    let msg = Message::Error {
+                        code: ErrorCode::InvalidRequest,
+                        reason: format!("{e:#}"),
+                    };
+                    send_message(stream, msg).await?;
+
+                    return Ok(true);
+                }
+            };
+            let paths = watched_path_filters
+                .into_iter()
+                .zip(resolved_paths.iter().cloned())
+                .map(|((requested, filter), resolved)| WatchedPath {
+                    requested,
+                    resolved,
+                    filter,
+                })
+                .collect();
+            let (new_subscription, watch_errors) =
+                Subscription::new(shared.watchers.clone(), paths);
+
+            // Updates recorded after the replayed ones are also received through the broadcast
+            // channel, since it was subscribed to before, so none are missed
+            let last_seq = last_seq.filter(|_| client.capabilities.contains(&Capability::Journal));
+            let (journal_watches, replay, journal_id) = match &shared.journal {
+                Some(journal) => {
+                    let journal = journal.clone();
+                    let (guard, replay, journal_id) = tokio::task::spawn_blocking(move || {
+                        replay_journal(&journal, &resolved_paths, journal_id.as_deref(), last_seq)
+                    })
+                    .await?;
+                    (Some(guard), replay, Some(journal_id))
+                }
+                None => (None, None, None),
+            };
+            let replaying = replay.is_some();
+
+            let msg = Message::Connected {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: client.capabilities.clone(),
+                filters: args.filter_rules(),
+                replaying,
+                journal_id: journal_id
+                    .filter(|_| client.capabilities.contains(&Capability::Journal)),
+            };
+            if send_message(stream, msg).await? {
+                return Ok(true);
+            }
+
+            for msg in watch_errors {
+                if send_message(stream, msg).await? {
+                    return Ok(true);
+                }
+            }
+
+            let replay = replay.unwrap_or_default();
+            if !replay.is_empty() {
+                log::info!("replaying {} change(s) from the journal", replay.len());
+            }
+            client.subscription = Some(new_subscription);
+            client.journal_watches = journal_watches;
+            client.filters = client_filters;
+            for msg in replay {
+                if send_notification(Notification::Change(msg), client, stream).await? {
+                    return Ok(true);
+                }
             }
 
-            Message::Connected
-                .write_to_stream(&mut write_stream)
-                .await?;
+            // The client expected the updates it missed to be replayed, so it may not look for
+            // them otherwise
+            if last_seq.is_some() && !replaying {
+                return request_resync(client, stream).await;
+            }
+        }
+        Message::ManifestRequest { path } => {
+            let Some(watched_path) = client
+                .subscription
+                .as_ref()
+                .and_then(|subscription| subscription.watched_path(&path))
+            else {
+                log::warn!("refusing to list {path:?}, which isn't watched by the client");
+
+                let msg = Message::Error {
+                    code: ErrorCode::PathNotAllowed,
+                    reason: format!(
+                        "listing {path:?} isn't allowed, only watched paths can be listed"
+                    ),
+                };
+                send_message(stream, msg).await?;
+
+                return Ok(true);
+            };
+
+            let binary_paths = client.capabilities.contains(&Capability::BinaryPaths);
+            let (resolved, filter) = (watched_path.resolved.clone(), watched_path.filter.clone());
+            client
+                .manifests
+                .request(path, resolved, filter, binary_paths);
+        }
+        Message::Ack { path, seq } => client.deliveries.ack(path, seq),
+        Message::Nack { path, seq, reason } => client.deliveries.nack(path, seq, &reason),
+        Message::Ping => {
+            return send_message(stream, Message::Pong).await;
         }
         _ => (),
     }
 
     Ok(false)
 }
+
+/// Returns true if the connection is broken and should be terminated.
+async fn send_message(stream: &mut ClientStream, msg: Message) -> anyhow::Result<bool> {
+    match stream.send(msg).await {
+        Ok(()) => Ok(false),
+        Err(e) if e.is_disconnect() => Ok(true),
+        Err(e) => Err(anyhow::anyhow!(e).context("failed writing to socket")),
+    }
+}
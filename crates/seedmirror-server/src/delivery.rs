@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use seedmirror_core::message::Message;
+use tokio::time::Instant;
+
+use crate::cli::Args;
+
+/// Updates sent to a client supporting acknowledgements that it hasn't acknowledged yet. They're
+/// delivered again if the client reports a failure or doesn't acknowledge them in time. Updates
+/// pending when the client disconnects are replayed from the journal if it's enabled.
+pub(crate) struct PendingDeliveries {
+    args: Args,
+    pending: HashMap<PathBuf, Delivery>,
+}
+
+struct Delivery {
+    msg: Message,
+
+    /// Sequence number of `msg`.
+    seq: Option<u64>,
+
+    /// When `msg` is delivered again.
+    deadline: Instant,
+
+    /// Amount of times `msg` was delivered.
+    attempts: u32,
+}
+
+impl PendingDeliveries {
+    pub(crate) fn new(args: Args) -> Self {
+        Self {
+            args,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Records that `msg` was sent to the client, replacing a pending delivery of the same path.
+    /// Deliveries of paths that were removed or renamed since are no longer pending.
+    pub(crate) fn sent(&mut self, msg: &Message) {
+        let (path, seq) = match msg {
+            Message::FileUpdated { path, seq } => (path, seq),
+            Message::FileRemoved { path, .. } | Message::FileRenamed { from: path, .. } => {
+                self.pending
+                    .retain(|pending_path, _delivery| !pending_path.starts_with(path));
+                return;
+            }
+            _ => return,
+        };
+
+        let delivery = Delivery {
+            msg: msg.clone(),
+            seq: *seq,
+            deadline: Instant::now() + self.args.ack_timeout,
+            attempts: 1,
+        };
+        self.pending.insert(path.clone(), delivery);
+    }
+
+    /// Handles an `Ack` message. A newer update of the same path stays pending.
+    pub(crate) fn ack(&mut self, path: PathBuf, seq: Option<u64>) {
+        if self.is_pending(&path, seq) {
+            log::debug!("client acknowledged update of {path:?}");
+            self.pending.remove(&path);
+        }
+    }
+
+    /// Handles a `Nack` message, delivering the update again after a delay.
+    pub(crate) fn nack(&mut self, path: PathBuf, seq: Option<u64>, reason: &str) {
+        log::warn!("client failed to sync {path:?}: {reason}");
+        if !self.is_pending(&path, seq) {
+            return;
+        }
+
+        let delivery = self
+            .pending
+            .get_mut(&path)
+            .expect("delivery should be pending");
+        delivery.deadline = Instant::now() + self.args.nack_retry_delay;
+    }
+
+    /// Returns when the next update has to be delivered again, if any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|delivery| delivery.deadline)
+            .min()
+    }
+
+    /// Returns the updates that have to be delivered again now, giving up on those that were
+    /// delivered too many times.
+    pub(crate) fn due(&mut self) -> Vec<Message> {
+        let now = Instant::now();
+        let mut msgs = Vec::new();
+        self.pending.retain(|path, delivery| {
+            if delivery.deadline > now {
+                return true;
+            }
+
+            if delivery.attempts >= self.args.max_delivery_attempts {
+                log::warn!(
+                    "giving up on delivering update of {path:?} after {} attempts",
+                    delivery.attempts
+                );
+                return false;
+            }
+
+            log::info!(
+                "delivering update of {path:?} again, attempt {}",
+                delivery.attempts + 1
+            );
+            delivery.deadline = now + self.args.ack_timeout;
+            delivery.attempts += 1;
+            msgs.push(delivery.msg.clone());
+            true
+        });
+
+        msgs
+    }
+
+    fn is_pending(&self, path: &Path, seq: Option<u64>) -> bool {
+        self.pending
+            .get(path)
+            .is_some_and(|delivery| seq.is_none() || delivery.seq == seq)
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use notify::{
+    EventKind,
+    event::{AccessKind, AccessMode, ModifyKind},
+};
+use serde::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::unix::pipe,
+    process::Command,
+    sync::{Mutex, Semaphore},
+    time::timeout,
+};
+
+use crate::cli::Args;
+
+/// Command and FIFO informed of every update reported to clients, for post-processing on the
+/// server.
+pub(crate) struct Hooks {
+    args: Args,
+
+    /// Limits the amount of hook commands running at once.
+    permits: Semaphore,
+
+    /// Serializes writes to the FIFO, so that lines aren't interleaved.
+    fifo_lock: Mutex<()>,
+}
+
+pub(crate) type SharedHooks = Arc<Hooks>;
+
+/// Update passed to the hooks.
+#[derive(Serialize, Debug)]
+pub(crate) struct HookEvent {
+    #[serde(with = "seedmirror_core::path")]
+    pub path: PathBuf,
+
+    /// Watched path the updated path is under.
+    #[serde(with = "seedmirror_core::path")]
+    pub watched_root: PathBuf,
+
+    /// Kind of the last filesystem event that updated the path.
+    pub event: &'static str,
+}
+
+impl Hooks {
+    /// Returns `None` if no hook is configured.
+    pub(crate) fn new(args: &Args) -> Option<SharedHooks> {
+        if args.hook_command.is_none() && args.hook_fifo.is_none() {
+            return None;
+        }
+
+        Some(Arc::new(Self {
+            args: args.clone(),
+            permits: Semaphore::new(args.hook_concurrency),
+            fifo_lock: Mutex::new(()),
+        }))
+    }
+
+    /// Runs the hooks for `event` in the background.
+    pub(crate) fn run(self: &Arc<Self>, event: HookEvent) {
+        let hooks = self.clone();
+        tokio::spawn(async move {
+            if let Some(fifo_path) = &hooks.args.hook_fifo
+                && let Err(e) = hooks.write_fifo(fifo_path, &event).await
+            {
+                log::warn!(
+                    "failed to write update of {:?} to hook FIFO: {e:#}",
+                    event.path
+                );
+            }
+
+            if let Some(command) = &hooks.args.hook_command
+                && let Err(e) = hooks.run_command(command, &event).await
+            {
+                log::warn!("hook command failed for update of {:?}: {e:#}", event.path);
+            }
+        });
+    }
+
+    async fn run_command(&self, command: &str, event: &HookEvent) -> anyhow::Result<()> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("hook semaphore should never be closed");
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(command)
+            .env("SEEDMIRROR_PATH", &event.path)
+            .env("SEEDMIRROR_WATCHED_ROOT", &event.watched_root)
+            .env("SEEDMIRROR_EVENT", event.event)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+
+        log::debug!("running hook command for update of {:?}", event.path);
+        let output = timeout(self.args.hook_timeout, cmd.output())
+            .await
+            .with_context(|| format!("timed out after {:?}", self.args.hook_timeout))?
+            .context("failed to run hook command")?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !stdout.trim().is_empty() {
+            log::info!("hook command output: {}", stdout.trim());
+        }
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("{}: {}", output.status, stderr.trim());
+        }
+
+        Ok(())
+    }
+
+    /// Writes `event` to the FIFO as a JSON line. Fails if no process is reading the FIFO.
+    async fn write_fifo(&self, fifo_path: &Path, event: &HookEvent) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        let _lock = self.fifo_lock.lock().await;
+        let write = async {
+            let mut sender = pipe::OpenOptions::new()
+                .open_sender(fifo_path)
+                .with_context(|| format!("failed to open {fifo_path:?}"))?;
+            sender.write_all(&line).await?;
+            Ok::<(), anyhow::Error>(())
+        };
+        timeout(self.args.hook_timeout, write)
+            .await
+            .with_context(|| format!("timed out after {:?}", self.args.hook_timeout))?
+    }
+}
+
+/// Returns the name of the kind of an event updating a file, as passed to the hooks.
+pub(crate) fn event_name(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Create(_) => "create",
+        EventKind::Modify(ModifyKind::Name(_)) => "rename",
+        EventKind::Modify(_) => "modify",
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => "close-write",
+        _ => "other",
+    }
+}
@@ -1,95 +1,261 @@
 use std::{
     collections::HashMap,
+    io,
     path::{self, Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::Context;
-use notify::Event;
-use seedmirror_core::message::Message;
-use tokio::{sync::broadcast, task::JoinHandle, time::sleep};
+use notify::{
+    Event, EventKind,
+    event::{AccessKind, AccessMode, CreateKind, MetadataKind, ModifyKind, RenameMode},
+};
+use seedmirror_core::{filter::Filter, message::Message};
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{
+    cli::{Args, CompletionStrategy, EventSource},
+    completion,
+    hooks::{self, HookEvent, SharedHooks},
+    journal::{Journal, SharedJournal},
+    metrics::SharedMetrics,
+    subscription::filter_change,
+    watcher::{self, NotifyEventReceiver, SharedWatchers, WatchStatus},
+};
 
-use crate::{cli::Args, watcher::NotifyEventReceiver};
+/// Broadcast to every connection, which only forwards the notifications relevant to its client.
+#[derive(Clone, Debug)]
+pub(crate) enum Notification {
+    /// A file under any watched path changed. Only the filter configured on the server has been
+    /// applied, connections still have to apply the filters of their watched paths.
+    Change(Message),
+
+    /// The way a watched path is watched changed.
+    WatchStatus(WatchStatus),
+
+    /// The file watcher failed on `paths`, so changes under them may have been missed.
+    WatchError { paths: Vec<PathBuf>, reason: String },
+}
 
 struct NotifyHandler {
     args: Args,
 
+    /// Receives the arguments when the configuration is reloaded. Updates that are already
+    /// pending keep their delay.
+    args_rx: watch::Receiver<Args>,
+
     /// Channel for incoming filesystem events.
     notify_rx: NotifyEventReceiver,
 
+    /// Watchers sending the filesystem events.
+    watchers: SharedWatchers,
+
+    /// Filter configured on the server, which is part of the filter of every watched path.
+    /// Changes of other paths are dropped before they're debounced, recorded in the journal or
+    /// passed to the hooks.
+    filter: Filter,
+
     /// Broadcast channel used to inform clients of updated files.
-    server_msg_tx: broadcast::Sender<Message>,
+    notification_tx: broadcast::Sender<Notification>,
+
+    /// Journal recording updates before they're broadcast, if enabled.
+    journal: Option<SharedJournal>,
+
+    metrics: SharedMetrics,
+
+    /// Hooks run when updates are broadcast, if any are configured.
+    hooks: Option<SharedHooks>,
 
     /// Ongoing event handlers for file updates.
-    event_handlers: HashMap<PathBuf, JoinHandle<()>>,
+    event_handlers: HashMap<PathBuf, EventHandler>,
+}
+
+struct EventHandler {
+    /// Message broadcast once the handler finishes.
+    msg: Message,
+
+    /// Kind of the event that caused the update, passed to the hooks.
+    kind: EventKind,
+
+    handle: JoinHandle<()>,
 }
 
 impl NotifyHandler {
     fn new(
-        args: Args,
+        mut args_rx: watch::Receiver<Args>,
         notify_rx: NotifyEventReceiver,
-        server_msg_tx: broadcast::Sender<Message>,
-    ) -> Self {
-        Self {
+        watchers: SharedWatchers,
+        notification_tx: broadcast::Sender<Notification>,
+        journal: Option<SharedJournal>,
+        metrics: SharedMetrics,
+        hooks: Option<SharedHooks>,
+    ) -> anyhow::Result<Self> {
+        let args = args_rx.borrow_and_update().clone();
+        let filter = args
+            .filter_rules()
+            .compile()
+            .context("invalid filter configured on the server")?;
+        Ok(Self {
             args,
+            args_rx,
             notify_rx,
-            server_msg_tx,
+            watchers,
+            filter,
+            notification_tx,
+            journal,
+            metrics,
+            hooks,
             event_handlers: HashMap::new(),
-        }
+        })
     }
 
     async fn handle(mut self) -> anyhow::Result<()> {
         log::debug!("started notify handler");
-        let mut msg_rx = self.server_msg_tx.subscribe();
+        let mut notification_rx = self.notification_tx.subscribe();
 
         loop {
             tokio::select! {
                 Some(res) = self.notify_rx.recv() => {
                     match res {
                         Ok(event) => {
-                            self.process_event(&event)
-                                .with_context(|| format!("failed to process filesystem event: {event:?}"))?;
+                            if let Err(e) = self.process_event(&event) {
+                                log::error!("failed to process filesystem event {event:?}: {e:#}");
+                            }
                         },
-                        Err(e) => {
-                            anyhow::bail!(e);
+                        // Reported when watching new directories under a watched path
+                        Err(e) if watcher::is_watch_limit(&e) => {
+                            for path in e.paths {
+                                if let Err(e) = self.handle_watch_limit(&path) {
+                                    log::error!("failed to handle the watch limit under {path:?}: {e:#}");
+                                }
+                            }
                         }
+                        // The handler is shared by every client, so an error of one path only
+                        // concerns the clients watching it
+                        Err(e) => self.handle_watch_error(e),
                     }
                 },
-                Ok(msg) = msg_rx.recv() => {
+                Ok(()) = self.args_rx.changed() => {
+                    self.args = self.args_rx.borrow_and_update().clone();
+                    match self.args.filter_rules().compile() {
+                        Ok(filter) => self.filter = filter,
+                        Err(e) => log::error!("keeping the previous filter, the reloaded one is invalid: {e:#}"),
+                    }
+                }
+                Ok(notification) = notification_rx.recv() => {
                     // Clean up the event handler when the message has been sent
-                    if let Message::FileUpdated { path } = msg {
+                    if let Notification::Change(
+                        Message::FileUpdated { path, .. }
+                        | Message::FileRemoved { path, .. }
+                        | Message::FileRenamed { to: path, .. },
+                    ) = notification
+                    {
                         self.event_handlers.remove(&path);
                     }
                 }
             }
+
+            self.metrics
+                .set_pending_event_handlers(self.event_handlers.len());
         }
     }
 
     fn process_event(&mut self, event: &Event) -> anyhow::Result<()> {
         log::debug!("received filesystem event: {event:?}");
+        self.metrics.event_received(event.kind);
+
+        // Updates are reported by the torrent client instead
+        if self.args.event_source != EventSource::Filesystem {
+            return Ok(());
+        }
+
+        let absolute_paths = event
+            .paths
+            .iter()
+            .map(|path| {
+                path::absolute(path).with_context(|| format!("failed to resolve path: {path:?}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // A rename within the watched paths is reported as a `From` event, a `To` event and
+        // finally a `Both` event containing both paths, which supersedes the previous two.
+        if let (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) =
+            (event.kind, absolute_paths.as_slice())
+        {
+            self.abort_event_handler(from);
+
+            let msg = Message::FileRenamed {
+                from: from.clone(),
+                to: sync_path(to),
+                seq: None,
+            };
+            let Some(msg) = filter_change(msg, |path, is_dir| self.is_match(path, is_dir)) else {
+                return Ok(());
+            };
+            let path = match msg {
+                Message::FileRemoved { .. } => from,
+                _ => to,
+            };
+            self.queue_notify_message(path, msg, self.args.sync_delay, event.kind);
 
-        for path in &event.paths {
-            let absolute_path = path::absolute(path)
-                .with_context(|| format!("failed to resolve path: {path:?}"))?;
+            return Ok(());
+        }
 
-            #[allow(clippy::single_match)]
+        for absolute_path in absolute_paths {
             match event.kind {
-                notify::EventKind::Create(_) | notify::EventKind::Modify(_) => {
-                    let mut path = absolute_path.clone();
-
-                    // Push an empty component to the path to add a trailing slash. This is
-                    // important for rsync to treat it as a directory so that
-                    // `rsync <src dir> <dst dir>`
-                    // synchronizes the state of `<src dir>` with `<dst dir>` instead of placing
-                    // `<src dir>` inside `<dst dir>`.
-                    if path.is_dir() {
-                        path.push("");
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                    // The path doesn't exist anymore, so it might have been a directory
+                    if !self.is_match(&absolute_path, true) {
+                        continue;
                     }
 
-                    let msg = Message::FileUpdated { path };
-                    self.queue_notify_message(&absolute_path, msg);
+                    let msg = Message::FileRemoved {
+                        path: absolute_path.clone(),
+                        seq: None,
+                    };
+                    self.queue_notify_message(
+                        &absolute_path,
+                        msg,
+                        self.args.sync_delay,
+                        event.kind,
+                    );
                 }
-                notify::EventKind::Remove(_) => {
-                    self.abort_event_handler(&absolute_path);
+                EventKind::Create(_)
+                | EventKind::Modify(_)
+                | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                    if !self.is_match(&absolute_path, absolute_path.is_dir()) {
+                        log::debug!("ignoring filtered path: {absolute_path:?}");
+                        continue;
+                    }
+
+                    let Some(delay) = self.update_delay(event.kind, &absolute_path) else {
+                        continue;
+                    };
+
+                    // Syncing the directory syncs the path too, so only the directory is synced
+                    // once the path is complete
+                    if let Some((ancestor, msg, kind)) =
+                        self.pending_ancestor_update(&absolute_path)
+                    {
+                        log::debug!(
+                            "{absolute_path:?} is updated along with its ancestor {ancestor:?}"
+                        );
+                        self.queue_notify_message(&ancestor, msg, delay, kind);
+                        continue;
+                    }
+
+                    let msg = Message::FileUpdated {
+                        path: sync_path(&absolute_path),
+                        seq: None,
+                    };
+                    self.queue_notify_message(&absolute_path, msg, delay, event.kind);
+                    if absolute_path.is_dir() {
+                        self.abort_descendant_updates(&absolute_path);
+                    }
                 }
                 _ => (),
             };
@@ -98,47 +264,333 @@ impl NotifyHandler {
         Ok(())
     }
 
-    fn queue_notify_message(&mut self, path: &Path, msg: Message) {
+    /// Returns true if `path` is under a watched path and passes the filter configured on the
+    /// server. Paths are matched relative to the closest watched path, so a path that doesn't
+    /// match is filtered for every connection.
+    fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        matching_watched_root(&self.watchers, &self.filter, path, is_dir).is_some()
+    }
+
+    /// Returns the closest ancestor of `path` with a pending update that covers `path`, along with
+    /// the update and the kind of the event that caused it.
+    fn pending_ancestor_update(&self, path: &Path) -> Option<(PathBuf, Message, EventKind)> {
+        let (ancestor, handler) = path.ancestors().skip(1).find_map(|ancestor| {
+            let handler = self.event_handlers.get(ancestor)?;
+            let pending =
+                matches!(handler.msg, Message::FileUpdated { .. }) && !handler.handle.is_finished();
+            pending.then_some((ancestor, handler))
+        })?;
+
+        // Connections watching a path between them wouldn't be informed of the update
+        let watched_below = self
+            .watchers
+            .lock()
+            .expect("watchers lock should not be poisoned")
+            .is_watched_below(ancestor, path);
+        if watched_below {
+            return None;
+        }
+
+        Some((ancestor.to_path_buf(), handler.msg.clone(), handler.kind))
+    }
+
+    /// Aborts the pending updates of paths under `dir` that are covered by its update.
+    fn abort_descendant_updates(&mut self, dir: &Path) {
+        let descendants: Vec<_> = self
+            .event_handlers
+            .iter()
+            .filter(|(path, handler)| {
+                *path != dir
+                    && path.starts_with(dir)
+                    && matches!(handler.msg, Message::FileUpdated { .. })
+                    && !handler.handle.is_finished()
+            })
+            .map(|(path, _handler)| path.clone())
+            .collect();
+        if descendants.is_empty() {
+            return;
+        }
+
+        let watchers = self
+            .watchers
+            .lock()
+            .expect("watchers lock should not be poisoned");
+        for descendant in descendants {
+            if watchers.is_watched_below(dir, &descendant) {
+                continue;
+            }
+
+            log::debug!("{descendant:?} is updated along with its ancestor {dir:?}");
+            if let Some(handler) = self.event_handlers.remove(&descendant) {
+                handler.handle.abort();
+            }
+        }
+    }
+
+    fn handle_watch_limit(&mut self, path: &Path) -> anyhow::Result<()> {
+        let status = self
+            .watchers
+            .lock()
+            .expect("watchers lock should not be poisoned")
+            .handle_watch_limit(path)?;
+
+        if let Some(status) = status {
+            self.notification_tx
+                .send(Notification::WatchStatus(status))?;
+        }
+
+        Ok(())
+    }
+
+    /// Tells the clients watching the paths of `e` that changes under them may have been missed.
+    fn handle_watch_error(&self, e: notify::Error) {
+        // Paths removed while being scanned by polling, or watched paths that don't exist anymore,
+        // which are reported on every scan. There's nothing left to sync under them.
+        let not_found = matches!(
+            &e.kind,
+            notify::ErrorKind::Io(io_error) if io_error.kind() == io::ErrorKind::NotFound
+        );
+        if not_found {
+            log::debug!("file watcher error: {e}");
+            return;
+        }
+
+        log::error!("file watcher error: {e}");
+        if e.paths.is_empty() {
+            return;
+        }
+
+        let notification = Notification::WatchError {
+            reason: e.to_string(),
+            paths: e.paths,
+        };
+        if let Err(e) = self.notification_tx.send(notification) {
+            log::error!("failed to broadcast file watcher error: {e:#}");
+        }
+    }
+
+    /// Returns the delay before an update of `path` caused by an event of `kind` is reported, or
+    /// `None` if the event should be ignored.
+    fn update_delay(&self, kind: EventKind, path: &Path) -> Option<Duration> {
+        // The modification time of a directory changes when its entries change, which are reported
+        // separately. Only reported when polling.
+        if kind == EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime)) && path.is_dir()
+        {
+            return None;
+        }
+
+        let close_write = kind == EventKind::Access(AccessKind::Close(AccessMode::Write));
+        if self.args.completion != CompletionStrategy::CloseWrite {
+            return (!close_write).then_some(self.args.sync_delay);
+        }
+
+        // Directories are never closed after writing, so they're only debounced
+        let is_dir = kind == EventKind::Create(CreateKind::Folder) || path.is_dir();
+        if close_write || is_dir {
+            Some(self.args.sync_delay)
+        } else {
+            Some(self.args.unclosed_write_delay)
+        }
+    }
+
+    /// Returns the update passed to the hooks once `msg` is broadcast, if any. `kind` is the kind
+    /// of the event that caused the update of `path`, which is the destination of renames.
+    fn hook_event(&self, path: &Path, msg: &Message, kind: EventKind) -> Option<HookEvent> {
+        let updated = matches!(
+            msg,
+            Message::FileUpdated { .. } | Message::FileRenamed { .. }
+        );
+        if self.hooks.is_none() || !updated {
+            return None;
+        }
+
+        let watched_root = self
+            .watchers
+            .lock()
+            .expect("watchers lock should not be poisoned")
+            .watched_root(path)?;
+        Some(HookEvent {
+            path: path.to_path_buf(),
+            watched_root,
+            event: hooks::event_name(kind),
+        })
+    }
+
+    fn queue_notify_message(
+        &mut self,
+        path: &Path,
+        msg: Message,
+        delay: Duration,
+        kind: EventKind,
+    ) {
+        let wait_until_stable = self.args.completion == CompletionStrategy::Stable
+            && matches!(msg, Message::FileUpdated { .. });
+
+        // Further modifications are picked up when checking if the file is stable, so the
+        // handler is kept to not reset the maximum wait
+        let pending = self
+            .event_handlers
+            .get(path)
+            .is_some_and(|handler| handler.msg == msg && !handler.handle.is_finished());
+        if wait_until_stable && pending {
+            return;
+        }
+
         self.abort_event_handler(path);
 
-        let sync_delay = self.args.sync_delay;
-        let msg_tx = self.server_msg_tx.clone();
-        self.event_handlers.insert(
-            path.to_path_buf(),
+        let args = self.args.clone();
+        let stable_path = path.to_path_buf();
+        let notification_tx = self.notification_tx.clone();
+        let journal = self.journal.clone();
+        let hooks = self.hooks.clone();
+        let hook_event = self.hook_event(path, &msg, kind);
+        let handler_msg = msg.clone();
+        let handle = tokio::spawn(async move {
+            if wait_until_stable {
+                completion::wait_until_stable(
+                    stable_path,
+                    args.stability_check_interval,
+                    args.stability_window,
+                    args.stability_max_wait,
+                )
+                .await;
+            } else {
+                sleep(delay).await;
+            }
+
+            // Spawn a separate task so it can't be canceled while the change is recorded in the
+            // journal.
             tokio::spawn(async move {
-                sleep(sync_delay).await;
-
-                // Spawn a separate task so it can't be canceled. Currently there aren't any yield
-                // points, so it isn't strictly necessary right now.
-                tokio::spawn(async move {
-                    let inner = || -> anyhow::Result<()> {
-                        log::info!("broadcasting message: {msg:?}");
-                        msg_tx.send(msg.clone())?;
-                        Ok(())
-                    };
+                broadcast_change(&notification_tx, journal.as_ref(), msg).await;
 
-                    if let Err(e) = inner() {
-                        log::error!("failed to send message: {e:#}");
-                    }
-                });
-            }),
+                if let (Some(hooks), Some(hook_event)) = (hooks, hook_event) {
+                    hooks.run(hook_event);
+                }
+            });
+        });
+
+        self.event_handlers.insert(
+            path.to_path_buf(),
+            EventHandler {
+                msg: handler_msg,
+                kind,
+                handle,
+            },
         );
     }
 
     fn abort_event_handler(&mut self, path: &Path) {
-        if let Some(handle) = self.event_handlers.remove(path) {
-            handle.abort();
+        if let Some(handler) = self.event_handlers.remove(path) {
+            handler.handle.abort();
         }
     }
 }
 
+/// Returns the closest watched path containing `path`, unless `path` doesn't pass `filter`, the
+/// filter configured on the server, relative to it.
+pub(crate) fn matching_watched_root(
+    watchers: &SharedWatchers,
+    filter: &Filter,
+    path: &Path,
+    is_dir: bool,
+) -> Option<PathBuf> {
+    let watched_root = watchers
+        .lock()
+        .expect("watchers lock should not be poisoned")
+        .watched_root(path)?;
+
+    let relative_path = path.strip_prefix(&watched_root).ok()?;
+    filter
+        .is_match(relative_path, is_dir)
+        .then_some(watched_root)
+}
+
+/// Records `msg` in `journal` and informs every connection of it.
+pub(crate) async fn broadcast_change(
+    notification_tx: &broadcast::Sender<Notification>,
+    journal: Option<&SharedJournal>,
+    msg: Message,
+) {
+    let Some(journal) = journal.cloned() else {
+        send_change(notification_tx, msg);
+        return;
+    };
+
+    // Changes are broadcast while holding the lock, so that clients receive them in the order of
+    // their sequence numbers
+    let notification_tx = notification_tx.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        let mut journal = journal.lock().expect("journal lock should not be poisoned");
+        let msg = record_in_journal(&mut journal, msg);
+        send_change(&notification_tx, msg);
+    })
+    .await;
+    if let Err(e) = res {
+        log::error!("failed to broadcast change: {e:#}");
+    }
+}
+
+fn send_change(notification_tx: &broadcast::Sender<Notification>, msg: Message) {
+    log::info!("broadcasting message: {msg:?}");
+    if let Err(e) = notification_tx.send(Notification::Change(msg)) {
+        log::error!("failed to send message: {e:#}");
+    }
+}
+
+/// Records `msg` in `journal` if it's a change, returning it with its sequence number. Failing
+/// to record it doesn't prevent connected clients from being informed.
+fn record_in_journal(journal: &mut Journal, mut msg: Message) -> Message {
+    match journal.record(&msg) {
+        Ok(Some(recorded_seq)) => {
+            if let Message::FileUpdated { seq, .. }
+            | Message::FileRemoved { seq, .. }
+            | Message::FileRenamed { seq, .. } = &mut msg
+            {
+                *seq = Some(recorded_seq);
+            }
+        }
+        Ok(None) => (),
+        Err(e) => log::error!("failed to record change in journal: {e:#}"),
+    }
+
+    msg
+}
+
+/// Returns the path that should be synchronized when `path` is updated.
+pub(crate) fn sync_path(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+
+    // Push an empty component to the path to add a trailing slash. This is
+    // important for rsync to treat it as a directory so that
+    // `rsync <src dir> <dst dir>`
+    // synchronizes the state of `<src dir>` with `<dst dir>` instead of placing
+    // `<src dir>` inside `<dst dir>`.
+    if path.is_dir() {
+        path.push("");
+    }
+
+    path
+}
+
 pub(crate) async fn notify_handler(
-    args: Args,
+    args_rx: watch::Receiver<Args>,
     rx: NotifyEventReceiver,
-    server_msg_tx: broadcast::Sender<Message>,
+    watchers: SharedWatchers,
+    notification_tx: broadcast::Sender<Notification>,
+    journal: Option<SharedJournal>,
+    metrics: SharedMetrics,
+    hooks: Option<SharedHooks>,
 ) {
-    let state = NotifyHandler::new(args, rx, server_msg_tx);
-    if let Err(e) = state.handle().await {
+    let res = NotifyHandler::new(
+        args_rx,
+        rx,
+        watchers,
+        notification_tx,
+        journal,
+        metrics,
+        hooks,
+    );
+    if let Err(e) = async { res?.handle().await }.await {
         log::error!("error in filesystem event handler: {e:#}");
     }
 }
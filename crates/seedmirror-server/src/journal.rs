@@ -0,0 +1,560 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use seedmirror_core::message::Message;
+use serde::{Deserialize, Serialize};
+
+use tokio::time::{MissedTickBehavior, interval};
+
+use crate::{
+    cli::Args,
+    watcher::{SharedWatchers, WatchGuard},
+};
+
+/// Append-only log of the changes broadcast to clients, stored as one JSON record per line. Lets
+/// clients catch up on the changes they missed while disconnected.
+///
+/// Renames are recorded as the removal of the old path followed by an update of the new one, so
+/// that only the latest change of each path has to be replayed.
+///
+/// Paths watched by clients keep being watched after they disconnect, otherwise there would be
+/// nothing to catch up on. They're recorded in the journal too, to be watched again on restart,
+/// until no client has watched them for `--journal-watch-ttl`.
+///
+/// The journal has an ID, generated when it's created, so that sequence numbers of a journal that
+/// was recreated aren't mistaken for those of the previous one.
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+
+    /// ID sent to clients along with sequence numbers.
+    id: String,
+
+    /// Maximum amount of updates kept, older ones are discarded.
+    max_entries: usize,
+
+    /// Most recent updates, ordered by sequence number.
+    entries: VecDeque<JournalEntry>,
+
+    /// Sequence number of the last recorded update.
+    last_seq: u64,
+
+    /// Amount of records in the file, including discarded updates.
+    file_records: usize,
+
+    /// Duration that paths keep being watched after the last client watching them disconnected.
+    watch_ttl: Duration,
+
+    /// Paths that were watched by a client.
+    watched_paths: HashMap<PathBuf, WatchedPath>,
+    watches: WatchGuard,
+}
+
+pub(crate) type SharedJournal = Arc<Mutex<Journal>>;
+
+/// Bounds of the interval between checks for paths that are no longer watched for the journal.
+const MIN_EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+struct WatchedPath {
+    /// Sequence number of the last update recorded before the path was watched. Every change of
+    /// the path after it is recorded.
+    since: u64,
+
+    /// Amount of connections watching the path.
+    connections: usize,
+
+    /// When the last connection watching the path disconnected.
+    released: SystemTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// First record of the file, missing in journals predating IDs.
+    Journal {
+        id: String,
+
+        /// Sequence number of the last update when the file was written, so that sequence
+        /// numbers aren't reused if no update is kept.
+        last_seq: u64,
+    },
+
+    Update(JournalEntry),
+
+    /// Replaces the previous record of the same path.
+    Watch {
+        #[serde(with = "seedmirror_core::path")]
+        path: PathBuf,
+
+        /// Missing in journals predating watched paths expiring.
+        #[serde(default)]
+        since: u64,
+
+        /// Unix time in seconds when the last connection watching the path disconnected, `None`
+        /// if one was connected.
+        #[serde(default)]
+        released: Option<u64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct JournalEntry {
+    seq: u64,
+
+    #[serde(with = "seedmirror_core::path")]
+    path: PathBuf,
+
+    /// Missing in journals predating removals being recorded.
+    #[serde(default)]
+    change: Change,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    #[default]
+    Update,
+    Removal,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it doesn't exist, and watches the paths that
+    /// were watched by clients before.
+    pub(crate) fn open(args: &Args, path: &Path, watchers: SharedWatchers) -> anyhow::Result<Self> {
+        let max_entries = args.journal_max_entries;
+        let mut entries = VecDeque::new();
+        let mut watched_paths = HashMap::new();
+        let mut file_records = 0;
+        let mut id = None;
+        let mut last_seq = 0;
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.with_context(|| format!("failed to read journal {path:?}"))?;
+
+                    // The last line is incomplete if the server was killed while writing it
+                    let record = match serde_json::from_str(&line) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            log::warn!("skipping invalid journal record {line:?}: {e:#}");
+                            continue;
+                        }
+                    };
+
+                    file_records += 1;
+                    match record {
+                        Record::Journal {
+                            id: journal_id,
+                            last_seq: journal_last_seq,
+                        } => {
+                            id = Some(journal_id);
+                            last_seq = journal_last_seq;
+                        }
+                        Record::Update(entry) => {
+                            entries.push_back(entry);
+                            if entries.len() > max_entries {
+                                entries.pop_front();
+                            }
+                        }
+                        Record::Watch {
+                            path,
+                            since,
+                            released,
+                        } => {
+                            // Connections watching the path were closed when the server stopped
+                            let released = released.map_or_else(SystemTime::now, |secs| {
+                                UNIX_EPOCH + Duration::from_secs(secs)
+                            });
+                            let watched_path = WatchedPath {
+                                since,
+                                connections: 0,
+                                released,
+                            };
+                            watched_paths.insert(path, watched_path);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(anyhow::anyhow!(e).context(format!("failed to open journal {path:?}")));
+            }
+        }
+
+        let last_seq = entries
+            .back()
+            .map_or(last_seq, |entry| entry.seq.max(last_seq));
+        let has_id = id.is_some();
+        let id = id.unwrap_or_else(new_id);
+        log::info!(
+            "opened journal {path:?} ({id}) with {} update(s), last sequence number is {last_seq}",
+            entries.len()
+        );
+
+        // The allowed roots might have changed since the paths were recorded
+        let watch_ttl = args.journal_watch_ttl;
+        watched_paths.retain(|path, watched_path| {
+            if is_expired(watched_path, watch_ttl) {
+                log::info!("no longer watching {path:?}, no client watched it in {watch_ttl:?}");
+                return false;
+            }
+
+            // Recorded paths were resolved when they were checked, so they're still the same
+            let allowed = args.allowed_path(path).as_deref() == Some(path.as_path());
+            if !allowed {
+                log::warn!("no longer watching {path:?} outside of the allowed roots");
+            }
+            allowed
+        });
+
+        let mut watches = WatchGuard::new(watchers);
+        for watched_path in watched_paths.keys() {
+            if let Err(e) = watches.watch(watched_path) {
+                log::error!("failed to watch {watched_path:?}: {e:#}");
+            }
+        }
+
+        let mut journal = Self {
+            path: path.to_path_buf(),
+            file: open_append(path)?,
+            id,
+            max_entries,
+            entries,
+            last_seq,
+            file_records,
+            watch_ttl,
+            watched_paths,
+            watches,
+        };
+        if !has_id || journal.file_records > journal.entries.len() + journal.watched_paths.len() + 1
+        {
+            journal.compact()?;
+        }
+
+        Ok(journal)
+    }
+
+    /// Returns the ID of the journal.
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Keeps watching `path` on behalf of a connection, until no connection has watched it for
+    /// `--journal-watch-ttl`. [`Journal::release`] must be called once the connection is closed.
+    pub(crate) fn keep_watching(&mut self, path: &Path) -> anyhow::Result<()> {
+        match self.watched_paths.get_mut(path) {
+            Some(watched_path) => {
+                watched_path.connections += 1;
+                if watched_path.connections > 1 {
+                    return Ok(());
+                }
+            }
+            None => {
+                self.watches.watch(path)?;
+                let watched_path = WatchedPath {
+                    since: self.last_seq,
+                    connections: 1,
+                    released: SystemTime::now(),
+                };
+                self.watched_paths.insert(path.to_path_buf(), watched_path);
+            }
+        }
+
+        self.append_watch(path)
+    }
+
+    /// Records that a connection watching `path` was closed, which starts its time-to-live if it
+    /// was the last one.
+    pub(crate) fn release(&mut self, path: &Path) -> anyhow::Result<()> {
+        let Some(watched_path) = self.watched_paths.get_mut(path) else {
+            return Ok(());
+        };
+
+        watched_path.connections = watched_path.connections.saturating_sub(1);
+        if watched_path.connections > 0 {
+            return Ok(());
+        }
+
+        watched_path.released = SystemTime::now();
+        self.append_watch(path)
+    }
+
+    /// Stops watching the paths that no connection has watched for `--journal-watch-ttl`, and
+    /// removes them from the file.
+    pub(crate) fn expire_watches(&mut self) -> anyhow::Result<()> {
+        let expired: Vec<_> = self
+            .watched_paths
+            .iter()
+            .filter(|(_path, watched_path)| is_expired(watched_path, self.watch_ttl))
+            .map(|(path, _watched_path)| path.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        for path in &expired {
+            log::info!(
+                "no longer watching {path:?}, no client watched it in {:?}",
+                self.watch_ttl
+            );
+            self.watches.unwatch(path);
+            self.watched_paths.remove(path);
+        }
+
+        self.compact()
+    }
+
+    /// Records `msg` if it's a change of a file, returning its sequence number.
+    pub(crate) fn record(&mut self, msg: &Message) -> anyhow::Result<Option<u64>> {
+        let seq = match msg {
+            Message::FileUpdated { path, .. } => self.record_change(path, Change::Update)?,
+            Message::FileRemoved { path, .. } => self.record_change(path, Change::Removal)?,
+            Message::FileRenamed { from, to, .. } => {
+                self.record_change(from, Change::Removal)?;
+                self.record_change(to, Change::Update)?
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(seq))
+    }
+
+    fn record_change(&mut self, path: &Path, change: Change) -> anyhow::Result<u64> {
+        let entry = JournalEntry {
+            seq: self.last_seq + 1,
+            path: path.to_path_buf(),
+            change,
+        };
+        self.append(&Record::Update(entry.clone()))?;
+
+        self.last_seq = entry.seq;
+        self.entries.push_back(entry);
+        if self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+
+        // Rewriting the file on every discarded update would be wasteful
+        if self.file_records > self.max_entries * 2 + self.watched_paths.len() {
+            self.compact()?;
+        }
+
+        Ok(self.last_seq)
+    }
+
+    /// Returns the changes of `watched_paths` recorded after `last_seq`, only including the
+    /// latest change of each path and skipping those superseded by changes that weren't recorded,
+    /// e.g. a removed path that exists again. Returns `None` if changes after `last_seq` were
+    /// discarded or not recorded because a watched path wasn't watched since, or if `last_seq` is
+    /// unknown because it refers to the journal with ID `journal_id`, which isn't this one.
+    pub(crate) fn replay(
+        &self,
+        journal_id: Option<&str>,
+        last_seq: u64,
+        watched_paths: &[PathBuf],
+    ) -> Option<Vec<Message>> {
+        if journal_id != Some(self.id.as_str()) {
+            log::info!(
+                "sequence number {last_seq} refers to journal {journal_id:?}, not to {}",
+                self.id
+            );
+            return None;
+        }
+
+        let oldest_seq = self
+            .entries
+            .front()
+            .map_or(self.last_seq + 1, |entry| entry.seq);
+        if last_seq > self.last_seq || last_seq + 1 < oldest_seq {
+            return None;
+        }
+
+        let recorded = watched_paths.iter().all(|path| {
+            self.watched_paths
+                .get(path)
+                .is_some_and(|watched_path| watched_path.since <= last_seq)
+        });
+        if !recorded {
+            return None;
+        }
+
+        let latest_seqs: HashMap<_, _> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.seq > last_seq)
+            .map(|entry| (&entry.path, entry.seq))
+            .collect();
+
+        let msgs = self
+            .entries
+            .iter()
+            .filter(|entry| latest_seqs.get(&entry.path) == Some(&entry.seq))
+            .filter_map(|entry| {
+                let path = entry.path.clone();
+                let seq = Some(entry.seq);
+                match (entry.change, entry.path.symlink_metadata().is_ok()) {
+                    (Change::Update, true) => Some(Message::FileUpdated { path, seq }),
+                    (Change::Removal, false) => Some(Message::FileRemoved { path, seq }),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Some(msgs)
+    }
+
+    fn append_watch(&mut self, path: &Path) -> anyhow::Result<()> {
+        let record = watch_record(path, &self.watched_paths[path]);
+        self.append(&record)
+    }
+
+    fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .with_context(|| format!("failed to write to journal {:?}", self.path))?;
+        self.file_records += 1;
+
+        Ok(())
+    }
+
+    /// Rewrites the file to only contain the kept records.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let header = Record::Journal {
+            id: self.id.clone(),
+            last_seq: self.last_seq,
+        };
+        let records: Vec<_> = std::iter::once(header)
+            .chain(
+                self.watched_paths
+                    .iter()
+                    .map(|(path, watched_path)| watch_record(path, watched_path)),
+            )
+            .chain(self.entries.iter().cloned().map(Record::Update))
+            .collect();
+        let mut contents = Vec::new();
+        for record in &records {
+            serde_json::to_writer(&mut contents, record)?;
+            contents.push(b'\n');
+        }
+
+        // Synced before replacing the journal, so that it's never replaced by an incomplete file
+        let mut tmp_file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create journal {tmp_path:?}"))?;
+        tmp_file
+            .write_all(&contents)
+            .and_then(|()| tmp_file.sync_data())
+            .with_context(|| format!("failed to write journal {tmp_path:?}"))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to replace journal {:?}", self.path))?;
+
+        log::debug!(
+            "compacted journal {:?} from {} to {} records",
+            self.path,
+            self.file_records,
+            records.len()
+        );
+        self.file = open_append(&self.path)?;
+        self.file_records = records.len();
+
+        Ok(())
+    }
+}
+
+/// Stops watching the paths that no connection has watched for `watch_ttl` as they expire.
+pub(crate) async fn expire_watches(journal: SharedJournal, watch_ttl: Duration) {
+    let mut interval = interval(watch_ttl.clamp(MIN_EXPIRY_INTERVAL, MAX_EXPIRY_INTERVAL));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let journal = journal.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            journal
+                .lock()
+                .expect("journal lock should not be poisoned")
+                .expire_watches()
+        })
+        .await;
+        if let Err(e) = res.map_err(anyhow::Error::from).and_then(|res| res) {
+            log::error!("failed to expire paths watched for the journal: {e:#}");
+        }
+    }
+}
+
+/// Paths kept watched for the journal on behalf of a connection, which are released when dropped.
+pub(crate) struct JournalWatchGuard {
+    journal: SharedJournal,
+    paths: Vec<PathBuf>,
+}
+
+impl JournalWatchGuard {
+    /// Returns a guard releasing `paths`, which [`Journal::keep_watching`] was called for.
+    pub(crate) fn new(journal: SharedJournal, paths: Vec<PathBuf>) -> Self {
+        Self { journal, paths }
+    }
+}
+
+impl Drop for JournalWatchGuard {
+    fn drop(&mut self) {
+        let mut journal = self
+            .journal
+            .lock()
+            .expect("journal lock should not be poisoned");
+        for path in &self.paths {
+            if let Err(e) = journal.release(path) {
+                log::error!("failed to record that {path:?} was released: {e:#}");
+            }
+        }
+    }
+}
+
+fn is_expired(watched_path: &WatchedPath, watch_ttl: Duration) -> bool {
+    watched_path.connections == 0
+        && watched_path
+            .released
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed >= watch_ttl)
+}
+
+fn watch_record(path: &Path, watched_path: &WatchedPath) -> Record {
+    let released = watched_path
+        .released
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |released| released.as_secs());
+
+    Record::Watch {
+        path: path.to_path_buf(),
+        since: watched_path.since,
+        released: (watched_path.connections == 0).then_some(released),
+    }
+}
+
+/// Returns an ID that's unique to this journal, made of the time it was created at and the
+/// process that created it.
+fn new_id() -> String {
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{:x}-{:x}", created.as_nanos(), std::process::id())
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open journal {path:?}"))
+}
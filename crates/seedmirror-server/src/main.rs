@@ -1,47 +1,100 @@
-use clap::Parser;
 use tokio::{
     signal::{self, unix::SignalKind},
+    sync::watch,
     task::JoinSet,
 };
 
+use crate::{cli::Args, config::ArgsLoader, systemd::Watchdog};
+
 mod cli;
+mod completion;
+mod config;
 mod connection;
+mod delivery;
+mod hooks;
 mod informer;
+mod journal;
+mod manifest;
+mod metrics;
+mod subscription;
+mod systemd;
+mod tls;
+mod torrent;
 mod watcher;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    let args = cli::Args::parse();
+    let loader = ArgsLoader::new();
+    let args = loader.load()?;
+
+    // Everything is logged unless `RUST_LOG` is set, and filtered by the maximum level instead so
+    // that it can be changed when reloading the configuration
+    let log_level_from_env = std::env::var_os("RUST_LOG").is_some();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+    if !log_level_from_env {
+        log::set_max_level(args.log_level);
+    }
 
-    let mut sigterm = tokio::signal::unix::signal(SignalKind::terminate())?;
+    let mut sigterm = signal::unix::signal(SignalKind::terminate())?;
+    let mut sighup = signal::unix::signal(SignalKind::hangup())?;
+    let mut watchdog = Watchdog::new();
 
+    let (args_tx, args_rx) = watch::channel(args.clone());
     let mut set = JoinSet::new();
-    set.spawn(connection::connection_manager(args.clone()));
-
-    log::info!(
-        "initialized. waiting for connections on socket {:?}...",
-        args.socket_path
-    );
-
-    tokio::select! {
-        _ = sigterm.recv() => {
-            log::info!("received SIGTERM, shutting down...");
-        },
-        res = signal::ctrl_c() => {
-            match res {
-                Ok(()) => {
-                    log::info!("received SIGINT, shutting down...");
-                }
-                Err(e) => {
-                    log::error!("unable to listen for shutdown signal: {e:#}");
+    set.spawn(connection::connection_manager(args_rx));
+
+    log::info!("initialized. waiting for connections...");
+
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                log::info!("received SIGTERM, shutting down...");
+                break;
+            },
+            res = signal::ctrl_c() => {
+                match res {
+                    Ok(()) => {
+                        log::info!("received SIGINT, shutting down...");
+                    }
+                    Err(e) => {
+                        log::error!("unable to listen for shutdown signal: {e:#}");
+                    }
                 }
+                break;
+            },
+            _ = sighup.recv() => {
+                reload(&loader, &args_tx, log_level_from_env);
+            },
+            _ = watchdog.keep_alive() => (),
+            _ = set.join_next() => {
+                log::info!("exiting...");
+                break;
             }
-        },
-        _ = set.join_next() => {
-            log::info!("exiting...");
         }
     }
 
     Ok(())
 }
+
+/// Reads the configuration file again and applies it to the running server. The current
+/// configuration is kept if the file is invalid.
+fn reload(loader: &ArgsLoader, args_tx: &watch::Sender<Args>, log_level_from_env: bool) {
+    let args = match loader.load() {
+        Ok(args) => args,
+        Err(e) => {
+            log::error!("failed to reload configuration, keeping the current one: {e:#}");
+            return;
+        }
+    };
+
+    let Some(config_path) = &args.config else {
+        log::warn!("received SIGHUP without a configuration file to reload");
+        return;
+    };
+    log::info!("received SIGHUP, reloaded configuration from {config_path:?}");
+
+    if !log_level_from_env {
+        log::set_max_level(args.log_level);
+    }
+    args_tx.send_replace(args);
+}
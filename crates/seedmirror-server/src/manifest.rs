@@ -0,0 +1,160 @@
+use std::{collections::VecDeque, mem, ops::ControlFlow, path::PathBuf};
+
+use seedmirror_core::{
+    filter::Filter,
+    manifest,
+    message::{ErrorCode, Message},
+    path,
+};
+use tokio::sync::mpsc;
+
+/// Maximum amount of entries per `Manifest` message. Even with paths as long as allowed by Linux,
+/// messages stay well under the default maximum frame size.
+const MANIFEST_CHUNK_SIZE: usize = 1000;
+
+/// Amount of `Manifest` messages listed ahead of the ones sent to the client.
+const MANIFEST_CHANNEL_CAPACITY: usize = 2;
+
+/// Manifests requested by a client. They're listed one at a time, so that a client receiving a
+/// `ManifestFailed` error knows that only the manifest it was receiving is incomplete.
+pub(crate) struct ManifestListings {
+    /// Messages of the manifest being listed.
+    current: Option<mpsc::Receiver<Message>>,
+
+    /// Requested manifests waiting for the current one to be listed.
+    queued: VecDeque<ManifestRequest>,
+}
+
+struct ManifestRequest {
+    /// Path requested by the client, which the manifest is sent for.
+    path: PathBuf,
+
+    /// Path the requested one resolves to, which is the one that's listed.
+    resolved: PathBuf,
+
+    filter: Filter,
+    binary_paths: bool,
+}
+
+impl ManifestListings {
+    pub(crate) fn new() -> Self {
+        Self {
+            current: None,
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Lists the files under `resolved`, which `path` resolves to, that pass `filter` once the
+    /// previously requested manifests are listed. Entries with non-UTF-8 paths are skipped unless
+    /// `binary_paths` is set.
+    pub(crate) fn request(
+        &mut self,
+        path: PathBuf,
+        resolved: PathBuf,
+        filter: Filter,
+        binary_paths: bool,
+    ) {
+        self.queued.push_back(ManifestRequest {
+            path,
+            resolved,
+            filter,
+            binary_paths,
+        });
+        if self.current.is_none() {
+            self.start_next();
+        }
+    }
+
+    /// Returns the next message to send to the client, waiting forever if no manifest is being
+    /// listed. Cancel safe.
+    pub(crate) async fn next(&mut self) -> Message {
+        loop {
+            let Some(current) = &mut self.current else {
+                return std::future::pending().await;
+            };
+
+            match current.recv().await {
+                Some(msg) => return msg,
+                None => self.start_next(),
+            }
+        }
+    }
+
+    fn start_next(&mut self) {
+        self.current = self.queued.pop_front().map(list);
+    }
+}
+
+/// Walks the requested path in a blocking task, sending the `Manifest` messages through the
+/// returned channel as the entries are found. The walk stops if the receiver is dropped.
+fn list(request: ManifestRequest) -> mpsc::Receiver<Message> {
+    let (tx, rx) = mpsc::channel(MANIFEST_CHANNEL_CAPACITY);
+    tokio::task::spawn_blocking(move || {
+        let ManifestRequest {
+            path,
+            resolved,
+            filter,
+            binary_paths,
+        } = request;
+
+        let mut chunk = Vec::new();
+        let mut listed = 0;
+        let mut cancelled = false;
+        let res = manifest::walk_each(&resolved, &filter, |entry| {
+            if !binary_paths && !path::is_utf8(&entry.path) {
+                log::warn!(
+                    "skipping non-UTF-8 path unsupported by client: {:?}",
+                    entry.path
+                );
+                return ControlFlow::Continue(());
+            }
+
+            listed += 1;
+            chunk.push(entry);
+            if chunk.len() < MANIFEST_CHUNK_SIZE {
+                return ControlFlow::Continue(());
+            }
+
+            let msg = Message::Manifest {
+                path: path.clone(),
+                entries: mem::take(&mut chunk),
+                done: false,
+            };
+            match tx.blocking_send(msg) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(_) => {
+                    cancelled = true;
+                    ControlFlow::Break(())
+                }
+            }
+        });
+
+        let msg = match res {
+            // The connection was closed or the manifest is no longer wanted
+            Ok(()) if cancelled => {
+                log::debug!("stopped listing {path:?} after {listed} file(s)");
+                return;
+            }
+            Ok(()) => {
+                log::info!("listed {listed} file(s) under {path:?}");
+                Message::Manifest {
+                    path,
+                    entries: chunk,
+                    done: true,
+                }
+            }
+            Err(e) => {
+                let e = anyhow::anyhow!(e).context(format!("failed to list files under {path:?}"));
+                log::error!("{e:#}");
+                Message::Error {
+                    code: ErrorCode::ManifestFailed,
+                    reason: format!("{e:#}"),
+                }
+            }
+        };
+        // Only fails if the connection was closed
+        let _ = tx.blocking_send(msg);
+    });
+
+    rx
+}
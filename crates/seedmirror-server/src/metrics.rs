@@ -0,0 +1,251 @@
+use std::{
+    fmt::Write as _,
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use notify::EventKind;
+use tokio::{
+    fs::{remove_file, set_permissions},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
+    time::timeout,
+};
+
+use crate::{cli::Args, watcher::SharedWatchers};
+
+/// Maximum duration of reading a request and writing the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum size of a request, which only has to contain a request line and a few headers.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Labels of the kinds of filesystem events, indexing [`Metrics::events`].
+const EVENT_KINDS: [&str; 6] = ["any", "access", "create", "modify", "remove", "other"];
+
+/// Counters and gauges exposed in the Prometheus text format.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Filesystem events received, by kind.
+    events: [AtomicU64; EVENT_KINDS.len()],
+
+    /// Notifications skipped by connections that couldn't keep up with the broadcast channel.
+    dropped_notifications: AtomicU64,
+
+    /// Event handlers waiting to report an update.
+    pending_event_handlers: AtomicU64,
+
+    connected_clients: AtomicU64,
+}
+
+pub(crate) type SharedMetrics = Arc<Metrics>;
+
+impl Metrics {
+    pub(crate) fn event_received(&self, kind: EventKind) {
+        let index = match kind {
+            EventKind::Any => 0,
+            EventKind::Access(_) => 1,
+            EventKind::Create(_) => 2,
+            EventKind::Modify(_) => 3,
+            EventKind::Remove(_) => 4,
+            EventKind::Other => 5,
+        };
+        self.events[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn notifications_dropped(&self, amount: u64) {
+        self.dropped_notifications
+            .fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_pending_event_handlers(&self, amount: usize) {
+        self.pending_event_handlers
+            .store(amount as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, watched_roots: usize) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "seedmirror_filesystem_events_total",
+            "counter",
+            "Filesystem events received from the watchers.",
+        );
+        for (kind, count) in EVENT_KINDS.iter().zip(&self.events) {
+            let count = count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "seedmirror_filesystem_events_total{{kind=\"{kind}\"}} {count}"
+            );
+        }
+
+        let metrics = [
+            (
+                "seedmirror_dropped_notifications_total",
+                "counter",
+                "Notifications skipped by connections that couldn't keep up.",
+                self.dropped_notifications.load(Ordering::Relaxed),
+            ),
+            (
+                "seedmirror_pending_event_handlers",
+                "gauge",
+                "Updates waiting for their delay or completion check before being reported.",
+                self.pending_event_handlers.load(Ordering::Relaxed),
+            ),
+            (
+                "seedmirror_connected_clients",
+                "gauge",
+                "Clients currently connected.",
+                self.connected_clients.load(Ordering::Relaxed),
+            ),
+            (
+                "seedmirror_watched_roots",
+                "gauge",
+                "Paths watched by clients, excluding those covered by a watched ancestor.",
+                watched_roots as u64,
+            ),
+        ];
+        for (name, metric_type, help, value) in metrics {
+            write_header(&mut out, name, metric_type, help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {metric_type}");
+}
+
+/// Starts serving the metrics over HTTP on the configured address and unix socket, if any.
+pub(crate) async fn serve(
+    args: &Args,
+    metrics: SharedMetrics,
+    watchers: SharedWatchers,
+) -> anyhow::Result<()> {
+    if let Some(addr) = args.metrics_address {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to listen for metrics requests on {addr}"))?;
+        log::info!("serving metrics on http://{addr}/metrics");
+
+        let (metrics, watchers) = (metrics.clone(), watchers.clone());
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _addr)) => {
+                        tokio::spawn(handle_request(stream, metrics.clone(), watchers.clone()));
+                    }
+                    Err(e) => log::error!("failed to accept metrics connection: {e:#}"),
+                }
+            }
+        });
+    }
+
+    if let Some(socket_path) = &args.metrics_socket_path {
+        let listener = bind_unix(socket_path, args.metrics_socket_mode).await?;
+        log::info!("serving metrics on socket {socket_path:?}");
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _addr)) => {
+                        tokio::spawn(handle_request(stream, metrics.clone(), watchers.clone()));
+                    }
+                    Err(e) => log::error!("failed to accept metrics connection: {e:#}"),
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn bind_unix(socket_path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if socket_path.try_exists()? {
+        remove_file(socket_path)
+            .await
+            .with_context(|| format!("failed to remove existing socket: {socket_path:?}"))?;
+    }
+
+    let listener = UnixListener::bind(socket_path)
+        .with_context(|| format!("failed to listen for metrics requests at {socket_path:?}"))?;
+    set_permissions(socket_path, Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("failed to set permissions of socket at {socket_path:?}"))?;
+
+    Ok(listener)
+}
+
+async fn handle_request(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    metrics: SharedMetrics,
+    watchers: SharedWatchers,
+) {
+    let res = timeout(REQUEST_TIMEOUT, respond(stream, metrics, watchers)).await;
+    match res {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => log::debug!("failed to answer metrics request: {e:#}"),
+        Err(_) => log::debug!("metrics request timed out"),
+    }
+}
+
+async fn respond(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    metrics: SharedMetrics,
+    watchers: SharedWatchers,
+) -> anyhow::Result<()> {
+    // Only the request line is needed, but the headers are read to not reset the connection
+    // while the client is still sending them
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the end of the request");
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("request is too large");
+        }
+    }
+
+    let request_line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let response = if request_line.starts_with(b"GET /metrics ") {
+        let watched_roots = watchers
+            .lock()
+            .expect("watchers lock should not be poisoned")
+            .watched_root_count();
+        let body = metrics.render(watched_roots);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use seedmirror_core::{
+    filter::Filter,
+    message::{ErrorCode, Message},
+};
+
+use crate::{
+    informer::Notification,
+    watcher::{SharedWatchers, WatchGuard, WatchStatus},
+};
+
+/// Path watched by a connection.
+pub(crate) struct WatchedPath {
+    /// Path requested by the client, which is used in the messages sent to it.
+    pub(crate) requested: PathBuf,
+
+    /// Path the requested one resolves to, which is the one that's watched, routed and journaled.
+    pub(crate) resolved: PathBuf,
+
+    pub(crate) filter: Filter,
+}
+
+/// Paths a connection is watching, deciding which notifications are forwarded to its client.
+pub(crate) struct Subscription {
+    paths: Vec<WatchedPath>,
+
+    /// Kept to keep the paths watched for as long as the subscription is alive.
+    _watches: WatchGuard,
+}
+
+impl Subscription {
+    /// Watches each path, returning the subscription and the errors to report to the client for
+    /// the paths that couldn't be watched entirely. Paths that couldn't be watched at all stay in
+    /// the subscription, so that they can still be listed, but no changes are reported under them.
+    pub(crate) fn new(watchers: SharedWatchers, paths: Vec<WatchedPath>) -> (Self, Vec<Message>) {
+        let mut watches = WatchGuard::new(watchers);
+        let errors = paths
+            .iter()
+            .filter_map(|path| match watches.watch(&path.resolved) {
+                Ok(status) => watch_status_error(status),
+                Err(e) => {
+                    let path = &path.requested;
+                    log::error!("failed to watch {path:?}: {e:#}");
+                    Some(Message::Error {
+                        code: ErrorCode::WatchFailed,
+                        reason: format!(
+                            "failed to watch {path:?}, changes under it won't be reported: {e:#}"
+                        ),
+                    })
+                }
+            })
+            .collect();
+
+        let subscription = Self {
+            paths,
+            _watches: watches,
+        };
+        (subscription, errors)
+    }
+
+    /// Returns the message to forward to the client for `notification`, if it's relevant to it.
+    pub(crate) fn route(&self, notification: Notification) -> Option<Message> {
+        let msg = self.route_resolved(notification)?;
+        Some(self.to_requested(msg))
+    }
+
+    /// Same as [`Self::route`], but the paths of the message are the resolved ones.
+    fn route_resolved(&self, notification: Notification) -> Option<Message> {
+        match notification {
+            Notification::Change(msg) => self.filter(msg),
+            Notification::WatchStatus(status) => {
+                let WatchStatus::LimitReached { path, .. } = &status else {
+                    return None;
+                };
+
+                if self.affected_paths(path).next().is_some() {
+                    watch_status_error(status)
+                } else {
+                    None
+                }
+            }
+            Notification::WatchError { paths, reason } => {
+                let mut affected: Vec<_> = paths
+                    .iter()
+                    .flat_map(|path| self.affected_paths(path))
+                    .map(Path::to_path_buf)
+                    .collect();
+                affected.sort();
+                affected.dedup();
+                if affected.is_empty() {
+                    return None;
+                }
+
+                log::warn!("changes under {affected:?} may have been missed: {reason}");
+                Some(Message::ResyncRequired { paths: affected })
+            }
+        }
+    }
+
+    /// Returns the watched paths containing `path` or contained in it.
+    fn affected_paths<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a Path> {
+        self.watched_paths().filter(move |watched_path| {
+            watched_path.starts_with(path) || path.starts_with(watched_path)
+        })
+    }
+
+    /// Returns the resolved watched paths.
+    pub(crate) fn watched_paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths.iter().map(|path| path.resolved.as_path())
+    }
+
+    /// Returns the watched paths as requested by the client.
+    pub(crate) fn requested_paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths.iter().map(|path| path.requested.as_path())
+    }
+
+    /// Replaces the filters of the watched paths, given for each requested path, which stay
+    /// watched.
+    pub(crate) fn set_filters(&mut self, filters: Vec<(PathBuf, Filter)>) {
+        for (requested, filter) in filters {
+            for path in &mut self.paths {
+                if path.requested == requested {
+                    path.filter = filter.clone();
+                }
+            }
+        }
+    }
+
+    /// Returns the watched path requested as `path`, if any.
+    pub(crate) fn watched_path(&self, path: &Path) -> Option<&WatchedPath> {
+        self.paths.iter().find(|watched| watched.requested == path)
+    }
+
+    /// Rewrites the resolved paths of `msg` to the paths requested by the client.
+    fn to_requested(&self, msg: Message) -> Message {
+        let requested = |path: PathBuf| self.requested_path(&path).unwrap_or(path);
+        match msg {
+            Message::FileUpdated { path, seq } => Message::FileUpdated {
+                path: requested(path),
+                seq,
+            },
+            Message::FileRemoved { path, seq } => Message::FileRemoved {
+                path: requested(path),
+                seq,
+            },
+            Message::FileRenamed { from, to, seq } => Message::FileRenamed {
+                from: requested(from),
+                to: requested(to),
+                seq,
+            },
+            Message::ResyncRequired { paths } => Message::ResyncRequired {
+                paths: paths.into_iter().map(requested).collect(),
+            },
+            msg => msg,
+        }
+    }
+
+    /// Returns `path` under the requested path of the closest watched path containing it, keeping
+    /// the trailing slash of directories.
+    fn requested_path(&self, path: &Path) -> Option<PathBuf> {
+        let watched = self
+            .paths
+            .iter()
+            .filter(|watched| path.starts_with(&watched.resolved))
+            .max_by_key(|watched| watched.resolved.components().count())?;
+        if watched.requested == watched.resolved {
+            return None;
+        }
+
+        let relative_path = path.strip_prefix(&watched.resolved).ok()?;
+        let mut requested = if relative_path.as_os_str().is_empty() {
+            watched.requested.clone().into_os_string()
+        } else {
+            watched.requested.join(relative_path).into_os_string()
+        };
+        let is_dir = |path: &OsStr| path.as_bytes().ends_with(b"/");
+        if is_dir(path.as_os_str()) && !is_dir(&requested) {
+            requested.push("/");
+        }
+
+        Some(PathBuf::from(requested))
+    }
+
+    fn filter(&self, msg: Message) -> Option<Message> {
+        filter_change(msg, |path, is_dir| self.is_match(path, is_dir))
+    }
+
+    /// Returns true if `path` is under a watched path and passes its filter.
+    fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        let best_match = self
+            .paths
+            .iter()
+            .filter(|watched| path.starts_with(&watched.resolved))
+            .max_by_key(|watched| watched.resolved.components().count());
+
+        best_match.is_some_and(|watched| {
+            path.strip_prefix(&watched.resolved)
+                .is_ok_and(|relative_path| watched.filter.is_match(relative_path, is_dir))
+        })
+    }
+}
+
+/// Returns the change to report instead of `msg` when only the paths for which `is_match` returns
+/// true are reported, if any. `is_match` is called with the path and whether it might be a
+/// directory.
+pub(crate) fn filter_change(
+    msg: Message,
+    is_match: impl Fn(&Path, bool) -> bool,
+) -> Option<Message> {
+    match msg {
+        // Renaming from or to a filtered path is the same as creating or removing a file
+        Message::FileRenamed { from, to, seq } => {
+            match (is_match(&from, true), is_match(&to, to.is_dir())) {
+                (true, true) => Some(Message::FileRenamed { from, to, seq }),
+                (false, true) => Some(Message::FileUpdated { path: to, seq }),
+                (true, false) => Some(Message::FileRemoved { path: from, seq }),
+                (false, false) => None,
+            }
+        }
+        Message::FileUpdated { ref path, .. } if !is_match(path, path.is_dir()) => {
+            log::debug!("ignoring filtered path: {path:?}");
+            None
+        }
+        // The path doesn't exist anymore, so it might have been a directory
+        Message::FileRemoved { ref path, .. } if !is_match(path, true) => None,
+        msg => Some(msg),
+    }
+}
+
+/// Returns the error reported to clients if a watched path couldn't be watched using inotify.
+pub(crate) fn watch_status_error(status: WatchStatus) -> Option<Message> {
+    let WatchStatus::LimitReached { path, polling } = status else {
+        return None;
+    };
+
+    let consequence = if polling {
+        "polling it instead"
+    } else {
+        "changes under it won't be reported, raise fs.inotify.max_user_watches or start the \
+        server with --poll-on-watch-limit"
+    };
+
+    Some(Message::Error {
+        code: ErrorCode::WatchLimitReached,
+        reason: format!("reached the inotify watch limit while watching {path:?}, {consequence}"),
+    })
+}
@@ -0,0 +1,90 @@
+use std::{
+    mem::ManuallyDrop,
+    os::{fd::FromRawFd, unix::net::UnixListener as StdUnixListener},
+    time::Duration,
+};
+
+use anyhow::Context;
+use sd_notify::NotifyState;
+use tokio::{
+    net::UnixListener,
+    time::{Interval, MissedTickBehavior, interval},
+};
+
+/// Returns the listening socket passed by the service manager through `LISTEN_FDS` when the
+/// server is socket-activated, if any.
+pub(crate) fn inherited_listener() -> anyhow::Result<Option<UnixListener>> {
+    // The variables are kept since modifying the environment isn't thread-safe. Child processes
+    // ignore them anyway, since `LISTEN_PID` doesn't match their pid.
+    let fds: Vec<_> = sd_notify::listen_fds()
+        .context("invalid socket activation variables")?
+        .collect();
+    let fd = match fds.as_slice() {
+        [] => return Ok(None),
+        [fd] => *fd,
+        fds => anyhow::bail!("expected a single socket from the service manager, got {fds:?}"),
+    };
+
+    // SAFETY: The service manager passes ownership of the file descriptors listed in `LISTEN_FDS`
+    // to the process, and they're only taken once since `listen_fds` is only called here. The
+    // descriptor isn't closed if it's invalid, since it might then belong to something else.
+    let listener = ManuallyDrop::new(unsafe { StdUnixListener::from_raw_fd(fd) });
+    listener
+        .local_addr()
+        .context("file descriptor passed by the service manager isn't a unix domain socket")?;
+    let listener = ManuallyDrop::into_inner(listener);
+    listener.set_nonblocking(true)?;
+
+    Ok(Some(UnixListener::from_std(listener)?))
+}
+
+/// Tells the service manager that the server is ready to accept connections, if it's waiting for
+/// it through `NOTIFY_SOCKET`.
+pub(crate) fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        log::error!("failed to notify service manager: {e:#}");
+    }
+}
+
+/// Pings the service manager when its watchdog is enabled through `WATCHDOG_USEC`, so that it
+/// restarts the server if it stops responding.
+pub(crate) struct Watchdog {
+    /// `None` if the watchdog isn't enabled.
+    interval: Option<Interval>,
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        let mut timeout_usec = 0;
+        if !sd_notify::watchdog_enabled(false, &mut timeout_usec) {
+            return Self { interval: None };
+        }
+
+        // Ping twice per timeout, as recommended by sd_watchdog_enabled(3), so a late ping doesn't
+        // trigger a restart
+        let period = Duration::from_micros(timeout_usec) / 2;
+        log::info!("service manager watchdog enabled, pinging it every {period:?}");
+        let mut interval = interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            interval: Some(interval),
+        }
+    }
+
+    /// Waits until the service manager has to be pinged again and pings it. Never returns if the
+    /// watchdog isn't enabled.
+    pub(crate) async fn keep_alive(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+                notify(NotifyState::Watchdog);
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::cli::Args;
+
+/// Creates the acceptor for TLS connections over TCP from the configured certificates.
+pub(crate) fn acceptor(args: &Args) -> anyhow::Result<TlsAcceptor> {
+    let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) else {
+        anyhow::bail!("--tls-cert and --tls-key are required to listen on TCP");
+    };
+
+    let certs = read_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("failed to read private key {key_path:?}"))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &args.tls_client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("invalid client certificate authorities")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .context("invalid certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates {path:?}"))
+}
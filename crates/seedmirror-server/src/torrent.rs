@@ -0,0 +1,491 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use reqwest::{
+    StatusCode, Url,
+    header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+};
+use seedmirror_core::{filter::Filter, message::Message};
+use serde::Deserialize;
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+    time::{MissedTickBehavior, interval},
+};
+
+use crate::{
+    cli::{Args, EventSource},
+    hooks::{HookEvent, SharedHooks},
+    informer::{self, Notification},
+    journal::SharedJournal,
+    watcher::SharedWatchers,
+};
+
+/// Maximum duration of a request to the torrent client, including reading the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum size of a response, which lists every torrent of the torrent client.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Fields of the torrents requested from Transmission.
+const TRANSMISSION_TORRENT_GET: &str = r#"{"method":"torrent-get","arguments":{"fields":["hashString","downloadDir","name","percentDone"]}}"#;
+
+/// Plain HTTP URL of the API of a torrent client.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpUrl(Url);
+
+impl HttpUrl {
+    pub(crate) fn parse(s: &str) -> clap::error::Result<Self, String> {
+        let url = Url::parse(s).map_err(|e| format!("invalid URL '{s}': {e}"))?;
+        if url.scheme() != "http" {
+            return Err(format!(
+                "invalid URL '{s}', only http:// URLs are supported"
+            ));
+        }
+
+        Ok(Self(url))
+    }
+
+    /// Returns the URL of `path` under this one.
+    fn join(&self, path: &str) -> Url {
+        let mut url = self.0.clone();
+        let base_path = url.path().trim_end_matches('/').to_string();
+        url.set_path(&format!("{base_path}{path}"));
+        url
+    }
+}
+
+/// Torrent as reported by the torrent client.
+struct Torrent {
+    hash: String,
+
+    /// File of a single-file torrent, or directory containing the files of the torrent.
+    content_path: PathBuf,
+
+    /// Whether every wanted file of the torrent was downloaded.
+    complete: bool,
+}
+
+/// API of the torrent client configured with `--event-source`.
+enum TorrentClient {
+    Qbittorrent(Qbittorrent),
+    Transmission(Transmission),
+}
+
+impl TorrentClient {
+    /// Returns `None` if updates aren't reported by a torrent client.
+    fn new(args: &Args) -> anyhow::Result<Option<Self>> {
+        if args.event_source == EventSource::Filesystem {
+            return Ok(None);
+        }
+
+        let url = args
+            .torrent_client_url
+            .clone()
+            .context("--torrent-client-url is required to poll a torrent client")?;
+        let credentials = match (
+            &args.torrent_client_username,
+            &args.torrent_client_password_file,
+        ) {
+            (Some(username), Some(password_file)) => {
+                Some((username.clone(), read_password(password_file)?))
+            }
+            _ => None,
+        };
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("failed to create HTTP client")?;
+
+        let client = match args.event_source {
+            EventSource::Filesystem => unreachable!("handled above"),
+            EventSource::Qbittorrent => Self::Qbittorrent(Qbittorrent {
+                http,
+                url,
+                credentials,
+                cookie: None,
+            }),
+            EventSource::Transmission => Self::Transmission(Transmission {
+                http,
+                url,
+                credentials,
+                session_id: None,
+            }),
+        };
+        Ok(Some(client))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Qbittorrent(_) => "qBittorrent",
+            Self::Transmission(_) => "Transmission",
+        }
+    }
+
+    async fn torrents(&mut self) -> anyhow::Result<Vec<Torrent>> {
+        match self {
+            Self::Qbittorrent(client) => client.torrents().await,
+            Self::Transmission(client) => client.torrents().await,
+        }
+    }
+}
+
+/// qBittorrent WebUI API, see
+/// <https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)>.
+struct Qbittorrent {
+    http: reqwest::Client,
+    url: HttpUrl,
+
+    /// User name and password, if authentication isn't bypassed.
+    credentials: Option<(String, String)>,
+
+    /// Session cookie set when logging in.
+    cookie: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QbittorrentTorrent {
+    hash: String,
+    content_path: PathBuf,
+    progress: f64,
+}
+
+impl Qbittorrent {
+    async fn torrents(&mut self) -> anyhow::Result<Vec<Torrent>> {
+        if self.cookie.is_none() && self.credentials.is_some() {
+            self.login().await?;
+        }
+
+        let mut response = self.torrent_info().await?;
+        // The session expires after a period of inactivity, or when qBittorrent restarts
+        if response.status() == StatusCode::FORBIDDEN && self.credentials.is_some() {
+            self.login().await?;
+            response = self.torrent_info().await?;
+        }
+
+        let body = read_body(response.error_for_status()?).await?;
+        let torrents: Vec<QbittorrentTorrent> =
+            serde_json::from_slice(&body).context("invalid list of torrents")?;
+        Ok(torrents
+            .into_iter()
+            .map(|torrent| Torrent {
+                hash: torrent.hash,
+                content_path: torrent.content_path,
+                complete: torrent.progress >= 1.0,
+            })
+            .collect())
+    }
+
+    async fn torrent_info(&self) -> anyhow::Result<reqwest::Response> {
+        let mut request = self.http.get(self.url.join("/api/v2/torrents/info"));
+        if let Some(cookie) = &self.cookie {
+            request = request.header(COOKIE, cookie);
+        }
+
+        request.send().await.context("failed to list torrents")
+    }
+
+    async fn login(&mut self) -> anyhow::Result<()> {
+        let (username, password) = self
+            .credentials
+            .as_ref()
+            .expect("login requires credentials");
+        let response = self
+            .http
+            .post(self.url.join("/api/v2/auth/login"))
+            .form(&[("username", username), ("password", password)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("failed to log in")?;
+
+        // Failed logins are also answered with 200 OK
+        let cookie = response
+            .headers()
+            .get(SET_COOKIE)
+            .and_then(|cookie| cookie.to_str().ok())
+            .and_then(|cookie| cookie.split(';').next())
+            .context("failed to log in, invalid user name or password")?;
+        self.cookie = Some(cookie.to_string());
+        log::debug!("logged in to qBittorrent as {username:?}");
+
+        Ok(())
+    }
+}
+
+/// Transmission RPC interface, see
+/// <https://github.com/transmission/transmission/blob/main/docs/rpc-spec.md>.
+struct Transmission {
+    http: reqwest::Client,
+    url: HttpUrl,
+
+    /// User name and password, if authentication is required.
+    credentials: Option<(String, String)>,
+
+    /// Token protecting against CSRF, given by Transmission when it rejects a request without it.
+    session_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TransmissionResponse {
+    result: String,
+    arguments: Option<TransmissionTorrents>,
+}
+
+#[derive(Deserialize)]
+struct TransmissionTorrents {
+    torrents: Vec<TransmissionTorrent>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransmissionTorrent {
+    hash_string: String,
+    download_dir: PathBuf,
+    name: String,
+    percent_done: f64,
+}
+
+impl Transmission {
+    async fn torrents(&mut self) -> anyhow::Result<Vec<Torrent>> {
+        let mut response = self.torrent_get().await?;
+        // The session id changes when Transmission restarts
+        if response.status() == StatusCode::CONFLICT {
+            let session_id = response
+                .headers()
+                .get("X-Transmission-Session-Id")
+                .and_then(|session_id| session_id.to_str().ok())
+                .context("missing session id in 409 response")?;
+            self.session_id = Some(session_id.to_string());
+            response = self.torrent_get().await?;
+        }
+        if response.status() == StatusCode::UNAUTHORIZED {
+            anyhow::bail!("unauthorized, invalid user name or password");
+        }
+
+        let body = read_body(response.error_for_status()?).await?;
+        let response: TransmissionResponse =
+            serde_json::from_slice(&body).context("invalid list of torrents")?;
+        let torrents = match (response.result.as_str(), response.arguments) {
+            ("success", Some(arguments)) => arguments.torrents,
+            (result, _) => anyhow::bail!("failed to get torrents: {result}"),
+        };
+        Ok(torrents
+            .into_iter()
+            .map(|torrent| Torrent {
+                hash: torrent.hash_string,
+                content_path: torrent.download_dir.join(torrent.name),
+                complete: torrent.percent_done >= 1.0,
+            })
+            .collect())
+    }
+
+    async fn torrent_get(&self) -> anyhow::Result<reqwest::Response> {
+        let mut request = self
+            .http
+            .post(self.url.join("/transmission/rpc"))
+            .header(CONTENT_TYPE, "application/json")
+            .body(TRANSMISSION_TORRENT_GET);
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        if let Some(session_id) = &self.session_id {
+            request = request.header("X-Transmission-Session-Id", session_id);
+        }
+
+        request.send().await.context("failed to list torrents")
+    }
+}
+
+fn read_password(path: &Path) -> anyhow::Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read torrent client password {path:?}"))?;
+    Ok(contents.lines().next().unwrap_or_default().to_string())
+}
+
+/// Reads the body of `response`, failing if it's larger than [`MAX_RESPONSE_SIZE`].
+async fn read_body(mut response: reqwest::Response) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("failed to read response body")?
+    {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_RESPONSE_SIZE {
+            anyhow::bail!("response is too large");
+        }
+    }
+
+    Ok(body)
+}
+
+/// Starts reporting the content of torrents in the background once the torrent client completes
+/// them. Returns the polling task, or `None` if updates aren't reported by a torrent client.
+pub(crate) fn poll_torrents(
+    mut args_rx: watch::Receiver<Args>,
+    watchers: SharedWatchers,
+    notification_tx: broadcast::Sender<Notification>,
+    journal: Option<SharedJournal>,
+    hooks: Option<SharedHooks>,
+) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let args = args_rx.borrow_and_update().clone();
+    let Some(mut client) = TorrentClient::new(&args)? else {
+        return Ok(None);
+    };
+
+    // Torrents are filtered like filesystem events, with the filter configured on the server
+    let mut filter = args
+        .filter_rules()
+        .compile()
+        .context("invalid filter configured on the server")?;
+
+    let mut interval = interval(args.torrent_poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // Without a state file, torrents completed before the server started aren't reported, and
+    // clients replaying the journal would miss them
+    let state_path = args.torrent_state_path.clone();
+    let mut completed = match &state_path {
+        Some(path) => load_completed(path)?,
+        None => None,
+    };
+    let poller = tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+
+            if args_rx.has_changed().unwrap_or(false) {
+                match args_rx.borrow_and_update().filter_rules().compile() {
+                    Ok(new_filter) => filter = new_filter,
+                    Err(e) => log::error!(
+                        "keeping the previous filter for torrents, the reloaded one is invalid: {e:#}"
+                    ),
+                }
+            }
+
+            let torrents = match client.torrents().await {
+                Ok(torrents) => torrents,
+                Err(e) => {
+                    log::warn!("failed to get torrents from {}: {e:#}", client.name());
+                    continue;
+                }
+            };
+
+            let now_completed: HashSet<_> = torrents
+                .iter()
+                .filter(|torrent| torrent.complete)
+                .map(|torrent| torrent.hash.clone())
+                .collect();
+            match &completed {
+                Some(previously_completed) => {
+                    let newly_completed = torrents.iter().filter(|torrent| {
+                        torrent.complete && !previously_completed.contains(&torrent.hash)
+                    });
+                    for torrent in newly_completed {
+                        log::debug!("torrent {} completed", torrent.hash);
+                        report_update(
+                            &torrent.content_path,
+                            &watchers,
+                            &filter,
+                            &notification_tx,
+                            journal.as_ref(),
+                            hooks.as_ref(),
+                        )
+                        .await;
+                    }
+                }
+                None => log::info!(
+                    "polling {} for completed torrents, {} torrent(s) already completed",
+                    client.name(),
+                    now_completed.len()
+                ),
+            }
+
+            // Torrents that are removed, or checked again and found incomplete, are reported
+            // again once they're complete
+            if completed.as_ref() == Some(&now_completed) {
+                continue;
+            }
+            if let Some(path) = &state_path {
+                let path = path.clone();
+                let hashes = now_completed.clone();
+                let saved = tokio::task::spawn_blocking(move || save_completed(&path, &hashes))
+                    .await
+                    .expect("saving torrent state should not panic");
+                if let Err(e) = saved {
+                    log::warn!("{e:#}");
+                }
+            }
+            completed = Some(now_completed);
+        }
+    });
+
+    Ok(Some(poller))
+}
+
+/// Reads the hashes of the torrents completed when the server last polled the torrent client, or
+/// returns `None` if it never did.
+fn load_completed(path: &Path) -> anyhow::Result<Option<HashSet<String>>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(
+            contents
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read torrent state {path:?}")),
+    }
+}
+
+fn save_completed(path: &Path, hashes: &HashSet<String>) -> anyhow::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+
+    let mut contents = String::new();
+    for hash in hashes {
+        contents.push_str(hash);
+        contents.push('\n');
+    }
+    fs::write(&tmp_path, contents)
+        .with_context(|| format!("failed to write torrent state {tmp_path:?}"))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("failed to replace torrent state {path:?}"))
+}
+
+/// Reports the content of a completed torrent at `path` if it's under a watched path and passes
+/// `filter`, the filter configured on the server.
+async fn report_update(
+    path: &Path,
+    watchers: &SharedWatchers,
+    filter: &Filter,
+    notification_tx: &broadcast::Sender<Notification>,
+    journal: Option<&SharedJournal>,
+    hooks: Option<&SharedHooks>,
+) {
+    let Some(watched_root) = informer::matching_watched_root(watchers, filter, path, path.is_dir())
+    else {
+        log::debug!("ignoring completed torrent {path:?}, which isn't watched or is filtered");
+        return;
+    };
+
+    let msg = Message::FileUpdated {
+        path: informer::sync_path(path),
+        seq: None,
+    };
+    informer::broadcast_change(notification_tx, journal, msg).await;
+
+    if let Some(hooks) = hooks {
+        hooks.run(HookEvent {
+            path: path.to_path_buf(),
+            watched_root,
+            event: "torrent-complete",
+        });
+    }
+}
@@ -1,31 +1,752 @@
-use notify::{Error, Event, INotifyWatcher, RecommendedWatcher, Watcher};
-use tokio::{
-    runtime::Handle,
-    sync::mpsc::{self, Receiver},
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::CString,
+    io,
+    mem::{self, MaybeUninit},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-pub(crate) type NotifyEventReceiver = Receiver<Result<Event, Error>>;
+use anyhow::Context;
+use notify::{Error, ErrorKind, Event, INotifyWatcher, PollWatcher, RecursiveMode, Watcher};
+use tokio::sync::{
+    Notify,
+    mpsc::{self, error::TrySendError},
+};
+
+use crate::cli::{Args, EventSource, WatcherBackend};
+
+/// Amount of file watcher events waiting to be processed before further events are dropped.
+const EVENT_CHANNEL_CAPACITY: usize = 16 * 1024;
+
+/// Receives the events of the file watchers, and an error for the paths of the events dropped
+/// while too many were waiting to be processed, so that changes under them are synced again.
+pub(crate) struct NotifyEventReceiver {
+    rx: mpsc::Receiver<Result<Event, Error>>,
+    overflow: Arc<Overflow>,
+}
+
+/// Sends the events of the file watchers to the [`NotifyEventReceiver`].
+#[derive(Clone)]
+struct NotifyEventSender {
+    tx: mpsc::Sender<Result<Event, Error>>,
+    overflow: Arc<Overflow>,
+}
+
+/// Paths of the events dropped since the receiver was last told about it.
+#[derive(Default)]
+struct Overflow {
+    paths: Mutex<BTreeSet<PathBuf>>,
+    notify: Notify,
+}
+
+impl NotifyEventReceiver {
+    /// Receives the next event, or an error for the paths of the dropped events. Cancel safe.
+    pub(crate) async fn recv(&mut self) -> Option<Result<Event, Error>> {
+        tokio::select! {
+            res = self.rx.recv() => res,
+            () = self.overflow.notify.notified() => {
+                let paths = mem::take(
+                    &mut *self
+                        .overflow
+                        .paths
+                        .lock()
+                        .expect("overflow lock should not be poisoned"),
+                );
+                let error = paths.into_iter().fold(
+                    Error::generic("too many file watcher events, some were dropped"),
+                    Error::add_path,
+                );
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl NotifyEventSender {
+    fn send(&self, res: Result<Event, Error>) {
+        let res = match self.tx.try_send(res) {
+            Ok(()) => return,
+            Err(TrySendError::Full(res)) => res,
+            Err(TrySendError::Closed(_)) => {
+                log::error!("failed to send file watcher message: channel closed");
+                return;
+            }
+        };
+
+        let paths = match res {
+            Ok(event) => event.paths,
+            Err(e) => e.paths,
+        };
+        let mut overflow = self
+            .overflow
+            .paths
+            .lock()
+            .expect("overflow lock should not be poisoned");
+        if paths.is_empty() {
+            // The event may concern any watched path
+            overflow.insert(PathBuf::from("/"));
+        } else {
+            overflow.extend(paths);
+        }
+        drop(overflow);
+        self.overflow.notify.notify_one();
+    }
+}
+
+fn notify_channel(capacity: usize) -> (NotifyEventSender, NotifyEventReceiver) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let overflow = Arc::new(Overflow::default());
+    let tx = NotifyEventSender {
+        tx,
+        overflow: overflow.clone(),
+    };
+    (tx, NotifyEventReceiver { rx, overflow })
+}
+
+/// Filesystems where inotify doesn't report changes made by other machines, identified by the
+/// magic numbers returned by statfs(2).
+const REMOTE_FILESYSTEMS: &[(&str, u32)] = &[
+    ("nfs", 0x6969),
+    ("smb", 0x517b),
+    ("cifs", 0xff534d42),
+    ("smb2", 0xfe534d42),
+    ("fuse", 0x65735546),
+];
+
+/// File watchers of every backend, all sending events to the same [`NotifyEventReceiver`]. Shared
+/// by all connections, so each path is only watched once regardless of how many clients watch it.
+pub(crate) struct Watchers {
+    args: Args,
+    tx: NotifyEventSender,
+    inotify: INotifyWatcher,
+
+    /// Created once the first path is watched by polling.
+    poll: Option<PollWatcher>,
+
+    /// Paths watched by at least one connection.
+    registrations: HashMap<PathBuf, Registration>,
+}
+
+pub(crate) type SharedWatchers = Arc<Mutex<Watchers>>;
+
+struct Registration {
+    /// Amount of connections watching the path.
+    connections: usize,
+
+    status: WatchStatus,
+
+    /// Backend watching the path, `None` if it's watched through a registered ancestor or not
+    /// watched at all, which is also the case when updates are reported by a torrent client.
+    backend: Option<WatcherBackend>,
+
+    /// Directories under the path that are polled since inotify ran out of watches while watching
+    /// them, while the rest of the path is still watched using inotify.
+    polled_subtrees: Vec<PathBuf>,
+}
 
-pub(crate) async fn create_watcher() -> anyhow::Result<(INotifyWatcher, NotifyEventReceiver)> {
-    let (tx, rx) = mpsc::channel(1);
+/// Result of watching a path.
+#[derive(Clone, Debug)]
+pub(crate) enum WatchStatus {
+    Watched,
 
-    let handle = Handle::current();
-    let watcher = RecommendedWatcher::new(
-        move |res| {
-            let tx = tx.clone();
-            handle.spawn(async move {
-                let fut = async {
-                    tx.send(res).await?;
-                    Ok::<(), anyhow::Error>(())
+    /// The inotify watch limit was reached while watching `path`. It's polled instead if
+    /// `--poll-on-watch-limit` is set, otherwise changes under it aren't reported.
+    LimitReached {
+        path: PathBuf,
+        polling: bool,
+    },
+}
+
+impl Watchers {
+    /// Recursively watches `path` on behalf of a connection, unless it's already watched.
+    fn watch(&mut self, path: &Path) -> anyhow::Result<WatchStatus> {
+        if let Some(registration) = self.registrations.get_mut(path) {
+            registration.connections += 1;
+            return Ok(registration.status.clone());
+        }
+
+        let registration = match self.ancestor_registration(path) {
+            Some(ancestor) => Registration {
+                connections: 1,
+                status: ancestor.status.clone(),
+                backend: None,
+                polled_subtrees: Vec::new(),
+            },
+            None => {
+                // Descendants are now watched through `path`, and watching a path more than once
+                // would make unwatching one of them unwatch both. They're unwatched first, since
+                // inotify reuses their watches for `path` and unwatching them afterwards would
+                // remove those too.
+                let descendants: Vec<_> = self
+                    .registrations
+                    .iter()
+                    .filter(|(registered, registration)| {
+                        registered.starts_with(path) && registration.backend.is_some()
+                    })
+                    .map(|(registered, _registration)| registered.clone())
+                    .collect();
+                for descendant in &descendants {
+                    self.stop_watching(descendant);
+                }
+
+                let (status, backend) = match self.start_watching(path) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        for descendant in &descendants {
+                            self.rewatch(descendant);
+                        }
+                        return Err(e);
+                    }
                 };
 
-                if let Err(e) = fut.await {
-                    log::error!("failed to send file watcher message: {e:#}");
+                Registration {
+                    connections: 1,
+                    status,
+                    backend,
+                    polled_subtrees: Vec::new(),
+                }
+            }
+        };
+
+        let status = registration.status.clone();
+        self.registrations.insert(path.to_path_buf(), registration);
+        Ok(status)
+    }
+
+    /// Stops watching `path` on behalf of a connection, and stops watching it altogether if no
+    /// other connection is watching it.
+    fn unwatch(&mut self, path: &Path) {
+        let Some(registration) = self.registrations.get_mut(path) else {
+            return;
+        };
+
+        registration.connections -= 1;
+        if registration.connections > 0 {
+            return;
+        }
+
+        if registration.backend.is_some() {
+            self.stop_watching(path);
+        }
+        self.registrations.remove(path);
+
+        // Descendants that were watched through `path` must now be watched by themselves
+        let mut orphans: Vec<_> = self
+            .registrations
+            .keys()
+            .filter(|registered| registered.starts_with(path))
+            .cloned()
+            .collect();
+        orphans.sort_by_key(|orphan| orphan.components().count());
+        for orphan in orphans {
+            if self.ancestor_registration(&orphan).is_none() {
+                self.rewatch(&orphan);
+            }
+        }
+    }
+
+    /// Starts watching the registered `path` by itself again, after it stopped being watched
+    /// through an ancestor.
+    fn rewatch(&mut self, path: &Path) {
+        let (status, backend) = match self.start_watching(path) {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!("failed to watch {path:?}: {e:#}");
+                return;
+            }
+        };
+
+        let registration = self
+            .registrations
+            .get_mut(path)
+            .expect("path should be registered");
+        registration.status = status;
+        registration.backend = backend;
+    }
+
+    /// Returns the amount of registered paths, excluding the ones under a registered ancestor.
+    pub(crate) fn watched_root_count(&self) -> usize {
+        self.registrations
+            .keys()
+            .filter(|path| self.ancestor_registration(path).is_none())
+            .count()
+    }
+
+    /// Returns the closest watched path containing `path`.
+    pub(crate) fn watched_root(&self, path: &Path) -> Option<PathBuf> {
+        path.ancestors()
+            .find(|ancestor| self.registrations.contains_key(*ancestor))
+            .map(Path::to_path_buf)
+    }
+
+    /// Returns true if a path containing `path` but not `ancestor` is watched, in which case the
+    /// connections watching it aren't informed of updates of `ancestor`.
+    pub(crate) fn is_watched_below(&self, ancestor: &Path, path: &Path) -> bool {
+        self.registrations
+            .keys()
+            .any(|registered| path.starts_with(registered) && !ancestor.starts_with(registered))
+    }
+
+    /// Handles inotify running out of watches while watching `path`, a new directory under a
+    /// path watched using inotify. The inotify watches of the rest of the watched path are kept,
+    /// and only `path` is polled instead if `--poll-on-watch-limit` is set. Returns `None` if
+    /// `path` isn't watched using inotify.
+    pub(crate) fn handle_watch_limit(
+        &mut self,
+        path: &Path,
+    ) -> anyhow::Result<Option<WatchStatus>> {
+        let watched_path = self
+            .registrations
+            .iter()
+            .filter(|(registered, registration)| {
+                path.starts_with(registered)
+                    && registration.backend == Some(WatcherBackend::Inotify)
+            })
+            .max_by_key(|(registered, _registration)| registered.components().count())
+            .map(|(registered, _registration)| registered.clone());
+        let Some(watched_path) = watched_path else {
+            return Ok(None);
+        };
+
+        log::warn!(
+            "reached the inotify watch limit while watching {path:?} under {watched_path:?}"
+        );
+        let polling = self.args.poll_on_watch_limit;
+        if polling {
+            self.poll(path)?;
+        }
+
+        let status = WatchStatus::LimitReached {
+            path: path.to_path_buf(),
+            polling,
+        };
+        let registration = self
+            .registrations
+            .get_mut(&watched_path)
+            .expect("watched path should be registered");
+        registration.status = status.clone();
+        if polling {
+            registration.polled_subtrees.push(path.to_path_buf());
+        }
+
+        Ok(Some(status))
+    }
+
+    /// Returns the closest registered ancestor of `path`.
+    fn ancestor_registration(&self, path: &Path) -> Option<&Registration> {
+        path.ancestors()
+            .skip(1)
+            .find_map(|ancestor| self.registrations.get(ancestor))
+    }
+
+    fn start_watching(
+        &mut self,
+        path: &Path,
+    ) -> anyhow::Result<(WatchStatus, Option<WatcherBackend>)> {
+        // Filesystem events are ignored when the torrent client reports completed torrents, so
+        // paths are only registered to route its updates
+        if self.args.event_source != EventSource::Filesystem {
+            log::debug!("not watching {path:?} since updates are reported by the torrent client");
+            return Ok((WatchStatus::Watched, None));
+        }
+
+        if self.backend(path) == WatcherBackend::Poll {
+            self.poll(path)?;
+            return Ok((WatchStatus::Watched, Some(WatcherBackend::Poll)));
+        }
+
+        log::info!("watching {path:?} using inotify");
+        let res = self.inotify.watch(path, RecursiveMode::Recursive);
+        self.inotify_watched(path, res)
+    }
+
+    /// Handles the result of watching `path` using inotify, falling back to polling if enabled
+    /// when the watch limit was reached.
+    fn inotify_watched(
+        &mut self,
+        path: &Path,
+        res: Result<(), Error>,
+    ) -> anyhow::Result<(WatchStatus, Option<WatcherBackend>)> {
+        match res {
+            Ok(()) => Ok((WatchStatus::Watched, Some(WatcherBackend::Inotify))),
+            Err(e) if is_watch_limit(&e) => self.watch_limit_reached(path),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn stop_watching(&mut self, path: &Path) {
+        let Some(registration) = self.registrations.get_mut(path) else {
+            return;
+        };
+
+        let res = match registration.backend.take() {
+            Some(WatcherBackend::Poll) => {
+                self.poll.as_mut().map_or(Ok(()), |poll| poll.unwatch(path))
+            }
+            Some(_) => self.inotify.unwatch(path),
+            None => Ok(()),
+        };
+
+        match res {
+            Ok(()) => log::info!("stopped watching {path:?}"),
+            Err(e) => log::error!("failed to stop watching {path:?}: {e:#}"),
+        }
+
+        for subtree in std::mem::take(&mut registration.polled_subtrees) {
+            let res = self
+                .poll
+                .as_mut()
+                .map_or(Ok(()), |poll| poll.unwatch(&subtree));
+            if let Err(e) = res {
+                log::error!("failed to stop polling {subtree:?}: {e:#}");
+            }
+        }
+    }
+
+    fn watch_limit_reached(
+        &mut self,
+        path: &Path,
+    ) -> anyhow::Result<(WatchStatus, Option<WatcherBackend>)> {
+        log::warn!("reached the inotify watch limit while watching {path:?}");
+
+        // Release the watches added before reaching the limit, so they can be used for other
+        // paths. Fails if the limit was reached before watching `path` itself.
+        let _ = self.inotify.unwatch(path);
+
+        let polling = self.args.poll_on_watch_limit;
+        if polling {
+            self.poll(path)?;
+        }
+
+        let status = WatchStatus::LimitReached {
+            path: path.to_path_buf(),
+            polling,
+        };
+        Ok((status, polling.then_some(WatcherBackend::Poll)))
+    }
+
+    fn poll(&mut self, path: &Path) -> anyhow::Result<()> {
+        log::info!(
+            "watching {path:?} by polling every {:?}",
+            self.args.poll_interval
+        );
+        let poll = match &mut self.poll {
+            Some(poll) => poll,
+            None => {
+                let config = watcher_config(&self.args).with_poll_interval(self.args.poll_interval);
+                let poll = PollWatcher::new(event_handler(self.tx.clone()), config)?;
+                self.poll.insert(poll)
+            }
+        };
+        poll.watch(path, RecursiveMode::Recursive)?;
+
+        Ok(())
+    }
+
+    fn backend(&self, path: &Path) -> WatcherBackend {
+        if self
+            .args
+            .poll_paths
+            .iter()
+            .any(|poll_path| path.starts_with(poll_path))
+        {
+            return WatcherBackend::Poll;
+        }
+
+        match self.args.watcher_backend {
+            WatcherBackend::Auto => match remote_filesystem(path) {
+                Ok(Some(fs_type)) => {
+                    log::info!("{path:?} is on a {fs_type} filesystem, falling back to polling");
+                    WatcherBackend::Poll
                 }
-            });
-        },
-        notify::Config::default(),
-    )?;
+                Ok(None) => WatcherBackend::Inotify,
+                Err(e) => {
+                    log::warn!("failed to determine filesystem type of {path:?}: {e:#}");
+                    WatcherBackend::Inotify
+                }
+            },
+            backend => backend,
+        }
+    }
+}
+
+/// Paths watched on behalf of a connection, which are unwatched when dropped.
+pub(crate) struct WatchGuard {
+    watchers: SharedWatchers,
+    paths: Vec<PathBuf>,
+}
+
+impl WatchGuard {
+    pub(crate) fn new(watchers: SharedWatchers) -> Self {
+        Self {
+            watchers,
+            paths: Vec::new(),
+        }
+    }
+
+    pub(crate) fn watch(&mut self, path: &Path) -> anyhow::Result<WatchStatus> {
+        let status = self
+            .watchers
+            .lock()
+            .expect("watchers lock should not be poisoned")
+            .watch(path)?;
+        self.paths.push(path.to_path_buf());
+
+        Ok(status)
+    }
+
+    /// Stops watching `path` on behalf of the connection.
+    pub(crate) fn unwatch(&mut self, path: &Path) {
+        let Some(index) = self
+            .paths
+            .iter()
+            .position(|watched_path| watched_path == path)
+        else {
+            return;
+        };
+
+        self.paths.swap_remove(index);
+        self.watchers
+            .lock()
+            .expect("watchers lock should not be poisoned")
+            .unwatch(path);
+    }
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        let mut watchers = self
+            .watchers
+            .lock()
+            .expect("watchers lock should not be poisoned");
+        for path in &self.paths {
+            watchers.unwatch(path);
+        }
+    }
+}
+
+/// Returns the name of the filesystem `path` is on if inotify can't be relied on for it.
+fn remote_filesystem(path: &Path) -> anyhow::Result<Option<&'static str>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("path contains a nul byte: {path:?}"))?;
+
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `c_path` is a valid nul-terminated string and `stat` is only read if statfs
+    // succeeded, in which case it has been initialized.
+    let stat = unsafe {
+        if libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        stat.assume_init()
+    };
+
+    // The width and signedness of `f_type` differ between targets, but the magic numbers all fit
+    // in 32 bits
+    let fs_type = stat.f_type as u32;
+
+    Ok(REMOTE_FILESYSTEMS
+        .iter()
+        .find(|(_name, magic)| *magic == fs_type)
+        .map(|(name, _magic)| *name))
+}
+
+/// Returns true if `e` was caused by reaching the inotify watch limit, which inotify reports as
+/// ENOSPC. notify only translates it for the watches it adds itself.
+pub(crate) fn is_watch_limit(e: &Error) -> bool {
+    match &e.kind {
+        ErrorKind::MaxFilesWatch => true,
+        ErrorKind::Io(e) => e.raw_os_error() == Some(libc::ENOSPC),
+        _ => false,
+    }
+}
+
+/// Returns the configuration of the watchers, which don't follow symlinks whenever the allowed
+/// roots may be restricted, as described in [`Args::allowed_roots`].
+fn watcher_config(args: &Args) -> notify::Config {
+    let may_restrict_roots = !args.allowed_roots.is_empty() || args.config.is_some();
+    notify::Config::default().with_follow_symlinks(!may_restrict_roots)
+}
+
+/// Sends the events to the receiver in the order they're reported, without blocking the thread of
+/// the watcher, since removals have to be applied after the updates preceding them. Events are
+/// dropped if too many are waiting, and the paths under them are then synced again.
+fn event_handler(tx: NotifyEventSender) -> impl notify::EventHandler {
+    move |res| tx.send(res)
+}
+
+pub(crate) async fn create_watcher(
+    args: Args,
+) -> anyhow::Result<(SharedWatchers, NotifyEventReceiver)> {
+    let (tx, rx) = notify_channel(EVENT_CHANNEL_CAPACITY);
+
+    let inotify = INotifyWatcher::new(event_handler(tx.clone()), watcher_config(&args))?;
+    let watchers = Watchers {
+        args,
+        tx,
+        inotify,
+        poll: None,
+        registrations: HashMap::new(),
+    };
+
+    Ok((Arc::new(Mutex::new(watchers)), rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use seedmirror_core::message::{ErrorCode, Message};
+
+    use crate::subscription::watch_status_error;
+
+    use super::*;
+
+    fn watchers(args: &[&str]) -> Watchers {
+        let args = Args::parse_from(["seedmirror-server"].iter().chain(args));
+        let (tx, _rx) = notify_channel(EVENT_CHANNEL_CAPACITY);
+        let inotify =
+            INotifyWatcher::new(event_handler(tx.clone()), watcher_config(&args)).unwrap();
+        Watchers {
+            args,
+            tx,
+            inotify,
+            poll: None,
+            registrations: HashMap::new(),
+        }
+    }
+
+    fn enospc() -> Error {
+        Error::io(io::Error::from_raw_os_error(libc::ENOSPC))
+    }
+
+    #[test]
+    fn test_is_watch_limit() {
+        assert!(is_watch_limit(&Error::new(ErrorKind::MaxFilesWatch)));
+        assert!(is_watch_limit(&enospc()));
+        assert!(!is_watch_limit(&Error::io(io::Error::from_raw_os_error(
+            libc::EACCES
+        ))));
+        assert!(!is_watch_limit(&Error::new(ErrorKind::PathNotFound)));
+    }
+
+    #[test]
+    fn test_watch_limit_reported() {
+        let path = std::env::temp_dir();
+        let mut watchers = watchers(&[]);
+        let (status, backend) = watchers.inotify_watched(&path, Err(enospc())).unwrap();
+        assert!(matches!(
+            status,
+            WatchStatus::LimitReached { polling: false, .. }
+        ));
+        assert_eq!(backend, None);
+        assert!(matches!(
+            watch_status_error(status),
+            Some(Message::Error {
+                code: ErrorCode::WatchLimitReached,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_watch_limit_polling() {
+        let path = std::env::temp_dir();
+        let mut watchers = watchers(&["--poll-on-watch-limit"]);
+        let (status, backend) = watchers.inotify_watched(&path, Err(enospc())).unwrap();
+        assert!(matches!(
+            status,
+            WatchStatus::LimitReached { polling: true, .. }
+        ));
+        assert_eq!(backend, Some(WatcherBackend::Poll));
+        assert!(watchers.poll.is_some());
+    }
+
+    #[test]
+    fn test_watch_limit_under_watched_path() {
+        for poll_on_watch_limit in [false, true] {
+            let root = std::env::temp_dir().join(format!(
+                "seedmirror-watcher-test-{}-{poll_on_watch_limit}",
+                std::process::id()
+            ));
+            let subdir = root.join("subdir");
+            std::fs::create_dir_all(&subdir).unwrap();
+
+            let mut args = vec!["--watcher-backend", "inotify"];
+            if poll_on_watch_limit {
+                args.push("--poll-on-watch-limit");
+            }
+            let mut watchers = watchers(&args);
+            watchers.watch(&root).unwrap();
+
+            // The rest of the watched path stays watched using inotify
+            let status = watchers.handle_watch_limit(&subdir).unwrap();
+            assert!(matches!(
+                status,
+                Some(WatchStatus::LimitReached { path, polling })
+                    if path == subdir && polling == poll_on_watch_limit
+            ));
+            let registration = &watchers.registrations[&root];
+            assert_eq!(registration.backend, Some(WatcherBackend::Inotify));
+            assert_eq!(
+                registration.polled_subtrees.len(),
+                usize::from(poll_on_watch_limit)
+            );
+            assert_eq!(watchers.poll.is_some(), poll_on_watch_limit);
+
+            watchers.unwatch(&root);
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notify_channel_overflow() {
+        let (tx, mut rx) = notify_channel(1);
+        let event = |path: &str| Event::default().add_path(PathBuf::from(path));
+        tx.send(Ok(event("/watched/a")));
+        tx.send(Ok(event("/watched/b")));
+        tx.send(Ok(event("/watched/c")));
+        tx.send(Ok(Event::default()));
+
+        let mut received = [rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+        received.sort_by_key(Result::is_err);
+        assert_eq!(
+            received[0].as_ref().unwrap().paths,
+            [PathBuf::from("/watched/a")]
+        );
+        // Events without paths may concern any watched path
+        assert_eq!(
+            received[1].as_ref().unwrap_err().paths,
+            ["/", "/watched/b", "/watched/c"].map(PathBuf::from)
+        );
+
+        // Further events are received once there's room for them
+        tx.send(Ok(event("/watched/d")));
+        assert_eq!(
+            rx.recv().await.unwrap().unwrap().paths,
+            [PathBuf::from("/watched/d")]
+        );
+    }
 
-    Ok((watcher, rx))
+    #[test]
+    fn test_torrent_event_source_unwatched() {
+        let path = std::env::temp_dir();
+        let mut watchers = watchers(&[
+            "--event-source",
+            "qbittorrent",
+            "--torrent-client-url",
+            "http://127.0.0.1:8080",
+        ]);
+        assert!(matches!(
+            watchers.watch(&path).unwrap(),
+            WatchStatus::Watched
+        ));
+        assert_eq!(watchers.registrations[&path].backend, None);
+        assert_eq!(
+            watchers.watched_root(&path.join("file")),
+            Some(path.clone())
+        );
+        assert_eq!(watchers.watched_root_count(), 1);
+        watchers.unwatch(&path);
+    }
 }
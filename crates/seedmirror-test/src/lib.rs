@@ -1,2 +1,3 @@
+pub mod message;
 pub mod path;
 pub mod process;
@@ -0,0 +1,115 @@
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use seedmirror_core::{
+    codec::MessageCodec,
+    message::{Capability, Message, PROTOCOL_VERSION},
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Blocking connection to a server speaking the seedmirror protocol.
+pub struct Connection<S> {
+    stream: S,
+    codec: MessageCodec,
+    buf: BytesMut,
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            codec: MessageCodec::default(),
+            buf: BytesMut::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        let mut frame = BytesMut::new();
+        self.codec.encode(msg, &mut frame)?;
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the next message that isn't a `Ping`.
+    pub fn receive(&mut self) -> anyhow::Result<Message> {
+        loop {
+            match self.codec.decode(&mut self.buf)? {
+                Some(Message::Ping) => continue,
+                Some(msg) => return Ok(msg),
+                None => (),
+            }
+
+            let mut chunk = [0; 4096];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                anyhow::bail!("connection closed by server");
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Receives the next message, expecting it to be an update of `expected_path`.
+    pub fn expect_update(&mut self, expected_path: &Path) -> anyhow::Result<()> {
+        match self.receive()? {
+            Message::FileUpdated { path, .. } => assert_eq!(path, expected_path),
+            msg => anyhow::bail!("expected update of {expected_path:?}, got {msg:?}"),
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the request of a client supporting every capability, watching `watched_paths` without
+/// filters.
+pub fn connection_request(watched_paths: &[&Path]) -> Message {
+    Message::ConnectionRequest {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capability::supported(),
+        watched_paths: watched_paths
+            .iter()
+            .map(|path| path.to_path_buf())
+            .collect(),
+        filters: Vec::new(),
+        token: None,
+        last_seq: None,
+        journal_id: None,
+    }
+}
+
+/// Connects to the server listening on `socket_path` and watches `watched_paths`. Receiving times
+/// out after 10 seconds.
+pub fn connect(
+    socket_path: &Path,
+    watched_paths: &[&Path],
+) -> anyhow::Result<Connection<UnixStream>> {
+    let (connection, _connected) = connect_with(socket_path, connection_request(watched_paths))?;
+    Ok(connection)
+}
+
+/// Connects to the server listening on `socket_path` and sends `request`, returning the
+/// connection along with the `Connected` message accepting it.
+pub fn connect_with(
+    socket_path: &Path,
+    request: Message,
+) -> anyhow::Result<(Connection<UnixStream>, Message)> {
+    let stream = UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut connection = Connection::new(stream);
+
+    connection.send(request)?;
+    match connection.receive()? {
+        connected @ Message::Connected { .. } => Ok((connection, connected)),
+        msg => anyhow::bail!("expected connection to be accepted, got {msg:?}"),
+    }
+}
@@ -1,4 +1,7 @@
-use std::{fs, path::Path};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 
@@ -53,3 +56,26 @@ pub fn assert_dst_contains_src(src: &Path, dst: &Path) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Empty directory that is removed when dropped.
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> anyhow::Result<Self> {
+        let path = env::temp_dir().join(format!("seedmirror-test-{name}"));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -1,4 +1,14 @@
-use std::process::{Child, Command};
+use std::{
+    ffi::OsStr,
+    net::TcpListener,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
 
 pub struct ProcessGuard {
     child: Child,
@@ -9,6 +19,19 @@ impl ProcessGuard {
         let child = cmd.spawn()?;
         Ok(Self { child })
     }
+
+    /// Sends the signal, e.g. `HUP`, to the process.
+    pub fn signal(&self, signal: &str) -> anyhow::Result<()> {
+        let status = Command::new("kill")
+            .args(["-s", signal, &self.child.id().to_string()])
+            .status()?;
+        anyhow::ensure!(
+            status.success(),
+            "failed to send SIG{signal} to the process"
+        );
+
+        Ok(())
+    }
 }
 
 impl Drop for ProcessGuard {
@@ -19,3 +42,48 @@ impl Drop for ProcessGuard {
             .status();
     }
 }
+
+/// Builds the workspace and spawns a server listening on `socket_path`, returning once it accepts
+/// connections.
+pub fn spawn_server(socket_path: &Path, args: &[&OsStr]) -> anyhow::Result<ProcessGuard> {
+    let workspace_dir = build_workspace()?;
+
+    let mut cmd = Command::new("target/debug/seedmirror-server");
+    cmd.current_dir(&workspace_dir)
+        .arg("--socket-path")
+        .arg(socket_path);
+    let server = ProcessGuard::spawn(cmd.args(args))?;
+
+    // Wait for the server to start listening
+    for _ in 0..100 {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Ok(server);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    anyhow::bail!("server didn't start listening on {socket_path:?}")
+}
+
+/// Returns a TCP port on localhost that isn't in use.
+pub fn free_port() -> anyhow::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Builds the workspace, returning its directory.
+pub fn build_workspace() -> anyhow::Result<PathBuf> {
+    let workspace_dir = workspace_dir()?;
+    let _ = Command::new("cargo")
+        .current_dir(&workspace_dir)
+        .arg("build")
+        .status()?;
+
+    Ok(workspace_dir)
+}
+
+pub fn workspace_dir() -> anyhow::Result<PathBuf> {
+    Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../.."))
+        .canonicalize()
+        .context("failed to find workspace directory")
+}
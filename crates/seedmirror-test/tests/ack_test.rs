@@ -0,0 +1,126 @@
+use std::{fs, time::Duration};
+
+use seedmirror_core::message::Message;
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_redelivery_until_acknowledged() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("ack_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--ack-timeout".as_ref(),
+            "500".as_ref(),
+            "--nack-retry-delay".as_ref(),
+            "200".as_ref(),
+        ],
+    )?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    let file_path = watched_path.join("new_file.txt");
+    fs::write(&file_path, "")?;
+    connection.expect_update(&file_path)?;
+
+    // Delivered again since it wasn't acknowledged in time
+    connection.expect_update(&file_path)?;
+
+    // Delivered again since it couldn't be synced
+    connection.send(Message::Nack {
+        path: file_path.clone(),
+        seq: None,
+        reason: "rsync failed".to_string(),
+    })?;
+    connection.expect_update(&file_path)?;
+
+    connection.send(Message::Ack {
+        path: file_path.clone(),
+        seq: None,
+    })?;
+    connection
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(2)))?;
+    assert!(
+        connection.receive().is_err(),
+        "expected acknowledged update not to be delivered again"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_removal_clears_pending_delivery() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("ack_test_removal")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--ack-timeout".as_ref(),
+            "1000".as_ref(),
+        ],
+    )?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    let file_path = watched_path.join("removed.txt");
+    fs::write(&file_path, "")?;
+    connection.expect_update(&file_path)?;
+
+    fs::remove_file(&file_path)?;
+    match connection.receive()? {
+        Message::FileRemoved { path, .. } => assert_eq!(path, file_path),
+        msg => anyhow::bail!("expected removal of {file_path:?}, got {msg:?}"),
+    }
+
+    connection
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(3)))?;
+    assert!(
+        connection.receive().is_err(),
+        "expected update of removed file not to be delivered again"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_redelivery_gives_up() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("ack_test_gives_up")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--ack-timeout".as_ref(),
+            "300".as_ref(),
+            "--max-delivery-attempts".as_ref(),
+            "2".as_ref(),
+        ],
+    )?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    let file_path = watched_path.join("new_file.txt");
+    fs::write(&file_path, "")?;
+    connection.expect_update(&file_path)?;
+    connection.expect_update(&file_path)?;
+
+    connection
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(2)))?;
+    assert!(
+        connection.receive().is_err(),
+        "expected update not to be delivered more than twice"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::{fs, os::unix::fs::symlink, os::unix::net::UnixStream, thread, time::Duration};
+
+use seedmirror_core::message::{ErrorCode, Message};
+use seedmirror_test::{
+    message::{Connection, connect, connection_request},
+    path::TempDir,
+    process::spawn_server,
+};
+
+#[test]
+fn test_allowed_roots() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("allowed_roots_test")?;
+    let allowed_path = test_dir.path.join("allowed");
+    let watched_path = allowed_path.join("watched");
+    let outside_path = test_dir.path.join("outside");
+    fs::create_dir_all(&watched_path)?;
+    fs::create_dir(&outside_path)?;
+    symlink(&watched_path, test_dir.path.join("link_in"))?;
+    symlink(&outside_path, allowed_path.join("link_out"))?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--allowed-root".as_ref(),
+            allowed_path.as_os_str(),
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+        ],
+    )?;
+
+    // Paths are checked once resolved, so that symlinks can't escape the allowed roots
+    for path in [outside_path, allowed_path.join("link_out")] {
+        let stream = UnixStream::connect(&socket_path)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut connection = Connection::new(stream);
+        connection.send(connection_request(&[&path]))?;
+        match connection.receive()? {
+            Message::Error {
+                code: ErrorCode::PathNotAllowed,
+                ..
+            } => (),
+            msg => anyhow::bail!("expected {path:?} to be refused, got {msg:?}"),
+        }
+    }
+
+    // Paths resolving to allowed paths are watched, and updates are reported under the requested
+    // paths
+    let link_in = test_dir.path.join("link_in");
+    let dotdot = allowed_path.join("watched/../watched");
+    let mut connections = [
+        (connect(&socket_path, &[&watched_path])?, &watched_path),
+        (connect(&socket_path, &[&link_in])?, &link_in),
+        (connect(&socket_path, &[&dotdot])?, &dotdot),
+    ];
+    fs::write(watched_path.join("file.txt"), "")?;
+    for (connection, path) in &mut connections {
+        connection.expect_update(&path.join("file.txt"))?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_reloaded_allowed_roots() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("allowed_roots_test_reloaded")?;
+    let allowed_path = test_dir.path.join("allowed");
+    let watched_path = allowed_path.join("watched");
+    let outside_path = test_dir.path.join("outside");
+    fs::create_dir_all(&watched_path)?;
+    fs::create_dir(&outside_path)?;
+    symlink(&outside_path, watched_path.join("link_out"))?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let config_path = test_dir.path.join("seedmirror-server.toml");
+    fs::write(&config_path, "")?;
+    let server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--config".as_ref(),
+            config_path.as_os_str(),
+        ],
+    )?;
+
+    fs::write(
+        &config_path,
+        format!("allowed-roots = [{:?}]\n", allowed_path.to_str().unwrap()),
+    )?;
+    server.signal("HUP")?;
+    thread::sleep(Duration::from_millis(500));
+
+    // Symlinks to paths outside of the allowed roots reloaded after the server started aren't
+    // followed either
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+    fs::write(outside_path.join("outside.txt"), "")?;
+    thread::sleep(Duration::from_millis(500));
+    fs::write(watched_path.join("inside.txt"), "")?;
+    connection.expect_update(&watched_path.join("inside.txt"))?;
+
+    Ok(())
+}
@@ -0,0 +1,610 @@
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use seedmirror_core::{
+    filter::FilterRules,
+    message::{Capability, Message, PROTOCOL_VERSION},
+};
+use seedmirror_test::{
+    message::Connection,
+    path::TempDir,
+    process::{ProcessGuard, build_workspace, spawn_server},
+};
+
+#[test]
+fn test_rename_without_delete() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_rename")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir_all(remote_path.join("old_dir"))?;
+    fs::create_dir_all(local_path.join("old_dir"))?;
+    fs::write(remote_path.join("old.txt"), "")?;
+    fs::write(local_path.join("old.txt"), "")?;
+    fs::write(remote_path.join("old_dir/file.txt"), "")?;
+    fs::write(local_path.join("old_dir/file.txt"), "")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "100".as_ref()])?;
+    let rsync_log = test_dir.path.join("rsync.log");
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}", remote_path.display(), local_path.display()),
+    )?;
+    // Wait until the client is connected
+    fs::write(remote_path.join("new.txt"), "")?;
+    wait_for_log(
+        &rsync_log,
+        &remote_path.join("new.txt").display().to_string(),
+    )?;
+
+    // Renamed files and directories are linked locally, keeping the previous local paths since
+    // deletion is disabled
+    fs::rename(remote_path.join("old.txt"), remote_path.join("renamed.txt"))?;
+    fs::rename(remote_path.join("old_dir"), remote_path.join("renamed_dir"))?;
+    wait_for(|| {
+        local_path.join("renamed.txt").exists() && local_path.join("renamed_dir/file.txt").exists()
+    })?;
+    assert!(local_path.join("old.txt").exists());
+    assert!(local_path.join("old_dir/file.txt").exists());
+
+    // They're synced again afterwards, which only transfers changes since the linked files are
+    // used as the basis
+    wait_for_log(
+        &rsync_log,
+        &remote_path.join("renamed_dir/").display().to_string(),
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_after_write() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_rename_after_write")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir_all(remote_path.join("incomplete"))?;
+    fs::create_dir_all(remote_path.join("complete"))?;
+    fs::create_dir_all(local_path.join("incomplete"))?;
+    fs::write(remote_path.join("incomplete/file.txt"), "partial")?;
+    fs::write(local_path.join("incomplete/file.txt"), "partial")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "500".as_ref()])?;
+    let rsync_log = test_dir.path.join("rsync.log");
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}:delete", remote_path.display(), local_path.display()),
+    )?;
+    // Stand-in for rsync copying the remote file, replacing the local one like rsync does
+    write_script(
+        &test_dir.path.join("bin/rsync"),
+        &format!(
+            "echo \"$@\" >> {}\ncp \"${{5#*:}}\" \"$6.tmp\" && mv \"$6.tmp\" \"$6\"",
+            rsync_log.display()
+        ),
+    )?;
+    // Wait until the client is connected
+    fs::write(remote_path.join("new.txt"), "")?;
+    wait_for_log(
+        &rsync_log,
+        &remote_path.join("new.txt").display().to_string(),
+    )?;
+
+    // The file is completed then moved before its update is reported, like torrent clients moving
+    // completed downloads
+    fs::write(remote_path.join("incomplete/file.txt"), "complete")?;
+    fs::rename(
+        remote_path.join("incomplete/file.txt"),
+        remote_path.join("complete/file.txt"),
+    )?;
+    wait_for(|| {
+        fs::read_to_string(local_path.join("complete/file.txt"))
+            .is_ok_and(|content| content == "complete")
+    })?;
+    assert!(!local_path.join("incomplete/file.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_missing_local_source() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_rename_missing")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&local_path)?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}", remote_path.display(), local_path.display()),
+    )?;
+    let mut connection = accept_client(&listener, vec![Capability::FileRenamed])?;
+
+    // There's nothing to link, so the renamed file is transferred instead
+    connection.send(Message::FileRenamed {
+        from: remote_path.join("missing.txt"),
+        to: remote_path.join("renamed.txt"),
+        seq: None,
+    })?;
+    wait_for_log(
+        &test_dir.path.join("rsync.log"),
+        &remote_path.join("renamed.txt").display().to_string(),
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_across_destinations() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_rename_across")?;
+    let (remote_a, local_a) = (
+        test_dir.path.join("remote_a"),
+        test_dir.path.join("local_a"),
+    );
+    let (remote_b, local_b) = (
+        test_dir.path.join("remote_b"),
+        test_dir.path.join("local_b"),
+    );
+    fs::create_dir(&local_a)?;
+    fs::create_dir(&local_b)?;
+    fs::write(local_a.join("moved.txt"), "")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let mapping_b = format!("{}:{}", remote_b.display(), local_b.display());
+    let _client = spawn_client_with(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}:delete", remote_a.display(), local_a.display()),
+        &["--path-mapping".as_ref(), mapping_b.as_ref()],
+    )?;
+    let mut connection = accept_client(&listener, vec![Capability::FileRenamed])?;
+
+    // The file can't be renamed between destinations, so it's removed from the previous one and
+    // transferred to the new one
+    connection.send(Message::FileRenamed {
+        from: remote_a.join("moved.txt"),
+        to: remote_b.join("moved.txt"),
+        seq: None,
+    })?;
+    wait_for_log(
+        &test_dir.path.join("rsync.log"),
+        &remote_b.join("moved.txt").display().to_string(),
+    )?;
+    assert!(!local_a.join("moved.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_delete_only_on_resync() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_delete_resync")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&local_path)?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let rsync_log = test_dir.path.join("rsync.log");
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}:delete", remote_path.display(), local_path.display()),
+    )?;
+    let mut connection = accept_client(&listener, vec![Capability::Resync])?;
+
+    // Files already in the destination are kept by the initial sync
+    wait_for_log(&rsync_log, &remote_path.display().to_string())?;
+    assert!(!fs::read_to_string(&rsync_log)?.contains("--delete"));
+
+    // Removals may have been missed when the server asks for a resync
+    connection.send(Message::ResyncRequired {
+        paths: vec![remote_path.clone()],
+    })?;
+    wait_for_log(&rsync_log, "--delete")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_removed_watched_path() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_removed_root")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&remote_path)?;
+    fs::create_dir(&local_path)?;
+    fs::write(remote_path.join("removed.txt"), "")?;
+    fs::write(local_path.join("removed.txt"), "")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "100".as_ref()])?;
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}:delete", remote_path.display(), local_path.display()),
+    )?;
+    fs::remove_file(remote_path.join("removed.txt"))?;
+    wait_for(|| !local_path.join("removed.txt").exists())?;
+
+    // Removing the watched path doesn't remove the local destination
+    fs::remove_dir(&remote_path)?;
+    thread::sleep(Duration::from_secs(1));
+    assert!(local_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_removal_outside_destination() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_removal_outside")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&local_path)?;
+    fs::write(local_path.join("removed.txt"), "")?;
+    let outside_path = test_dir.path.join("outside.txt");
+    fs::write(&outside_path, "")?;
+
+    // Stand-in for a server sending paths escaping the watched path
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}:delete", remote_path.display(), local_path.display()),
+    )?;
+
+    let mut connection = accept_client(&listener, vec![Capability::FileRemoved])?;
+    for path in [
+        remote_path.join("../outside.txt"),
+        remote_path.join("removed.txt"),
+    ] {
+        connection.send(Message::FileRemoved { path, seq: None })?;
+    }
+
+    // Removals are applied in order
+    wait_for(|| !local_path.join("removed.txt").exists())?;
+    assert!(outside_path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_outside_destination() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_rename_outside")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&local_path)?;
+    fs::write(local_path.join("inside.txt"), "")?;
+    let outside_path = test_dir.path.join("outside.txt");
+    fs::write(&outside_path, "")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}:delete", remote_path.display(), local_path.display()),
+    )?;
+    let mut connection = accept_client(&listener, vec![Capability::FileRenamed])?;
+
+    // Neither end of a rename may be moved from or to outside of the local destination
+    for (from, to) in [
+        (
+            remote_path.join("inside.txt"),
+            remote_path.join("../moved.txt"),
+        ),
+        (
+            remote_path.join("../outside.txt"),
+            remote_path.join("stolen.txt"),
+        ),
+    ] {
+        connection.send(Message::FileRenamed {
+            from,
+            to,
+            seq: None,
+        })?;
+    }
+
+    // The previous path is removed and the renamed file is transferred instead
+    wait_for_log(
+        &test_dir.path.join("rsync.log"),
+        &remote_path.join("stolen.txt").display().to_string(),
+    )?;
+    wait_for(|| !local_path.join("inside.txt").exists())?;
+    assert!(outside_path.exists());
+    assert!(!test_dir.path.join("moved.txt").exists());
+    assert!(!local_path.join("stolen.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_duplicate_update_advances_seq() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_duplicate_update")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&local_path)?;
+    let state_file = test_dir.path.join("state");
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let _client = spawn_client_with(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}", remote_path.display(), local_path.display()),
+        &["--state-file".as_ref(), state_file.as_os_str()],
+    )?;
+    // Keep the first sync running while the second update arrives
+    write_script(
+        &test_dir.path.join("bin/rsync"),
+        &format!(
+            "sleep 1\necho \"$@\" >> {}",
+            test_dir.path.join("rsync.log").display()
+        ),
+    )?;
+    let mut connection = accept_client(&listener, Vec::new())?;
+
+    // The second update is dropped since the first sync of the path is still queued or running
+    let path = remote_path.join("file.txt");
+    for seq in [1, 2] {
+        connection.send(Message::FileUpdated {
+            path: path.clone(),
+            seq: Some(seq),
+        })?;
+    }
+    wait_for(|| fs::read_to_string(&state_file).is_ok_and(|state| state.trim() == "2"))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_merged_updates_acknowledged() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_merged_ack")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&local_path)?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}", remote_path.display(), local_path.display()),
+    )?;
+    // Keep the first sync running while the following updates are queued
+    write_script(&test_dir.path.join("bin/rsync"), "sleep 0.5")?;
+    let mut connection = accept_client(&listener, vec![Capability::Ack])?;
+
+    // The second update of the path is merged into the queued sync of the first one. The server
+    // only waits for an acknowledgement of the latest update of a path, which would otherwise be
+    // delivered again.
+    let path = remote_path.join("file.txt");
+    for (path, seq) in [
+        (remote_path.join("other.txt"), 1),
+        (path.clone(), 2),
+        (path.clone(), 3),
+    ] {
+        connection.send(Message::FileUpdated {
+            path,
+            seq: Some(seq),
+        })?;
+    }
+
+    let mut acked = Vec::new();
+    while acked.len() < 3 {
+        match connection.receive()? {
+            Message::Ack { path, seq } => acked.push((path, seq)),
+            msg => anyhow::bail!("expected acknowledgement, got {msg:?}"),
+        }
+    }
+    assert!(acked.contains(&(path.clone(), Some(2))));
+    assert!(acked.contains(&(path, Some(3))));
+
+    Ok(())
+}
+
+#[test]
+fn test_update_after_removal() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_update_after_removal")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&local_path)?;
+    fs::write(local_path.join("file.txt"), "")?;
+    let state_file = test_dir.path.join("state");
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    let _client = spawn_client_with(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}:delete", remote_path.display(), local_path.display()),
+        &["--state-file".as_ref(), state_file.as_os_str()],
+    )?;
+    // Syncs take long enough for the following updates to be queued while the first one runs,
+    // and create the local file as a transfer would
+    write_script(
+        &test_dir.path.join("bin/rsync"),
+        &format!(
+            "sleep 0.5\ncase \"$*\" in *file.txt*) touch {};; esac",
+            local_path.join("file.txt").display()
+        ),
+    )?;
+    let mut connection = accept_client(&listener, vec![Capability::FileRemoved])?;
+
+    // The update following the removal isn't merged into the sync queued before it, which is
+    // undone by the removal
+    let path = remote_path.join("file.txt");
+    connection.send(Message::FileUpdated {
+        path: remote_path.join("other.txt"),
+        seq: Some(1),
+    })?;
+    connection.send(Message::FileUpdated {
+        path: path.clone(),
+        seq: Some(2),
+    })?;
+    connection.send(Message::FileRemoved {
+        path: path.clone(),
+        seq: Some(3),
+    })?;
+    connection.send(Message::FileUpdated {
+        path: path.clone(),
+        seq: Some(4),
+    })?;
+    wait_for(|| fs::read_to_string(&state_file).is_ok_and(|state| state.trim() == "4"))?;
+    assert!(local_path.join("file.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_non_utf8_path() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("client_test_non_utf8")?;
+    let remote_path = test_dir.path.join("remote");
+    let local_path = test_dir.path.join("local");
+    fs::create_dir(&remote_path)?;
+    fs::create_dir(&local_path)?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "100".as_ref()])?;
+    let rsync_log = test_dir.path.join("rsync.log");
+    let _client = spawn_client(
+        &test_dir.path,
+        &socket_path,
+        &format!("{}:{}", remote_path.display(), local_path.display()),
+    )?;
+    // Wait until the client is connected
+    fs::write(remote_path.join("new.txt"), "")?;
+    wait_for_log(
+        &rsync_log,
+        &remote_path.join("new.txt").display().to_string(),
+    )?;
+
+    // The path is received and passed to rsync byte for byte
+    let path = remote_path.join(OsStr::from_bytes(b"caf\xe9"));
+    fs::write(&path, "")?;
+    let path = path.as_os_str().as_bytes();
+    wait_for(|| fs::read(&rsync_log).is_ok_and(|log| log.windows(path.len()).any(|w| w == path)))?;
+
+    Ok(())
+}
+
+/// Accepts a connection from a client on behalf of a server supporting `capabilities`.
+fn accept_client(
+    listener: &UnixListener,
+    capabilities: Vec<Capability>,
+) -> anyhow::Result<Connection<UnixStream>> {
+    let (stream, _addr) = listener.accept()?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut connection = Connection::new(stream);
+    match connection.receive()? {
+        Message::ConnectionRequest { .. } => (),
+        msg => anyhow::bail!("expected connection request, got {msg:?}"),
+    }
+    connection.send(Message::Connected {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities,
+        filters: FilterRules::default(),
+        replaying: false,
+        journal_id: None,
+    })?;
+
+    Ok(connection)
+}
+
+/// Spawns a client syncing `path_mapping` from the server listening on `socket_path`. ssh and
+/// rsync are replaced by stand-ins in `test_dir`: ssh forwards the socket with a symlink, and
+/// rsync only appends its arguments to `rsync.log`.
+fn spawn_client(
+    test_dir: &Path,
+    socket_path: &Path,
+    path_mapping: &str,
+) -> anyhow::Result<ProcessGuard> {
+    spawn_client_with(test_dir, socket_path, path_mapping, &[])
+}
+
+/// Same as [`spawn_client`], passing `extra_args` to the client.
+fn spawn_client_with(
+    test_dir: &Path,
+    socket_path: &Path,
+    path_mapping: &str,
+    extra_args: &[&OsStr],
+) -> anyhow::Result<ProcessGuard> {
+    let bin_path = test_dir.join("bin");
+    fs::create_dir(&bin_path)?;
+    // The forwarding is the last argument, as `<local socket>:<remote socket>`
+    write_script(
+        &bin_path.join("ssh"),
+        "for arg; do forward=$arg; done\nln -s \"${forward#*:}\" \"${forward%%:*}\"\nexec sleep 60",
+    )?;
+    write_script(
+        &bin_path.join("rsync"),
+        &format!("echo \"$@\" >> {}", test_dir.join("rsync.log").display()),
+    )?;
+
+    let path = env::join_paths(
+        [bin_path]
+            .into_iter()
+            .chain(env::split_paths(&env::var_os("PATH").unwrap_or_default())),
+    )?;
+    let local_socket_path = test_dir.join("forwarded.sock");
+    let args: [&OsStr; 8] = [
+        "--ssh-hostname".as_ref(),
+        "localhost".as_ref(),
+        "--socket-path".as_ref(),
+        socket_path.as_os_str(),
+        "--local-socket-path".as_ref(),
+        local_socket_path.as_os_str(),
+        "--path-mapping".as_ref(),
+        path_mapping.as_ref(),
+    ];
+    ProcessGuard::spawn(
+        Command::new(build_workspace()?.join("target/debug/seedmirror-client"))
+            .env("PATH", path)
+            .args(args)
+            .args(extra_args),
+    )
+}
+
+fn write_script(path: &PathBuf, body: &str) -> anyhow::Result<()> {
+    fs::write(path, format!("#!/bin/sh\n{body}\n"))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+
+    Ok(())
+}
+
+/// Waits until a line of the log contains `expected`.
+fn wait_for_log(log_path: &Path, expected: &str) -> anyhow::Result<()> {
+    wait_for(|| {
+        fs::read_to_string(log_path)
+            .is_ok_and(|log| log.lines().any(|line| line.contains(expected)))
+    })
+}
+
+/// Calls `f` until it returns true, for up to 10 seconds.
+fn wait_for(mut f: impl FnMut() -> bool) -> anyhow::Result<()> {
+    let started = Instant::now();
+    while !f() {
+        if started.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("timed out waiting for the client");
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Ok(())
+}
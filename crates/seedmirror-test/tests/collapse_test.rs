@@ -0,0 +1,40 @@
+use std::{
+    fs::{self, Permissions},
+    os::unix::fs::PermissionsExt,
+    time::Duration,
+};
+
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_collapse_under_pending_directory() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("collapse_test")?;
+    let watched_path = test_dir.path.join("watched");
+    let dir_path = watched_path.join("torrent");
+    fs::create_dir_all(dir_path.join("extras"))?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+
+    // Long enough for every file to be written while the directory update is pending
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "2000".as_ref()])?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    // The directories are already watched, so changing the directory's permissions makes its
+    // update pending before any file is written
+    fs::set_permissions(&dir_path, Permissions::from_mode(0o700))?;
+    for i in 0..20 {
+        fs::write(dir_path.join(format!("{i}.txt")), "")?;
+        fs::write(dir_path.join("extras").join(format!("{i}.txt")), "")?;
+    }
+
+    connection.expect_update(&dir_path.join(""))?;
+
+    // The files are synced along with the directory
+    connection
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(3)))?;
+    if let Ok(msg) = connection.receive() {
+        anyhow::bail!("expected a single directory update, got {msg:?}");
+    }
+
+    Ok(())
+}
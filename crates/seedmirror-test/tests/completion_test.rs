@@ -0,0 +1,58 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    thread,
+    time::{Duration, Instant},
+};
+
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_stable_completion() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("completion_test_stable")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--completion".as_ref(),
+            "stable".as_ref(),
+            "--stability-check-interval".as_ref(),
+            "100".as_ref(),
+            "--stability-window".as_ref(),
+            "1000".as_ref(),
+        ],
+    )?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    // The file keeps growing for longer than the stability window
+    let file_path = watched_path.join("video.mkv");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&file_path)?;
+    for _ in 0..10 {
+        file.write_all(b"chunk")?;
+        thread::sleep(Duration::from_millis(300));
+    }
+    file.write_all(b"chunk")?;
+    let last_write = Instant::now();
+    drop(file);
+
+    // It's reported once, after its size stopped changing for the stability window
+    connection.expect_update(&file_path)?;
+    let elapsed = last_write.elapsed();
+    if elapsed < Duration::from_millis(900) {
+        anyhow::bail!("file was reported {elapsed:?} after the last write");
+    }
+
+    connection
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(3)))?;
+    if let Ok(msg) = connection.receive() {
+        anyhow::bail!("expected a single update, got {msg:?}");
+    }
+
+    Ok(())
+}
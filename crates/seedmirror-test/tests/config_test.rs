@@ -0,0 +1,62 @@
+use std::{fs, thread, time::Duration};
+
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_config_reload() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("config_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let config_path = test_dir.path.join("seedmirror-server.toml");
+
+    // The sync delay given on the command line takes precedence over the one in the file
+    fs::write(&config_path, "sync-delay = 600000\nexclude = [\"*.tmp\"]\n")?;
+    let server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--config".as_ref(),
+            config_path.as_os_str(),
+        ],
+    )?;
+
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    fs::write(watched_path.join("first.tmp"), "")?;
+    fs::write(watched_path.join("first.txt"), "")?;
+    connection.expect_update(&watched_path.join("first.txt"))?;
+
+    // The reloaded filters apply to the existing connection
+    fs::write(&config_path, "exclude = [\"*.txt\"]\n")?;
+    server.signal("HUP")?;
+    thread::sleep(Duration::from_millis(500));
+
+    fs::write(watched_path.join("second.txt"), "")?;
+    fs::write(watched_path.join("second.tmp"), "")?;
+    connection.expect_update(&watched_path.join("second.tmp"))?;
+
+    // An invalid file is ignored
+    fs::write(&config_path, "exclude = \"*.txt\"\n")?;
+    server.signal("HUP")?;
+    thread::sleep(Duration::from_millis(500));
+
+    fs::write(watched_path.join("third.txt"), "")?;
+    fs::write(watched_path.join("third.tmp"), "")?;
+    connection.expect_update(&watched_path.join("third.tmp"))?;
+
+    // So is a file with a duration that's invalid on the command line too
+    fs::write(
+        &config_path,
+        "stability-check-interval = 0\nexclude = [\"*.tmp\"]\n",
+    )?;
+    server.signal("HUP")?;
+    thread::sleep(Duration::from_millis(500));
+
+    fs::write(watched_path.join("fourth.txt"), "")?;
+    fs::write(watched_path.join("fourth.tmp"), "")?;
+    connection.expect_update(&watched_path.join("fourth.tmp"))?;
+
+    Ok(())
+}
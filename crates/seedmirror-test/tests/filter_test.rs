@@ -0,0 +1,62 @@
+use std::{fs, time::Duration};
+
+use seedmirror_core::{
+    filter::{FilterRules, WatchedPathFilter},
+    message::{Capability, Message, PROTOCOL_VERSION},
+};
+use seedmirror_test::{message::connect_with, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_merged_include_patterns() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("filter_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--include".as_ref(),
+            "*.mkv".as_ref(),
+            "--include".as_ref(),
+            "*.txt".as_ref(),
+        ],
+    )?;
+
+    let (mut connection, _connected) = connect_with(
+        &socket_path,
+        Message::ConnectionRequest {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capability::supported(),
+            watched_paths: vec![watched_path.clone()],
+            filters: vec![WatchedPathFilter {
+                path: watched_path.clone(),
+                rules: FilterRules {
+                    include: vec!["*.txt".to_string(), "*.srt".to_string()],
+                    exclude: Vec::new(),
+                    include_also: Vec::new(),
+                },
+            }],
+            token: None,
+            last_seq: None,
+            journal_id: None,
+        },
+    )?;
+
+    // Files have to be included by both the server and the client
+    fs::write(watched_path.join("video.mkv"), "")?;
+    fs::write(watched_path.join("subtitles.srt"), "")?;
+    fs::write(watched_path.join("notes.txt"), "")?;
+    connection.expect_update(&watched_path.join("notes.txt"))?;
+
+    connection
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_secs(1)))?;
+    if let Ok(msg) = connection.receive() {
+        anyhow::bail!("expected files included by only one side to be filtered, got {msg:?}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::{
+    fs,
+    os::unix::net::UnixStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use seedmirror_core::message::Message;
+use seedmirror_test::{
+    message::{Connection, connect},
+    path::TempDir,
+    process::spawn_server,
+};
+
+#[test]
+fn test_heartbeat_timeout() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("heartbeat_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--heartbeat-interval".as_ref(),
+            "200".as_ref(),
+            "--missed-heartbeats".as_ref(),
+            "3".as_ref(),
+        ],
+    )?;
+
+    // Pings sent by the server are never answered, but any message counts as a heartbeat
+    let mut silent_connection = connect(&socket_path, &[&watched_path])?;
+    let connected = Instant::now();
+    let silent = thread::spawn(move || (silent_connection.receive(), connected.elapsed()));
+    let mut live_connection = connect(&socket_path, &[&watched_path])?;
+
+    while connected.elapsed() < Duration::from_millis(1500) {
+        live_connection.send(Message::Ping)?;
+        match live_connection.receive()? {
+            Message::Pong => (),
+            msg => anyhow::bail!("expected pong, got {msg:?}"),
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // The connection is closed after missing 3 heartbeats of 200 ms. Pings keep arriving
+    // otherwise, so receiving doesn't time out
+    while !silent.is_finished() {
+        anyhow::ensure!(
+            connected.elapsed() < Duration::from_secs(5),
+            "expected connection to be closed after 3 heartbeats"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    let (res, elapsed) = silent.join().unwrap();
+    assert!(
+        res.is_err(),
+        "expected connection to be closed, got {res:?}"
+    );
+    assert!(
+        elapsed >= Duration::from_millis(600),
+        "expected connection to be closed after 3 heartbeats, took {elapsed:?}"
+    );
+
+    live_connection.send(Message::Ping)?;
+    match live_connection.receive()? {
+        Message::Pong => (),
+        msg => anyhow::bail!("expected live connection to stay open, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_handshake_timeout() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("heartbeat_test_handshake")?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--heartbeat-interval".as_ref(),
+            "200".as_ref(),
+            "--missed-heartbeats".as_ref(),
+            "3".as_ref(),
+        ],
+    )?;
+
+    // Pings don't count as a handshake, so the connection is closed all the same
+    let stream = UnixStream::connect(&socket_path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut connection = Connection::new(stream);
+    let connected = Instant::now();
+    connection.send(Message::Ping)?;
+    match connection.receive()? {
+        Message::Pong => (),
+        msg => anyhow::bail!("expected pong, got {msg:?}"),
+    }
+
+    let err = connection.receive().unwrap_err();
+    assert!(
+        err.to_string().contains("connection closed by server"),
+        "expected connection to be closed, got {err:#}"
+    );
+    assert!(connected.elapsed() >= Duration::from_millis(600));
+
+    Ok(())
+}
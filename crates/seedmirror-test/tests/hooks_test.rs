@@ -0,0 +1,137 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read},
+    os::unix::fs::OpenOptionsExt,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use seedmirror_core::message::Message;
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_hooks() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("hooks_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let output_path = test_dir.path.join("hook_output");
+    let fifo_path = test_dir.path.join("hook_fifo");
+    anyhow::ensure!(Command::new("mkfifo").arg(&fifo_path).status()?.success());
+
+    // Opened for writing too, so that reading doesn't return EOF while the server isn't writing
+    let mut fifo = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&fifo_path)?;
+
+    let hook_command = format!(
+        "echo \"$SEEDMIRROR_EVENT $SEEDMIRROR_WATCHED_ROOT $SEEDMIRROR_PATH\" > {}",
+        output_path.display()
+    );
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            // The update is then caused by the file being closed
+            "--completion".as_ref(),
+            "close-write".as_ref(),
+            "--hook-command".as_ref(),
+            hook_command.as_ref(),
+            "--hook-fifo".as_ref(),
+            fifo_path.as_os_str(),
+        ],
+    )?;
+
+    // Paths are only watched while a client is connected
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    let file_path = watched_path.join("new_file.txt");
+    fs::write(&file_path, "")?;
+    connection.expect_update(&file_path)?;
+
+    let output = wait_for(|| {
+        let output = fs::read_to_string(&output_path).ok()?;
+        output.ends_with('\n').then_some(output)
+    })?;
+    assert_eq!(
+        output,
+        format!(
+            "close-write {} {}\n",
+            watched_path.display(),
+            file_path.display()
+        )
+    );
+
+    let event = read_event(&mut fifo)?;
+    assert_eq!(event["path"], file_path.to_str().unwrap());
+    assert_eq!(event["watched_root"], watched_path.to_str().unwrap());
+    assert_eq!(event["event"], "close-write");
+
+    // Renamed files are passed to the hooks with their new path
+    let renamed_path = watched_path.join("renamed_file.txt");
+    fs::rename(&file_path, &renamed_path)?;
+    match connection.receive()? {
+        Message::FileRenamed { from, to, .. } => {
+            assert_eq!(from, file_path);
+            assert_eq!(to, renamed_path);
+        }
+        msg => anyhow::bail!("expected rename, got {msg:?}"),
+    }
+
+    let expected_output = format!(
+        "rename {} {}\n",
+        watched_path.display(),
+        renamed_path.display()
+    );
+    wait_for(|| {
+        let output = fs::read_to_string(&output_path).ok()?;
+        (output == expected_output).then_some(())
+    })?;
+
+    let event = read_event(&mut fifo)?;
+    assert_eq!(event["path"], renamed_path.to_str().unwrap());
+    assert_eq!(event["event"], "rename");
+
+    // Updates of files in a new directory are reported along with the directory, with the kind
+    // of the event that created it
+    let dir_path = watched_path.join("new_dir");
+    fs::create_dir(&dir_path)?;
+    fs::write(dir_path.join("file.txt"), "")?;
+    connection.expect_update(&dir_path)?;
+
+    let event = read_event(&mut fifo)?;
+    assert_eq!(event["path"], dir_path.to_str().unwrap());
+    assert_eq!(event["event"], "create");
+
+    Ok(())
+}
+
+/// Reads the next update written to the FIFO.
+fn read_event(fifo: &mut File) -> anyhow::Result<serde_json::Value> {
+    let line = wait_for(|| {
+        let mut buf = [0; 4096];
+        match fifo.read(&mut buf) {
+            Ok(n) => Some(String::from_utf8_lossy(&buf[..n]).into_owned()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(e) => panic!("failed to read from FIFO: {e}"),
+        }
+    })?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Calls `f` until it returns a value, for up to 10 seconds.
+fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> anyhow::Result<T> {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(10) {
+        if let Some(value) = f() {
+            return Ok(value);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    anyhow::bail!("timed out waiting for hook")
+}
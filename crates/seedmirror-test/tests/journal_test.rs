@@ -0,0 +1,278 @@
+use std::{fs, os::unix::net::UnixStream, path::Path, thread, time::Duration};
+
+use seedmirror_core::message::{Capability, Message, PROTOCOL_VERSION};
+use seedmirror_test::{
+    message::{Connection, connect_with},
+    path::TempDir,
+    process::spawn_server,
+};
+
+type UnixConnection = Connection<UnixStream>;
+
+#[test]
+fn test_journal_replay() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("journal_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let journal_path = test_dir.path.join("journal");
+    let server_args = [
+        "--sync-delay".as_ref(),
+        "100".as_ref(),
+        "--journal-path".as_ref(),
+        journal_path.as_os_str(),
+    ];
+
+    let server = spawn_server(&socket_path, &server_args)?;
+    let (mut connection, journal_id) = connect(&socket_path, &watched_path, None)?;
+    fs::write(watched_path.join("first.txt"), "")?;
+    match connection.receive()? {
+        Message::FileUpdated { path, seq } => {
+            assert_eq!(path, watched_path.join("first.txt"));
+            assert_eq!(seq, Some(1));
+        }
+        msg => anyhow::bail!("expected file update, got {msg:?}"),
+    }
+    drop(connection);
+
+    // The path keeps being watched while no client is connected
+    thread::sleep(Duration::from_millis(500));
+    fs::write(watched_path.join("second.txt"), "")?;
+    thread::sleep(Duration::from_millis(500));
+    fs::rename(
+        watched_path.join("first.txt"),
+        watched_path.join("renamed.txt"),
+    )?;
+    thread::sleep(Duration::from_millis(500));
+    fs::write(watched_path.join("removed.txt"), "")?;
+    thread::sleep(Duration::from_millis(500));
+    fs::remove_file(watched_path.join("removed.txt"))?;
+    thread::sleep(Duration::from_secs(1));
+
+    // Restart the server to check that the journal is persisted
+    drop(server);
+    thread::sleep(Duration::from_millis(500));
+    let _server = spawn_server(&socket_path, &server_args)?;
+
+    // Renames are replayed as the removal of the old path and an update of the new one, and only
+    // the latest change of each path is replayed
+    let (mut connection, _journal_id) =
+        connect(&socket_path, &watched_path, Some((&journal_id, 1)))?;
+    let expected = [
+        Message::FileUpdated {
+            path: watched_path.join("second.txt"),
+            seq: Some(2),
+        },
+        Message::FileRemoved {
+            path: watched_path.join("first.txt"),
+            seq: Some(3),
+        },
+        Message::FileUpdated {
+            path: watched_path.join("renamed.txt"),
+            seq: Some(4),
+        },
+        Message::FileRemoved {
+            path: watched_path.join("removed.txt"),
+            seq: Some(6),
+        },
+    ];
+    for expected in expected {
+        assert_eq!(connection.receive()?, expected);
+    }
+
+    // Unknown sequence numbers can't be replayed, so the client is asked to resync instead
+    let (mut connection, connected) = connect_with(
+        &socket_path,
+        connection_request(&watched_path, Some((&journal_id, 99))),
+    )?;
+    assert!(matches!(
+        connected,
+        Message::Connected {
+            replaying: false,
+            ..
+        }
+    ));
+    expect_resync(&mut connection, &watched_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_recreated_journal() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("journal_test_recreated")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let journal_path = test_dir.path.join("journal");
+    let server_args = [
+        "--sync-delay".as_ref(),
+        "100".as_ref(),
+        "--journal-path".as_ref(),
+        journal_path.as_os_str(),
+    ];
+
+    let server = spawn_server(&socket_path, &server_args)?;
+    let (mut connection, journal_id) = connect(&socket_path, &watched_path, None)?;
+    fs::write(watched_path.join("first.txt"), "")?;
+    connection.expect_update(&watched_path.join("first.txt"))?;
+    drop(connection);
+
+    // Sequence numbers start over in the recreated journal, so the client's last sequence number
+    // doesn't refer to it
+    drop(server);
+    fs::remove_file(&journal_path)?;
+    thread::sleep(Duration::from_millis(500));
+    let _server = spawn_server(&socket_path, &server_args)?;
+    fs::write(watched_path.join("second.txt"), "")?;
+
+    let (mut connection, connected) = connect_with(
+        &socket_path,
+        connection_request(&watched_path, Some((&journal_id, 1))),
+    )?;
+    match connected {
+        Message::Connected {
+            replaying,
+            journal_id: new_journal_id,
+            ..
+        } => {
+            assert!(!replaying);
+            assert!(new_journal_id.is_some_and(|id| id != journal_id));
+        }
+        msg => anyhow::bail!("expected connection to be accepted, got {msg:?}"),
+    }
+    expect_resync(&mut connection, &watched_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_filtered_changes_not_recorded() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("journal_test_filtered")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let journal_path = test_dir.path.join("journal");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--journal-path".as_ref(),
+            journal_path.as_os_str(),
+            "--exclude".as_ref(),
+            "*.part".as_ref(),
+        ],
+    )?;
+
+    // Writing the partial file doesn't take a sequence number, and renaming it to its final name
+    // is an update of the final name
+    let (mut connection, _journal_id) = connect(&socket_path, &watched_path, None)?;
+    fs::write(watched_path.join("video.mkv.part"), "")?;
+    thread::sleep(Duration::from_millis(500));
+    fs::rename(
+        watched_path.join("video.mkv.part"),
+        watched_path.join("video.mkv"),
+    )?;
+    assert_eq!(
+        connection.receive()?,
+        Message::FileUpdated {
+            path: watched_path.join("video.mkv"),
+            seq: Some(1),
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_watched_path_expiry() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("journal_test_expiry")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let journal_path = test_dir.path.join("journal");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--journal-path".as_ref(),
+            journal_path.as_os_str(),
+            "--journal-watch-ttl".as_ref(),
+            "500".as_ref(),
+        ],
+    )?;
+
+    let (mut connection, journal_id) = connect(&socket_path, &watched_path, None)?;
+    fs::write(watched_path.join("first.txt"), "")?;
+    connection.expect_update(&watched_path.join("first.txt"))?;
+    assert!(fs::read_to_string(&journal_path)?.contains(r#"{"watch":"#));
+    drop(connection);
+
+    // The path is no longer watched once no client watched it for the time-to-live, and its record
+    // is removed from the journal
+    thread::sleep(Duration::from_secs(2));
+    assert!(!fs::read_to_string(&journal_path)?.contains(r#"{"watch":"#));
+    fs::write(watched_path.join("second.txt"), "")?;
+    thread::sleep(Duration::from_millis(500));
+
+    // The change made while the path wasn't watched can't be replayed
+    let (_connection, connected) = connect_with(
+        &socket_path,
+        connection_request(&watched_path, Some((&journal_id, 1))),
+    )?;
+    assert!(matches!(
+        connected,
+        Message::Connected {
+            replaying: false,
+            ..
+        }
+    ));
+
+    Ok(())
+}
+
+/// Connects to the server, expecting it to replay updates if `last_seq` is set, and returns the
+/// connection along with the ID of the server's journal.
+fn connect(
+    socket_path: &Path,
+    watched_path: &Path,
+    last_seq: Option<(&str, u64)>,
+) -> anyhow::Result<(UnixConnection, String)> {
+    let (connection, connected) =
+        connect_with(socket_path, connection_request(watched_path, last_seq))?;
+    match connected {
+        Message::Connected {
+            replaying,
+            journal_id: Some(journal_id),
+            ..
+        } => {
+            assert_eq!(replaying, last_seq.is_some());
+            Ok((connection, journal_id))
+        }
+        msg => anyhow::bail!("expected connection with a journal ID to be accepted, got {msg:?}"),
+    }
+}
+
+/// Returns the request of a client whose last synced update is `last_seq` in the journal with the
+/// given ID.
+fn connection_request(watched_path: &Path, last_seq: Option<(&str, u64)>) -> Message {
+    Message::ConnectionRequest {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capability::supported(),
+        watched_paths: vec![watched_path.to_path_buf()],
+        filters: Vec::new(),
+        token: None,
+        last_seq: last_seq.map(|(_journal_id, seq)| seq),
+        journal_id: last_seq.map(|(journal_id, _seq)| journal_id.to_string()),
+    }
+}
+
+fn expect_resync(connection: &mut UnixConnection, watched_path: &Path) -> anyhow::Result<()> {
+    match connection.receive()? {
+        Message::ResyncRequired { paths } => assert_eq!(paths, [watched_path]),
+        msg => anyhow::bail!("expected resync of {watched_path:?}, got {msg:?}"),
+    }
+
+    Ok(())
+}
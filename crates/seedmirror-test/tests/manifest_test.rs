@@ -0,0 +1,167 @@
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use seedmirror_core::{
+    filter::{FilterRules, WatchedPathFilter},
+    manifest::{EntryKind, ManifestEntry},
+    message::{Capability, ErrorCode, Message, PROTOCOL_VERSION},
+};
+use seedmirror_test::{
+    message::{connect, connect_with},
+    path::TempDir,
+    process::spawn_server,
+};
+
+#[test]
+fn test_manifest() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("manifest_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir_all(watched_path.join("dir"))?;
+    fs::write(watched_path.join("file.txt"), "content")?;
+    fs::write(watched_path.join("dir").join("nested.txt"), "")?;
+    fs::write(watched_path.join("excluded.tmp"), "")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &[])?;
+
+    let (mut connection, connected) = connect_with(
+        &socket_path,
+        Message::ConnectionRequest {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capability::supported(),
+            watched_paths: vec![watched_path.clone()],
+            filters: vec![WatchedPathFilter {
+                path: watched_path.clone(),
+                rules: FilterRules {
+                    include: Vec::new(),
+                    exclude: vec!["*.tmp".to_string()],
+                    include_also: Vec::new(),
+                },
+            }],
+            token: None,
+            last_seq: None,
+            journal_id: None,
+        },
+    )?;
+    if let Message::Connected { capabilities, .. } = connected {
+        assert!(capabilities.contains(&Capability::Manifest));
+    }
+
+    connection.send(Message::ManifestRequest {
+        path: watched_path.clone(),
+    })?;
+    let mut entries = match connection.receive()? {
+        Message::Manifest {
+            path,
+            entries,
+            done,
+        } => {
+            assert_eq!(path, watched_path);
+            assert!(done);
+            entries
+        }
+        msg => anyhow::bail!("expected manifest, got {msg:?}"),
+    };
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|entry: &ManifestEntry| (entry.path.as_path(), entry.kind))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (Path::new("dir"), EntryKind::Directory),
+            (Path::new("dir/nested.txt"), EntryKind::File),
+            (Path::new("file.txt"), EntryKind::File),
+        ]
+    );
+    assert_eq!(entries[2].size, "content".len() as u64);
+
+    // Only watched paths can be listed
+    connection.send(Message::ManifestRequest {
+        path: test_dir.path.clone(),
+    })?;
+    match connection.receive()? {
+        Message::Error { code, .. } => assert_eq!(code, ErrorCode::PathNotAllowed),
+        msg => anyhow::bail!("expected listing an unwatched path to be refused, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest_unreadable_directory() -> anyhow::Result<()> {
+    // Permissions don't stop root from reading directories
+    if unsafe { libc::geteuid() } == 0 {
+        eprintln!("skipping, running as root");
+        return Ok(());
+    }
+
+    let test_dir = TempDir::new("manifest_test_unreadable_directory")?;
+    let watched_path = test_dir.path.join("watched");
+    let unreadable_path = watched_path.join("unreadable");
+    fs::create_dir_all(&unreadable_path)?;
+    fs::write(unreadable_path.join("hidden.txt"), "")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &[])?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+    fs::set_permissions(&unreadable_path, fs::Permissions::from_mode(0o000))?;
+
+    // An incomplete listing would make the client remove the files it couldn't list
+    connection.send(Message::ManifestRequest {
+        path: watched_path.clone(),
+    })?;
+    let res = connection.receive();
+    fs::set_permissions(&unreadable_path, fs::Permissions::from_mode(0o755))?;
+    match res? {
+        Message::Error { code, .. } => assert_eq!(code, ErrorCode::ManifestFailed),
+        msg => anyhow::bail!("expected listing to fail, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest_chunks() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("manifest_test_chunks")?;
+    let large_path = test_dir.path.join("large");
+    let small_path = test_dir.path.join("small");
+    fs::create_dir(&large_path)?;
+    fs::create_dir(&small_path)?;
+    for i in 0..2500 {
+        fs::write(large_path.join(format!("{i}.txt")), "")?;
+    }
+    fs::write(small_path.join("file.txt"), "")?;
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &[])?;
+    let mut connection = connect(&socket_path, &[&large_path, &small_path])?;
+
+    // Manifests are sent in chunks, one manifest after the other
+    for path in [&large_path, &small_path] {
+        connection.send(Message::ManifestRequest { path: path.clone() })?;
+    }
+    let mut received = Vec::new();
+    while received.iter().filter(|(_path, _len, done)| *done).count() < 2 {
+        match connection.receive()? {
+            Message::Manifest {
+                path,
+                entries,
+                done,
+            } => received.push((path, entries.len(), done)),
+            msg => anyhow::bail!("expected manifest, got {msg:?}"),
+        }
+    }
+    assert_eq!(
+        received,
+        vec![
+            (large_path.clone(), 1000, false),
+            (large_path.clone(), 1000, false),
+            (large_path.clone(), 500, true),
+            (small_path.clone(), 1, true),
+        ]
+    );
+
+    Ok(())
+}
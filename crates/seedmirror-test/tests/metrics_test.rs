@@ -0,0 +1,69 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use seedmirror_core::message::Message;
+use seedmirror_test::{
+    message::connect,
+    path::TempDir,
+    process::{free_port, spawn_server},
+};
+
+#[test]
+fn test_metrics() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("metrics_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let port = free_port()?;
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--metrics-address".as_ref(),
+            format!("127.0.0.1:{port}").as_ref(),
+        ],
+    )?;
+
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    fs::write(watched_path.join("new_file.txt"), "")?;
+    match connection.receive()? {
+        Message::FileUpdated { .. } => (),
+        msg => anyhow::bail!("expected file update, got {msg:?}"),
+    }
+
+    let metrics = scrape(port, "/metrics")?;
+    assert!(metrics.starts_with("HTTP/1.1 200 OK"), "{metrics}");
+    assert!(
+        metrics.contains("\nseedmirror_connected_clients 1\n"),
+        "{metrics}"
+    );
+    assert!(metrics.contains("\nseedmirror_watched_roots 1\n"), "{metrics}");
+    assert!(
+        !metrics.contains("seedmirror_filesystem_events_total{kind=\"create\"} 0"),
+        "{metrics}"
+    );
+
+    let not_found = scrape(port, "/")?;
+    assert!(
+        not_found.starts_with("HTTP/1.1 404 Not Found"),
+        "{not_found}"
+    );
+
+    Ok(())
+}
+
+fn scrape(port: u16, path: &str) -> anyhow::Result<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
@@ -0,0 +1,24 @@
+use std::fs;
+
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_overlapping_watched_paths() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("overlap_test")?;
+    let tree_path = test_dir.path.join("tree");
+    let sub_path = tree_path.join("sub");
+    fs::create_dir_all(sub_path.join("deep"))?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "100".as_ref()])?;
+
+    // Watching the ancestor takes over the watches of the descendant watched by the first client
+    let mut sub_connection = connect(&socket_path, &[&sub_path])?;
+    let mut tree_connection = connect(&socket_path, &[&tree_path])?;
+
+    let file_path = sub_path.join("deep").join("f.txt");
+    fs::write(&file_path, "")?;
+    sub_connection.expect_update(&file_path)?;
+    tree_connection.expect_update(&file_path)?;
+
+    Ok(())
+}
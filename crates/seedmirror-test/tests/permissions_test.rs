@@ -0,0 +1,73 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    thread,
+    time::{Duration, Instant},
+};
+
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_socket_permissions() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("permissions_test")?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let server = spawn_server(&socket_path, &[])?;
+    assert_eq!(
+        fs::metadata(&socket_path)?.permissions().mode() & 0o777,
+        0o600
+    );
+
+    // The user running the server is always allowed to connect
+    let _connection = connect(&socket_path, &[&test_dir.path])?;
+    drop(server);
+    thread::sleep(Duration::from_millis(500));
+
+    let server = spawn_server(&socket_path, &["--socket-mode".as_ref(), "660".as_ref()])?;
+    assert_eq!(
+        fs::metadata(&socket_path)?.permissions().mode() & 0o777,
+        0o660
+    );
+    drop(server);
+    thread::sleep(Duration::from_millis(500));
+
+    // Allowing other users doesn't open the socket to everyone implicitly
+    let _server = spawn_server(&socket_path, &["--allowed-uid".as_ref(), "12345".as_ref()])?;
+    assert_eq!(
+        fs::metadata(&socket_path)?.permissions().mode() & 0o777,
+        0o600
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_metrics_socket_permissions() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("permissions_test_metrics")?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let metrics_socket_path = test_dir.path.join("metrics.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--metrics-socket-path".as_ref(),
+            metrics_socket_path.as_os_str(),
+        ],
+    )?;
+
+    // The metrics socket is created after the server's socket
+    let started = Instant::now();
+    loop {
+        let mode = fs::metadata(&metrics_socket_path)
+            .ok()
+            .map(|metadata| format!("{:o}", metadata.permissions().mode() & 0o777));
+        if mode.as_deref() == Some("600") {
+            break;
+        }
+        anyhow::ensure!(
+            started.elapsed() < Duration::from_secs(10),
+            "expected metrics socket with mode 600, got {mode:?}"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::{fs, thread, time::Duration};
+
+use seedmirror_core::message::Message;
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_poll_backend() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("poll_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--watcher-backend".as_ref(),
+            "poll".as_ref(),
+            "--poll-interval".as_ref(),
+            "100".as_ref(),
+        ],
+    )?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    // Creations, modifications and removals are all found by scanning the watched path
+    let file_path = watched_path.join("file.txt");
+    fs::write(&file_path, "created")?;
+    connection.expect_update(&file_path)?;
+
+    // Make sure the modification time changes even on filesystems with coarse timestamps
+    thread::sleep(Duration::from_millis(1100));
+    fs::write(&file_path, "modified")?;
+    connection.expect_update(&file_path)?;
+
+    fs::remove_file(&file_path)?;
+    match connection.receive()? {
+        Message::FileRemoved { path, .. } => assert_eq!(path, file_path),
+        msg => anyhow::bail!("expected removal of {file_path:?}, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_watcher_error() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("poll_test_error")?;
+    let removed_path = test_dir.path.join("removed");
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&removed_path)?;
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(
+        &socket_path,
+        &[
+            "--sync-delay".as_ref(),
+            "100".as_ref(),
+            "--watcher-backend".as_ref(),
+            "poll".as_ref(),
+            "--poll-interval".as_ref(),
+            "10".as_ref(),
+        ],
+    )?;
+    let _removed_connection = connect(&socket_path, &[&removed_path])?;
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    // Each scan fails to read the removed watched path, as well as files removed while a scan
+    // lists them, which doesn't stop the server from watching the other paths
+    fs::remove_dir(&removed_path)?;
+    for round in 0..10 {
+        let dir_path = watched_path.join(round.to_string());
+        fs::create_dir(&dir_path)?;
+        for i in 0..100 {
+            fs::write(dir_path.join(i.to_string()), "")?;
+        }
+        thread::sleep(Duration::from_millis(20));
+        fs::remove_dir_all(&dir_path)?;
+    }
+
+    let file_path = watched_path.join("file.txt");
+    fs::write(&file_path, "")?;
+    loop {
+        match connection.receive()? {
+            Message::FileUpdated { path, .. } if path == file_path => break,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
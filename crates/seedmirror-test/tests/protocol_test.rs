@@ -0,0 +1,95 @@
+use std::{fs, os::unix::net::UnixStream, time::Duration};
+
+use seedmirror_core::message::{Capability, ErrorCode, Message, PROTOCOL_VERSION};
+use seedmirror_test::{
+    message::{Connection, connect_with, connection_request},
+    path::TempDir,
+    process::spawn_server,
+};
+
+#[test]
+fn test_negotiated_capabilities() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("protocol_test_capabilities")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &[])?;
+
+    // Only the capabilities supported by both peers are advertised back
+    let mut request = connection_request(&[&watched_path]);
+    if let Message::ConnectionRequest { capabilities, .. } = &mut request {
+        *capabilities = vec![Capability::Manifest];
+    }
+    let (_connection, connected) = connect_with(&socket_path, request)?;
+    match connected {
+        Message::Connected { capabilities, .. } => {
+            assert_eq!(capabilities, vec![Capability::Manifest]);
+        }
+        msg => anyhow::bail!("expected connection to be accepted, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_incompatible_protocol() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("protocol_test_incompatible")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &[])?;
+
+    let stream = UnixStream::connect(&socket_path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut connection = Connection::new(stream);
+    let mut request = connection_request(&[&watched_path]);
+    if let Message::ConnectionRequest {
+        protocol_version, ..
+    } = &mut request
+    {
+        *protocol_version = PROTOCOL_VERSION + 1;
+    }
+    connection.send(request)?;
+    match connection.receive()? {
+        Message::Error {
+            code: ErrorCode::IncompatibleProtocol,
+            ..
+        } => (),
+        msg => anyhow::bail!("expected incompatible protocol error, got {msg:?}"),
+    }
+
+    // The server closes the connection after refusing the client
+    let err = connection.receive().unwrap_err();
+    assert!(err.to_string().contains("connection closed by server"));
+
+    Ok(())
+}
+
+#[test]
+fn test_unwatchable_path() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("protocol_test_unwatchable")?;
+    let missing_path = test_dir.path.join("missing");
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "100".as_ref()])?;
+
+    // The path that can't be watched is reported, and the other one is still watched
+    let (mut connection, _connected) = connect_with(
+        &socket_path,
+        connection_request(&[&missing_path, &watched_path]),
+    )?;
+    match connection.receive()? {
+        Message::Error {
+            code: ErrorCode::WatchFailed,
+            reason,
+        } => assert!(reason.contains(&missing_path.display().to_string())),
+        msg => anyhow::bail!("expected watch failure, got {msg:?}"),
+    }
+
+    let file_path = watched_path.join("new_file.txt");
+    fs::write(&file_path, "")?;
+    connection.expect_update(&file_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use std::{fs, thread, time::Duration};
+
+use seedmirror_core::message::Message;
+use seedmirror_test::{message::connect, path::TempDir, process::spawn_server};
+
+#[test]
+fn test_resync_after_skipped_updates() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("resync_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let _server = spawn_server(&socket_path, &["--sync-delay".as_ref(), "100".as_ref()])?;
+
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    // Not reading updates fills the socket buffer, after which the server can't keep up with
+    // the updates
+    let long_name = "x".repeat(200);
+    for i in 0..2000 {
+        fs::write(watched_path.join(format!("{long_name}{i}")), "")?;
+    }
+    thread::sleep(Duration::from_secs(3));
+
+    loop {
+        match connection.receive()? {
+            Message::FileUpdated { .. } => (),
+            Message::ResyncRequired { paths } => {
+                assert_eq!(paths, vec![watched_path]);
+                break;
+            }
+            msg => anyhow::bail!("expected file update or resync request, got {msg:?}"),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::{
+    fs,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            net::{UnixDatagram, UnixListener},
+            process::CommandExt,
+        },
+    },
+    process::Command,
+    time::Duration,
+};
+
+use seedmirror_test::{
+    message::connect,
+    path::TempDir,
+    process::{ProcessGuard, build_workspace},
+};
+
+/// First file descriptor passed by the service manager.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+#[test]
+fn test_socket_activation() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("systemd_test")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let activated_socket_path = test_dir.path.join("activated.sock");
+    let unused_socket_path = test_dir.path.join("unused.sock");
+    let notify_socket_path = test_dir.path.join("notify.sock");
+
+    // Bind the sockets like the service manager would
+    let listener = UnixListener::bind(&activated_socket_path)?;
+    let notify_socket = UnixDatagram::bind(&notify_socket_path)?;
+    notify_socket.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+    let workspace_dir = build_workspace()?;
+    let listener_fd = listener.as_raw_fd();
+    let mut cmd = Command::new("sh");
+    cmd.current_dir(&workspace_dir)
+        // The shell keeps its pid when executing the server
+        .args([
+            "-c",
+            "export LISTEN_PID=$$ WATCHDOG_PID=$$; exec \"$0\" \"$@\"",
+            "target/debug/seedmirror-server",
+            "--socket-path",
+        ])
+        .arg(&unused_socket_path)
+        .env("LISTEN_FDS", "1")
+        .env("NOTIFY_SOCKET", &notify_socket_path)
+        .env("WATCHDOG_USEC", "500000");
+    // SAFETY: dup2 and fcntl are async-signal-safe. The socket is moved to the first passed
+    // descriptor without the close-on-exec flag, so it's inherited by the server.
+    unsafe {
+        cmd.pre_exec(move || {
+            let res = if listener_fd == SD_LISTEN_FDS_START {
+                libc::fcntl(listener_fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(listener_fd, SD_LISTEN_FDS_START)
+            };
+            if res == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let _server = ProcessGuard::spawn(&mut cmd)?;
+
+    assert_eq!(receive_notification(&notify_socket)?, "READY=1\n");
+    assert_eq!(receive_notification(&notify_socket)?, "WATCHDOG=1\n");
+    assert_eq!(receive_notification(&notify_socket)?, "WATCHDOG=1\n");
+
+    let _connection = connect(&activated_socket_path, &[&watched_path])?;
+
+    assert!(
+        !unused_socket_path.exists(),
+        "expected the server not to bind its own socket"
+    );
+
+    Ok(())
+}
+
+fn receive_notification(notify_socket: &UnixDatagram) -> anyhow::Result<String> {
+    let mut buf = [0; 1024];
+    let n = notify_socket.recv(&mut buf)?;
+    Ok(String::from_utf8(buf[..n].to_vec())?)
+}
@@ -0,0 +1,196 @@
+use std::{fs, net::TcpStream, path::Path, process::Command, sync::Arc, thread, time::Duration};
+
+use rcgen::{CertifiedKey, generate_simple_self_signed};
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    crypto::ring,
+    pki_types::{PrivateKeyDer, ServerName},
+};
+use seedmirror_core::{
+    auth::Token,
+    message::{Capability, ErrorCode, Message, PROTOCOL_VERSION},
+};
+use seedmirror_test::{
+    message::Connection,
+    path::TempDir,
+    process::{ProcessGuard, free_port, workspace_dir},
+};
+
+type TlsConnection = Connection<StreamOwned<ClientConnection, TcpStream>>;
+
+#[test]
+fn test_token_authentication() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("tls_token_test")?;
+    let server_cert = write_cert(&test_dir.path, "server")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+
+    let token_path = test_dir.path.join("token");
+    fs::write(&token_path, "correct horse battery staple\n")?;
+    let wrong_token_path = test_dir.path.join("wrong-token");
+    fs::write(&wrong_token_path, "incorrect horse\n")?;
+
+    let port = free_port()?;
+    let _server = spawn_server(
+        &test_dir.path,
+        port,
+        &["--token-file".as_ref(), token_path.as_os_str()],
+    )?;
+
+    let mut connection = connect(port, &server_cert, None)?;
+    connection.send(connection_request(&watched_path, None))?;
+    match connection.receive()? {
+        Message::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+        msg => anyhow::bail!("expected missing token to be refused, got {msg:?}"),
+    }
+
+    let mut connection = connect(port, &server_cert, None)?;
+    let wrong_token = Token::read(&wrong_token_path)?;
+    connection.send(connection_request(&watched_path, Some(wrong_token)))?;
+    match connection.receive()? {
+        Message::Error { code, .. } => assert_eq!(code, ErrorCode::Unauthorized),
+        msg => anyhow::bail!("expected wrong token to be refused, got {msg:?}"),
+    }
+
+    let mut connection = connect(port, &server_cert, None)?;
+    let token = Token::read(&token_path)?;
+    connection.send(connection_request(&watched_path, Some(token)))?;
+    match connection.receive()? {
+        Message::Connected { .. } => (),
+        msg => anyhow::bail!("expected connection to be accepted, got {msg:?}"),
+    }
+
+    fs::write(watched_path.join("new_file.txt"), "")?;
+    match connection.receive()? {
+        Message::FileUpdated { path, .. } => assert_eq!(path, watched_path.join("new_file.txt")),
+        msg => anyhow::bail!("expected file update, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_client_certificate_authentication() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("tls_client_cert_test")?;
+    let server_cert = write_cert(&test_dir.path, "server")?;
+    let client_cert = write_cert(&test_dir.path, "client")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+
+    let port = free_port()?;
+    let client_ca = test_dir.path.join("client.pem");
+    let _server = spawn_server(
+        &test_dir.path,
+        port,
+        &["--tls-client-ca".as_ref(), client_ca.as_os_str()],
+    )?;
+
+    // The server refuses the handshake, which is noticed when reading from the connection
+    let res = connect(port, &server_cert, None).and_then(|mut connection| {
+        connection.send(connection_request(&watched_path, None))?;
+        connection.receive()
+    });
+    assert!(
+        res.is_err(),
+        "expected connection without certificate to fail"
+    );
+
+    let mut connection = connect(port, &server_cert, Some(&client_cert))?;
+    connection.send(connection_request(&watched_path, None))?;
+    match connection.receive()? {
+        Message::Connected { .. } => (),
+        msg => anyhow::bail!("expected connection to be accepted, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+/// Writes a self-signed certificate for localhost to `<name>.pem` and its key to `<name>.key`.
+fn write_cert(dir: &Path, name: &str) -> anyhow::Result<CertifiedKey<rcgen::KeyPair>> {
+    let cert = generate_simple_self_signed(vec!["localhost".to_string()])?;
+    fs::write(dir.join(format!("{name}.pem")), cert.cert.pem())?;
+    fs::write(
+        dir.join(format!("{name}.key")),
+        cert.signing_key.serialize_pem(),
+    )?;
+
+    Ok(cert)
+}
+
+fn spawn_server(
+    test_dir: &Path,
+    port: u16,
+    auth_args: &[&std::ffi::OsStr],
+) -> anyhow::Result<ProcessGuard> {
+    let workspace_dir = workspace_dir()?;
+    let _ = Command::new("cargo")
+        .current_dir(&workspace_dir)
+        .arg("build")
+        .status()?;
+
+    let server = ProcessGuard::spawn(
+        Command::new("target/debug/seedmirror-server")
+            .current_dir(&workspace_dir)
+            .arg("--socket-path")
+            .arg(test_dir.join("seedmirror-server.sock"))
+            .arg("--sync-delay")
+            .arg("100")
+            .arg("--listen")
+            .arg(format!("127.0.0.1:{port}"))
+            .arg("--tls-cert")
+            .arg(test_dir.join("server.pem"))
+            .arg("--tls-key")
+            .arg(test_dir.join("server.key"))
+            .args(auth_args),
+    )?;
+
+    // Wait for the server to start listening
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return Ok(server);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    anyhow::bail!("server didn't start listening on port {port}")
+}
+
+fn connect(
+    port: u16,
+    server_cert: &CertifiedKey<rcgen::KeyPair>,
+    client_cert: Option<&CertifiedKey<rcgen::KeyPair>>,
+) -> anyhow::Result<TlsConnection> {
+    let mut roots = RootCertStore::empty();
+    roots.add(server_cert.cert.der().clone())?;
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let config = match client_cert {
+        Some(client_cert) => {
+            let key = PrivateKeyDer::try_from(client_cert.signing_key.serialize_der())
+                .map_err(|e| anyhow::anyhow!(e))?;
+            builder.with_client_auth_cert(vec![client_cert.cert.der().clone()], key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_name = ServerName::try_from("localhost")?;
+    let client = ClientConnection::new(Arc::new(config), server_name)?;
+    let stream = TcpStream::connect(("127.0.0.1", port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    Ok(Connection::new(StreamOwned::new(client, stream)))
+}
+
+fn connection_request(watched_path: &Path, token: Option<Token>) -> Message {
+    Message::ConnectionRequest {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capability::supported(),
+        watched_paths: vec![watched_path.to_path_buf()],
+        filters: Vec::new(),
+        token,
+        last_seq: None,
+        journal_id: None,
+    }
+}
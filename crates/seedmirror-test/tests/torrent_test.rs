@@ -0,0 +1,372 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use seedmirror_core::message::Message;
+use seedmirror_test::{
+    message::connect,
+    path::TempDir,
+    process::{ProcessGuard, spawn_server},
+};
+
+/// Torrents listed by the stand-in, as content path and progress.
+type Torrents = Arc<Mutex<Vec<(PathBuf, f64)>>>;
+
+#[test]
+fn test_qbittorrent() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("torrent_test_qbittorrent")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let completed_path = watched_path.join("completed.mkv");
+    let downloading_path = watched_path.join("downloading.mkv");
+    let excluded_path = watched_path.join("excluded.part");
+    let unwatched_path = test_dir.path.join("unwatched.mkv");
+    fs::write(&completed_path, "")?;
+    fs::write(&downloading_path, "")?;
+    fs::write(&excluded_path, "")?;
+    fs::write(&unwatched_path, "")?;
+
+    let torrents: Torrents = Arc::new(Mutex::new(vec![
+        (completed_path, 1.0),
+        (downloading_path.clone(), 0.5),
+        (excluded_path, 0.5),
+        (unwatched_path, 0.5),
+    ]));
+    let polls = Arc::new(AtomicUsize::new(0));
+    let port = {
+        let (torrents, polls) = (torrents.clone(), polls.clone());
+        serve(move |head, body| {
+            if head.starts_with("POST /api/v2/auth/login ") {
+                return if body == "username=user&password=p%40ss" {
+                    response(
+                        "200 OK",
+                        "Set-Cookie: SID=session; HttpOnly; path=/\r\n",
+                        "Ok.",
+                    )
+                } else {
+                    response("200 OK", "", "Fails.")
+                };
+            }
+            if !head.starts_with("GET /api/v2/torrents/info ") {
+                return response("404 Not Found", "", "");
+            }
+            if !head.contains("\r\ncookie: SID=session\r\n") {
+                return response("403 Forbidden", "", "Forbidden");
+            }
+
+            polls.fetch_add(1, Ordering::SeqCst);
+            let torrents: Vec<_> = torrents
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(content_path, progress)| {
+                    format!(
+                        r#"{{"hash":"{}","content_path":"{}","progress":{progress}}}"#,
+                        content_path.file_name().unwrap().to_str().unwrap(),
+                        content_path.display()
+                    )
+                })
+                .collect();
+            response("200 OK", "", &format!("[{}]", torrents.join(",")))
+        })?
+    };
+
+    let password_path = test_dir.path.join("password");
+    fs::write(&password_path, "p@ss\n")?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let journal_path = test_dir.path.join("journal");
+    let url = format!("http://127.0.0.1:{port}");
+    let _server = spawn_torrent_server(
+        &socket_path,
+        &[
+            "--event-source",
+            "qbittorrent",
+            "--torrent-client-url",
+            &url,
+            "--torrent-client-username",
+            "user",
+            "--torrent-client-password-file",
+            password_path.to_str().unwrap(),
+            "--exclude",
+            "*.part",
+            "--journal-path",
+            journal_path.to_str().unwrap(),
+        ],
+    )?;
+
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+
+    // Changes to watched paths aren't reported when using a torrent client
+    fs::write(watched_path.join("unrelated.txt"), "")?;
+
+    // Neither are torrents excluded by the server's filter or outside of watched paths, which
+    // don't take a sequence number
+    wait_for_polls(&polls, 2)?;
+    torrents.lock().unwrap()[2].1 = 1.0;
+    torrents.lock().unwrap()[3].1 = 1.0;
+
+    let polled = polls.load(Ordering::SeqCst);
+    wait_for_polls(&polls, polled + 2)?;
+    torrents.lock().unwrap()[1].1 = 1.0;
+    assert_eq!(
+        connection.receive()?,
+        Message::FileUpdated {
+            path: downloading_path,
+            seq: Some(1),
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_transmission() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("torrent_test_transmission")?;
+    let watched_path = test_dir.path.join("watched");
+    let torrent_path = watched_path.join("season");
+    fs::create_dir_all(&torrent_path)?;
+
+    let torrents: Torrents = Arc::new(Mutex::new(vec![(torrent_path.clone(), 0.0)]));
+    let polls = Arc::new(AtomicUsize::new(0));
+    let port = {
+        let (torrents, polls) = (torrents.clone(), polls.clone());
+        serve(move |head, _body| {
+            if !head.starts_with("POST /transmission/rpc ") {
+                return response("404 Not Found", "", "");
+            }
+            // "user:secret"
+            if !head.contains("\r\nauthorization: Basic dXNlcjpzZWNyZXQ=\r\n") {
+                return response("401 Unauthorized", "", "");
+            }
+            if !head.contains("\r\nx-transmission-session-id: session\r\n") {
+                return response("409 Conflict", "X-Transmission-Session-Id: session\r\n", "");
+            }
+
+            polls.fetch_add(1, Ordering::SeqCst);
+            let torrents: Vec<_> = torrents
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(content_path, progress)| {
+                    format!(
+                        r#"{{"hashString":"{}","downloadDir":"{}","name":"{}","percentDone":{progress}}}"#,
+                        content_path.file_name().unwrap().to_str().unwrap(),
+                        content_path.parent().unwrap().display(),
+                        content_path.file_name().unwrap().to_str().unwrap(),
+                    )
+                })
+                .collect();
+            let body = format!(
+                r#"{{"arguments":{{"torrents":[{}]}},"result":"success"}}"#,
+                torrents.join(",")
+            );
+            chunked_response(&body)
+        })?
+    };
+
+    let password_path = test_dir.path.join("password");
+    fs::write(&password_path, "secret\n")?;
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let url = format!("http://127.0.0.1:{port}/");
+    let _server = spawn_torrent_server(
+        &socket_path,
+        &[
+            "--event-source",
+            "transmission",
+            "--torrent-client-url",
+            &url,
+            "--torrent-client-username",
+            "user",
+            "--torrent-client-password-file",
+            password_path.to_str().unwrap(),
+        ],
+    )?;
+
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+    wait_for_polls(&polls, 2)?;
+    torrents.lock().unwrap()[0].1 = 1.0;
+
+    // Directories are reported with a trailing slash
+    match connection.receive()? {
+        Message::FileUpdated { path, .. } => assert_eq!(path, torrent_path.join("")),
+        msg => anyhow::bail!("expected update of the completed torrent, got {msg:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_completed_while_stopped() -> anyhow::Result<()> {
+    let test_dir = TempDir::new("torrent_test_completed_while_stopped")?;
+    let watched_path = test_dir.path.join("watched");
+    fs::create_dir(&watched_path)?;
+    let previous_path = watched_path.join("previous.mkv");
+    let completed_path = watched_path.join("completed.mkv");
+    fs::write(&previous_path, "")?;
+    fs::write(&completed_path, "")?;
+
+    // The previous torrent was already complete when the server last polled
+    let state_path = test_dir.path.join("torrents");
+    fs::write(&state_path, "previous.mkv\n")?;
+
+    let torrents = format!(
+        r#"[{{"hash":"previous.mkv","content_path":"{}","progress":1}},{{"hash":"completed.mkv","content_path":"{}","progress":1}}]"#,
+        previous_path.display(),
+        completed_path.display()
+    );
+    let available = Arc::new(AtomicBool::new(false));
+    let port = {
+        let available = available.clone();
+        serve(move |head, _body| {
+            if !head.starts_with("GET /api/v2/torrents/info ") {
+                return response("404 Not Found", "", "");
+            }
+            // Don't answer until the client is connected, the update would be missed otherwise
+            if !available.load(Ordering::SeqCst) {
+                return response("503 Service Unavailable", "", "");
+            }
+
+            response("200 OK", "", &torrents)
+        })?
+    };
+
+    let socket_path = test_dir.path.join("seedmirror-server.sock");
+    let url = format!("http://127.0.0.1:{port}");
+    let _server = spawn_torrent_server(
+        &socket_path,
+        &[
+            "--event-source",
+            "qbittorrent",
+            "--torrent-client-url",
+            &url,
+            "--torrent-state-path",
+            state_path.to_str().unwrap(),
+        ],
+    )?;
+
+    let mut connection = connect(&socket_path, &[&watched_path])?;
+    available.store(true, Ordering::SeqCst);
+    match connection.receive()? {
+        Message::FileUpdated { path, .. } => assert_eq!(path, completed_path),
+        msg => anyhow::bail!("expected update of the completed torrent, got {msg:?}"),
+    }
+
+    let started = Instant::now();
+    loop {
+        let mut hashes: Vec<_> = fs::read_to_string(&state_path)?
+            .lines()
+            .map(str::to_string)
+            .collect();
+        hashes.sort();
+        if hashes == ["completed.mkv", "previous.mkv"] {
+            break;
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("expected both torrents to be saved as complete, got {hashes:?}");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(())
+}
+
+fn spawn_torrent_server(socket_path: &Path, args: &[&str]) -> anyhow::Result<ProcessGuard> {
+    let args: Vec<&OsStr> = args
+        .iter()
+        .chain(&["--sync-delay", "100", "--torrent-poll-interval", "100"])
+        .map(OsStr::new)
+        .collect();
+    spawn_server(socket_path, &args)
+}
+
+/// Waits until the server listed the torrents `count` times, so that it knows which ones were
+/// already complete.
+fn wait_for_polls(polls: &AtomicUsize, count: usize) -> anyhow::Result<()> {
+    let started = Instant::now();
+    while polls.load(Ordering::SeqCst) < count {
+        if started.elapsed() > Duration::from_secs(10) {
+            anyhow::bail!("server didn't poll the torrent client");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(())
+}
+
+/// Serves HTTP requests on a free local port in the background, answering each one with the raw
+/// response returned by `respond` for the request's head, with lowercase header names, and body.
+fn serve(respond: impl Fn(&str, &str) -> String + Send + 'static) -> anyhow::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+
+            let mut reader = BufReader::new(&mut stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+                match line.split_once(':') {
+                    Some((name, value)) if !head.is_empty() => {
+                        head.push_str(&name.to_ascii_lowercase());
+                        head.push(':');
+                        head.push_str(value);
+                    }
+                    _ => head.push_str(&line),
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+
+            let content_length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .and_then(|length| length.trim().parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; content_length];
+            if reader.read_exact(&mut body).is_err() {
+                continue;
+            }
+
+            let response = respond(&head, &String::from_utf8_lossy(&body));
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+
+    Ok(port)
+}
+
+fn response(status: &str, headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Returns a response with `body` split into two chunks.
+fn chunked_response(body: &str) -> String {
+    let (first, second) = body.split_at(body.len() / 2);
+    format!(
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+        {:x}\r\n{first}\r\n{:x};ext=1\r\n{second}\r\n0\r\n\r\n",
+        first.len(),
+        second.len()
+    )
+}